@@ -0,0 +1,59 @@
+//! The source of `exit.elf`, a minimal user program that calls `exit` with
+//! the value of a static. It has a text, a read-only and a data segment with
+//! .bss, and is linked with the layout of the user programs:
+//!
+//! rustc +nightly --target aarch64-unknown-none -C panic=abort \
+//!     -C target-cpu=cortex-a53 -O --crate-type bin \
+//!     -C link-arg=--script=user/fib/.cargo/layout.ld \
+//!     -C link-arg=--no-dynamic-linker -o ext/elf/exit.elf ext/elf/exit.rs
+
+#![feature(no_core, lang_items, rustc_attrs, decl_macro, auto_traits)]
+#![allow(internal_features)]
+#![no_core]
+#![no_std]
+#![no_main]
+
+#[lang = "pointee_sized"]
+trait PointeeSized {}
+
+#[lang = "meta_sized"]
+trait MetaSized: PointeeSized {}
+
+#[lang = "sized"]
+trait Sized: MetaSized {}
+
+#[lang = "copy"]
+trait Copy {}
+
+#[lang = "sync"]
+unsafe auto trait Sync {}
+
+#[lang = "drop_glue"]
+fn drop_glue<T: ?Sized>(_: *mut T) {}
+
+#[rustc_builtin_macro]
+macro asm("assembly template", $(operands,)* $(options($(option),*))?) {
+    /* compiler built-in */
+}
+
+impl Copy for u8 {}
+impl Copy for u64 {}
+impl<T: Copy, const N: usize> Copy for [T; N] {}
+
+#[no_mangle]
+#[used]
+static GREETING: [u8; 16] = *b"exit.elf rodata\n";
+
+#[no_mangle]
+#[used]
+static mut RESULT: u64 = 0x1234;
+
+#[no_mangle]
+#[used]
+static mut SCRATCH: [u8; 16] = [0; 16];
+
+#[no_mangle]
+#[link_section = ".text._start"]
+pub unsafe extern "C" fn _start() -> ! {
+    asm!("svc 3", in("x0") RESULT, options(noreturn));
+}
@@ -3,6 +3,9 @@ mod util;
 
 mod bin;
 mod bump;
+mod frame;
+mod memory_map;
+mod slab;
 
 type AllocatorImpl = bin::Allocator;
 
@@ -11,11 +14,18 @@ mod tests;
 
 use core::alloc::{GlobalAlloc, Layout};
 use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
 
 use crate::mutex::Mutex;
-use crate::param::{PAGE_ALIGN};
-use util::{align_up, align_down};
-use pi::atags::{Atag, Atags};
+use crate::param::{IO_BASE, KERNEL_HEAP_RESERVE, KERNEL_HEAP_SIZE, KERN_STACK_BASE, PAGE_SIZE};
+use crate::vm::PhysicalAddr;
+use crate::FRAMES;
+use pi::atags::{Atag, Atags, ATAG_BASE};
+
+pub use self::bin::BinStats;
+pub use self::frame::{FrameStats, Frames};
+pub use self::memory_map::{MemoryKind, MemoryMap, MemoryRegion};
+pub use self::slab::{FrameSource, SlabBox, SlabCache, SlabStats};
 
 /// `LocalAlloc` is an analogous trait to the standard library's `GlobalAlloc`,
 /// but it takes `&mut self` in `alloc()` and `dealloc()`.
@@ -24,8 +34,18 @@ pub trait LocalAlloc {
     unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout);
 }
 
-/// Thread-safe (locking) wrapper around a particular memory allocator.
-pub struct Allocator(Mutex<Option<AllocatorImpl>>);
+/// The kernel heap: the main allocator, and the reserve above it that
+/// allocations which cannot fail fall back to once the main one is exhausted.
+struct Heap {
+    main: AllocatorImpl,
+    reserve: AllocatorImpl,
+    reserve_start: usize,
+}
+
+/// Thread-safe (locking) wrapper around a particular memory allocator, and
+/// whether the heap reserve was used since the last call to
+/// `take_exhausted()`.
+pub struct Allocator(Mutex<Option<Heap>>, AtomicBool);
 
 impl Allocator {
     /// Returns an uninitialized `Allocator`.
@@ -33,10 +53,60 @@ impl Allocator {
     /// The allocator must be initialized by calling `initialize()` before the
     /// first memory allocation. Failure to do will result in panics.
     pub const fn uninitialized() -> Self {
-        Allocator(Mutex::new(None))
+        Allocator(Mutex::new(None), AtomicBool::new(false))
+    }
+
+    /// Initializes the memory allocator with a heap of `KERNEL_HEAP_SIZE`
+    /// bytes, or half of the free memory if there is less, carved out of the
+    /// frame allocator. Its last `KERNEL_HEAP_RESERVE` bytes are the reserve.
+    /// The caller should assure that the method is invoked only once during the
+    /// kernel initialization, after `FRAMES` has been initialized.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the heap could not be allocated.
+    pub unsafe fn initialize(&self) {
+        let frames = core::cmp::min(KERNEL_HEAP_SIZE / PAGE_SIZE, FRAMES.stats().free / 2);
+        let start = FRAMES.alloc_contiguous(frames).expect("failed to allocate the kernel heap");
+        let end = start.as_usize() + frames * PAGE_SIZE;
+        let reserve_start = end - KERNEL_HEAP_RESERVE;
+        *self.0.lock() = Some(Heap {
+            main: AllocatorImpl::new(start.as_usize(), reserve_start),
+            reserve: AllocatorImpl::new(reserve_start, end),
+            reserve_start,
+        });
+    }
+
+    /// Allocates memory for `layout` from the main heap only, returning a
+    /// null pointer if it is exhausted. Unlike `GlobalAlloc::alloc()`, a
+    /// failure neither takes from the reserve nor marks the heap exhausted:
+    /// the caller is expected to report it, as `NoMemory`.
+    pub unsafe fn try_alloc(&self, layout: Layout) -> *mut u8 {
+        return self.0.lock().as_mut().expect("allocator uninitialized").main.alloc(layout);
+    }
+
+    /// Returns `true` if an allocation had to be served from the reserve
+    /// since the last call, clearing the flag.
+    pub fn take_exhausted(&self) -> bool {
+        return self.1.swap(false, Ordering::Relaxed);
+    }
+}
+
+/// Thread-safe (locking) wrapper around the allocator of the physical page
+/// frames holding user pages and the kernel heap.
+pub struct FrameAllocator(Mutex<Option<Frames>>);
+
+impl FrameAllocator {
+    /// Returns an uninitialized `FrameAllocator`.
+    ///
+    /// The allocator must be initialized by calling `initialize()` before the
+    /// first frame allocation. Failure to do will result in panics.
+    pub const fn uninitialized() -> Self {
+        FrameAllocator(Mutex::new(None))
     }
 
-    /// Initializes the memory allocator.
+    /// Initializes the frame allocator with all of the memory in the
+    /// system's memory map.
     /// The caller should assure that the method is invoked only once during the
     /// kernel initialization.
     ///
@@ -44,59 +114,141 @@ impl Allocator {
     ///
     /// Panics if the system's memory map could not be retrieved.
     pub unsafe fn initialize(&self) {
-        let (start, end) = memory_map().expect("failed to find memory map");
-        *self.0.lock() = Some(AllocatorImpl::new(start, end));
+        let map = memory_map().expect("failed to find memory map");
+        *self.0.lock() = Some(Frames::new(&map));
+    }
+
+    /// Runs `f` on the initialized frame allocator.
+    fn with_frames<R, F: FnOnce(&mut Frames) -> R>(&self, f: F) -> R {
+        f(self.0.lock().as_mut().expect("frame allocator uninitialized"))
+    }
+
+    /// Allocates a zero-filled frame with one reference. Returns `None` if
+    /// every frame is in use.
+    pub fn alloc(&self) -> Option<PhysicalAddr> {
+        let frame = self.with_frames(|frames| frames.alloc())?;
+        unsafe {
+            (frame as *mut u8).write_bytes(0, PAGE_SIZE);
+        }
+
+        return Some(PhysicalAddr::from(frame));
+    }
+
+    /// Allocates `n` contiguous frames with one reference each and returns
+    /// the first one. Their contents are unspecified. Returns `None` if there
+    /// is no such run of free frames.
+    pub fn alloc_contiguous(&self, n: usize) -> Option<PhysicalAddr> {
+        return self.with_frames(|frames| frames.alloc_contiguous(n)).map(PhysicalAddr::from);
+    }
+
+    /// Takes another reference to `frame`. See `Frames::share()`.
+    pub fn share(&self, frame: PhysicalAddr) {
+        self.with_frames(|frames| frames.share(frame.as_usize()));
+    }
+
+    /// Gives back a reference to `frame`, freeing it if it was the last. See
+    /// `Frames::release()`.
+    pub fn release(&self, frame: PhysicalAddr) {
+        self.with_frames(|frames| frames.release(frame.as_usize()));
+    }
+
+    /// Returns the number of references to `frame`, zero if it is free.
+    pub fn count(&self, frame: PhysicalAddr) -> usize {
+        return self.with_frames(|frames| frames.count(frame.as_usize()));
+    }
+
+    /// Returns the number of total and free frames.
+    pub fn stats(&self) -> FrameStats {
+        return self.with_frames(|frames| frames.stats());
+    }
+}
+
+impl FrameSource for FrameAllocator {
+    fn alloc(&self) -> Option<PhysicalAddr> {
+        return FrameAllocator::alloc(self);
+    }
+
+    fn release(&self, frame: PhysicalAddr) {
+        FrameAllocator::release(self, frame);
     }
 }
 
 unsafe impl GlobalAlloc for Allocator {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        self.0
-            .lock()
-            .as_mut()
-            .expect("allocator uninitialized")
-            .alloc(layout)
+        let mut heap = self.0.lock();
+        let heap = heap.as_mut().expect("allocator uninitialized");
+
+        let ptr = heap.main.alloc(layout);
+        if !ptr.is_null() {
+            return ptr;
+        }
+
+        // Keep the kernel running until the scheduler has made room
+        self.1.store(true, Ordering::Relaxed);
+        heap.reserve.alloc(layout)
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        self.0
-            .lock()
-            .as_mut()
-            .expect("allocator uninitialized")
-            .dealloc(ptr, layout);
+        let mut heap = self.0.lock();
+        let heap = heap.as_mut().expect("allocator uninitialized");
+
+        if (ptr as usize) < heap.reserve_start {
+            heap.main.dealloc(ptr, layout);
+        } else {
+            heap.reserve.dealloc(ptr, layout);
+        }
     }
 }
 
 extern "C" {
+    static __text_beg: u8;
     static __text_end: u8;
 }
 
-/// Returns the (start address, end address) of the available memory on this
-/// system if it can be determined. If it cannot, `None` is returned.
+/// Returns the map of this system's RAM, as reported by the `Mem` ATAGs, if
+/// it can be determined. If it cannot, `None` is returned.
+///
+/// The memory the firmware and the kernel use from boot on is reserved: the
+/// spin tables and the kernel stack below the kernel image, the ATAGs and
+/// the image itself. Everything else, page tables included, is allocated
+/// from the usable regions.
 ///
 /// This function is expected to return `Some` under all normal cirumstances.
-pub fn memory_map() -> Option<(usize, usize)> {
-    let binary_end = unsafe { (&__text_end as *const u8) as usize };
-
-    let mut end_addr: usize = binary_end;
+pub fn memory_map() -> Option<MemoryMap> {
+    let mut map = MemoryMap::new();
 
     for atag in Atags::get() {
-        match atag {
-            Atag::Mem(mem) => {
-                end_addr = (mem.start + mem.size) as usize;
-            },
-            _ => {}
-        };
+        if let Atag::Mem(mem) = atag {
+            // The GPU's memory and the peripherals are above the ARM's
+            let start = mem.start as usize;
+            let end = core::cmp::min(start + mem.size as usize, IO_BASE);
+            map.add(start, end);
+        }
     }
 
-    return Some((align_up(binary_end, PAGE_ALIGN), align_down(end_addr, PAGE_ALIGN)));
-}
+    if map.regions().is_empty() {
+        return None;
+    }
 
+    let (text_beg, text_end) = unsafe {
+        ((&__text_beg as *const u8) as usize, (&__text_end as *const u8) as usize)
+    };
+
+    map.reserve(0, KERN_STACK_BASE);
+    map.reserve(ATAG_BASE, Atags::get().end());
+    map.reserve(text_beg, text_end);
+
+    return Some(map);
+}
 
 impl fmt::Debug for Allocator {
    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self.0.lock().as_mut() {
-            Some(ref alloc) => write!(f, "{:?}", alloc)?,
+            Some(ref heap) => {
+                fmt::Debug::fmt(&heap.main, f)?;
+                write!(f, "\nReserve: ")?;
+                fmt::Debug::fmt(&heap.reserve, f)?;
+            }
             None => write!(f, "Not yet initialized")?,
         }
         Ok(())
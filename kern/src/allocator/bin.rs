@@ -1,21 +1,62 @@
 use core::alloc::Layout;
+use core::fmt;
+use core::ptr;
 
 use crate::allocator::linked_list::LinkedList;
 use crate::allocator::util::*;
 use crate::allocator::LocalAlloc;
 
-/// A simple allocator that allocates based on size classes.
-///   bin 0 (2^3 bytes)    : handles allocations in (0, 2^3]
-///   bin 1 (2^4 bytes)    : handles allocations in (2^3, 2^4]
+/// The number of block sizes, from 2^3 up to 2^(3 + BINS - 1) bytes.
+pub const BINS: usize = 29;
+
+/// The size of the red zones before and after every allocation of the debug
+/// heap.
+#[cfg(feature = "debug-heap")]
+const RED_ZONE: usize = 16;
+
+/// The byte filling the red zones of the debug heap.
+#[cfg(feature = "debug-heap")]
+const RED_ZONE_BYTE: u8 = 0xfd;
+
+/// The byte the debug heap fills freed blocks with.
+#[cfg(feature = "debug-heap")]
+const POISON_BYTE: u8 = 0xdd;
+
+/// Counters of the allocations served from one bin.
+#[derive(Default, Debug, Copy, Clone, PartialEq, Eq)]
+pub struct BinStats {
+    /// The number of allocations so far.
+    pub allocs: u64,
+    /// The number of deallocations so far.
+    pub frees: u64,
+    /// The bytes requested by the live allocations.
+    pub in_use: usize,
+    /// The largest value `in_use` has had.
+    pub high_water: usize,
+}
+
+/// A buddy allocator with one free list ("bin") per block size.
+///   bin 0 (2^3 bytes)  : handles allocations in (0, 2^3]
+///   bin 1 (2^4 bytes)  : handles allocations in (2^3, 2^4]
 ///   ...
-///   bin 29 (2^22 bytes): handles allocations in (2^31, 2^32]
+///   bin 28 (2^31 bytes): handles allocations in (2^30, 2^31]
 ///
-///   map_to_bin(size) -> k
-
+/// Every block of 2^k bytes is aligned to 2^k, so its buddy, the other half
+/// of the block of 2^(k + 1) bytes both were split from, is at its address
+/// with bit k flipped. A request is served from the smallest free block that
+/// is large enough, splitting it in halves down to the requested size; a
+/// freed block is merged with its buddy for as long as the buddy is free.
+///
+/// The memory is initially cut into the largest aligned blocks that fit.
+///
+/// With the `debug-heap` feature, every allocation is surrounded by red zones
+/// that are checked when it is freed, freed blocks are poisoned, and freeing
+/// a block that is already free panics.
 pub struct Allocator {
-    bins: [LinkedList; 29], // 32 bins, starting at 2^3
-    free_start: usize,
-    free_end: usize,
+    bins: [LinkedList; BINS],
+    stats: [BinStats; BINS],
+    start: usize,
+    end: usize,
 }
 
 /// Maps a size to a given bin
@@ -49,75 +90,161 @@ fn map_to_size(bin: usize) -> usize {
     return 1 << (bin + 3);
 }
 
+/// Maps `layout` to the bin of the smallest block that can hold it. Blocks
+/// are aligned to their size, so that block is suitably aligned too.
+fn layout_to_bin(layout: &Layout) -> usize {
+    return map_to_bin(core::cmp::max(layout.size(), layout.align()));
+}
+
+/// Returns the layout of the block backing an allocation of `layout` and the
+/// offset of the allocation in that block.
+#[cfg(not(feature = "debug-heap"))]
+fn block_layout(layout: Layout) -> (Layout, usize) {
+    return (layout, 0);
+}
+
+/// Returns the layout of the block backing an allocation of `layout` and the
+/// offset of the allocation in that block: the allocation is preceded by a
+/// red zone of at least `RED_ZONE` bytes, keeping it aligned, and followed
+/// by one of `RED_ZONE` bytes.
+#[cfg(feature = "debug-heap")]
+fn block_layout(layout: Layout) -> (Layout, usize) {
+    let offset = core::cmp::max(RED_ZONE, layout.align());
+    let size = offset + layout.size() + RED_ZONE;
+    return (unsafe { Layout::from_size_align_unchecked(size, layout.align()) }, offset);
+}
+
 impl Allocator {
-    /// Creates a new bin allocator that will allocate memory from the region
-    /// starting at address `start` and ending at address `end`.
+    /// Creates a new buddy allocator that will allocate memory from the
+    /// region starting at address `start` and ending at address `end`.
     pub fn new(start: usize, end: usize) -> Allocator {
-        return Allocator {
-            bins: [LinkedList::new(); 29],
-            free_start: start,
-            free_end: end
+        let mut allocator = Allocator {
+            bins: [LinkedList::new(); BINS],
+            stats: [BinStats::default(); BINS],
+            start,
+            end,
+        };
+
+        let mut block = align_up(start, map_to_size(0));
+        while block < end {
+            // The largest block aligned at `block` that fits before `end`
+            let mut bin = core::cmp::min(block.trailing_zeros() as usize - 3, BINS - 1);
+            while bin > 0 && block.saturating_add(map_to_size(bin)) > end {
+                bin -= 1;
+            }
+
+            if block + map_to_size(bin) > end {
+                break;
+            }
+
+            unsafe { allocator.bins[bin].push(block as *mut usize) };
+            block += map_to_size(bin);
         }
+
+        return allocator;
     }
 
-    /// Gets a block from giant free memory chunk
-    fn get_block_from_free_mem(&mut self, layout: Layout) -> Option<*mut usize> {
-        let block_start = align_up(self.free_start, layout.align());
-        let desired_size = map_to_size(map_to_bin(layout.size()));
-
-        match block_start.checked_add(desired_size) {
-            Some(res) => {
-                if res > self.free_end {
-                    return None;
-                }
-                self.free_start = res;
-
-                return Some(block_start as *mut usize);
-            },
-            None => None
+    /// Removes `block` from the free list of bin `bin`. Returns `true` if it
+    /// was there.
+    fn take(&mut self, bin: usize, block: usize) -> bool {
+        for node in self.bins[bin].iter_mut() {
+            if node.value() as usize == block {
+                node.pop();
+                return true;
+            }
         }
+
+        return false;
     }
 
-    /// Insert a block back into the linked lists
-    fn insert_block(&mut self, block: *mut usize, size: usize) {
-        let bin_index = map_to_bin(size);
-        let curr_size = map_to_size(bin_index);
+    /// Returns the number of free blocks in each bin.
+    fn free_blocks(&self) -> [usize; BINS] {
+        let mut counts = [0; BINS];
+        for (count, bin) in counts.iter_mut().zip(self.bins.iter()) {
+            *count = bin.iter().count();
+        }
+
+        return counts;
+    }
 
-        let nodes = self.bins[bin_index];
+    /// Returns the allocation counters of each bin.
+    pub fn stats(&self) -> &[BinStats; BINS] {
+        return &self.stats;
+    }
 
-        // Merge with neighbors, insert larger blocks
-        for node in self.bins[bin_index].iter_mut() {
-            // If is to the right of block
-            if node.value() as usize + curr_size == block as usize {
-                let neighbor = node.pop();
-                self.insert_block(neighbor, size * 2);
+    /// Returns `true` if `addr` lies in a free block.
+    #[cfg(feature = "debug-heap")]
+    fn is_free(&self, addr: usize) -> bool {
+        return self.bins.iter().enumerate().any(|(bin, list)| {
+            list.iter().any(|block| {
+                let block = block as usize;
+                block <= addr && addr < block + map_to_size(bin)
+            })
+        });
+    }
 
-                return;
-            } else if block as usize + curr_size == node.value() as usize {
-                // Pop neighbor, but throw away
-                node.pop();
-                self.insert_block(block, size * 2);
+    /// Takes a free block of bin `bin`, splitting a larger one if needed.
+    /// Returns a null pointer if there is none.
+    unsafe fn alloc_block(&mut self, bin: usize) -> *mut u8 {
+        let mut curr_bin = bin;
+        let block = loop {
+            if curr_bin >= BINS {
+                return ptr::null_mut();
+            }
 
-                return;
+            if let Some(block) = self.bins[curr_bin].pop() {
+                break block as usize;
             }
+
+            curr_bin += 1;
+        };
+
+        // Give back the upper halves until the block has the right size
+        while curr_bin > bin {
+            curr_bin -= 1;
+            self.bins[curr_bin].push((block + map_to_size(curr_bin)) as *mut usize);
         }
 
-        unsafe { self.bins[bin_index].push(block); }
+        return block as *mut u8;
     }
 
-    /// Recursively reinsert blocks into the linked lists, inserting parts as you go up
-    fn reinsert_block(&mut self, block: *mut usize, bin_index: usize, size: usize) {
-        if size == 0 {
-            return;
-        }
+    /// Frees `block` of bin `bin`, merging it with its free buddies.
+    unsafe fn dealloc_block(&mut self, mut block: usize, mut bin: usize) {
+        debug_assert!(is_aligned(block, map_to_size(bin)), "misaligned block {:#x}", block);
 
-        let insert_size = map_to_size(bin_index);
+        // Merge with the buddy for as long as it is free
+        while bin + 1 < BINS {
+            let buddy = block ^ map_to_size(bin);
+            if !self.take(bin, buddy) {
+                break;
+            }
 
-        self.insert_block(block, insert_size);
+            block = core::cmp::min(block, buddy);
+            bin += 1;
+        }
+
+        self.bins[bin].push(block as *mut usize);
+    }
+}
 
-        let remaining_block = (block as usize + insert_size) as *mut usize;
+/// Fills the red zones around the allocation of `size` bytes at `offset` in
+/// `block`.
+#[cfg(feature = "debug-heap")]
+unsafe fn paint_red_zones(block: *mut u8, offset: usize, size: usize) {
+    block.write_bytes(RED_ZONE_BYTE, offset);
+    block.add(offset + size).write_bytes(RED_ZONE_BYTE, RED_ZONE);
+}
 
-        self.reinsert_block(remaining_block, bin_index+1, size - insert_size);
+/// Panics if a red zone around the allocation of `size` bytes at `offset` in
+/// `block` was overwritten.
+#[cfg(feature = "debug-heap")]
+unsafe fn check_red_zones(block: *mut u8, offset: usize, size: usize) {
+    let front = core::slice::from_raw_parts(block, offset);
+    let back = core::slice::from_raw_parts(block.add(offset + size), RED_ZONE);
+    for (zone, bytes) in [("front", front), ("back", back)].iter() {
+        if bytes.iter().any(|&byte| byte != RED_ZONE_BYTE) {
+            panic!("heap corruption: {} red zone of {:#x} overwritten", zone, block as usize + offset);
+        }
     }
 }
 
@@ -144,46 +271,26 @@ impl LocalAlloc for Allocator {
     /// or `layout` does not meet this allocator's
     /// size or alignment constraints.
     unsafe fn alloc(&mut self, layout: Layout) -> *mut u8 {
-        let starting_bin_index = map_to_bin(layout.size());
-        let desired_size = map_to_size(starting_bin_index);
-
-        let mut curr_bin = starting_bin_index;
-
-        while curr_bin < self.bins.len() {
-            let curr_size = map_to_size(curr_bin);
-
-            for node in self.bins[curr_bin].iter_mut() {
-                // Check alignment, if not aligned, ignore that ish
-                if is_aligned(node.value() as usize, layout.align()) {
-                    let return_block = node.pop();
-
-                    if curr_bin == starting_bin_index {
-                        return return_block as *mut u8;
-                    } else {
-                        let to_reinsert = (return_block as usize + desired_size) as *mut usize;
-                        let size = map_to_size(curr_bin) - desired_size;
-
-                        self.reinsert_block(to_reinsert, starting_bin_index, size);
-
-                        return return_block as *mut u8;
-                    }
-                }
-            }
+        let (block_layout, offset) = block_layout(layout);
+        let bin = layout_to_bin(&block_layout);
+        if bin >= BINS {
+            return ptr::null_mut();
+        }
 
-            curr_bin += 1;
+        let block = self.alloc_block(bin);
+        if block.is_null() {
+            return block;
         }
 
-        // Didn't find suitable block in any bin
-        let block = self.get_block_from_free_mem(layout);
+        #[cfg(feature = "debug-heap")]
+        paint_red_zones(block, offset, layout.size());
 
-        match block {
-            Some(block) => {
-                return block as *mut u8;
-            },
-            None => {
-                core::ptr::null_mut()
-            }
-        }
+        let stats = &mut self.stats[bin];
+        stats.allocs += 1;
+        stats.in_use += layout.size();
+        stats.high_water = core::cmp::max(stats.high_water, stats.in_use);
+
+        return block.add(offset);
     }
 
     /// Deallocates the memory referenced by `ptr`.
@@ -200,16 +307,50 @@ impl LocalAlloc for Allocator {
     /// Parameters not meeting these conditions may result in undefined
     /// behavior.
     unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
-        let bin = map_to_bin(layout.size());
-        let size = map_to_size(bin);
+        let (block_layout, offset) = block_layout(layout);
+        let bin = layout_to_bin(&block_layout);
+        let block = ptr.sub(offset);
+
+        #[cfg(feature = "debug-heap")]
+        {
+            assert!(!self.is_free(block as usize), "double free of {:#x}", ptr as usize);
+            check_red_zones(block, offset, layout.size());
+            block.write_bytes(POISON_BYTE, map_to_size(bin));
+        }
+
+        let stats = &mut self.stats[bin];
+        stats.frees += 1;
+        stats.in_use -= layout.size();
 
-        self.insert_block(ptr as *mut usize, size);
+        self.dealloc_block(block as usize, bin);
     }
 }
 
-// FIXME: Implement `Debug` for `Allocator`.
-impl core::fmt::Debug for Allocator {
-    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        write!(f, "Allocator (free_start={}, free_en{})", self.free_start, self.free_end)
+impl fmt::Debug for Allocator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let free = self.free_blocks();
+
+        write!(f, "Allocator (start={:#x}, end={:#x}, free blocks:", self.start, self.end)?;
+        for (bin, &count) in free.iter().enumerate().filter(|&(_, &count)| count > 0) {
+            write!(f, " {}x{}", count, map_to_size(bin))?;
+        }
+        write!(f, ")")?;
+
+        // The alternate form adds the counters of every bin used so far
+        if f.alternate() {
+            for (bin, stats) in self.stats.iter().enumerate().filter(|&(_, stats)| stats.allocs > 0) {
+                write!(
+                    f,
+                    "\n  {:>10} B: {} allocs, {} frees, {} B in use, {} B high water",
+                    map_to_size(bin),
+                    stats.allocs,
+                    stats.frees,
+                    stats.in_use,
+                    stats.high_water
+                )?;
+            }
+        }
+
+        Ok(())
     }
 }
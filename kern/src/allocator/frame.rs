@@ -0,0 +1,225 @@
+use core::fmt;
+
+use crate::allocator::memory_map::MemoryMap;
+use crate::allocator::util::{align_down, align_up};
+use crate::param::PAGE_SIZE;
+
+/// Counts of the frames managed by a frame allocator.
+#[derive(Default, Debug, Copy, Clone, PartialEq, Eq)]
+pub struct FrameStats {
+    /// Frames of usable memory, including those holding the allocator's
+    /// reference counts.
+    pub total: usize,
+    /// Frames that are not allocated.
+    pub free: usize,
+}
+
+impl FrameStats {
+    /// Returns the number of allocated frames.
+    pub fn used(&self) -> usize {
+        return self.total - self.free;
+    }
+}
+
+impl fmt::Display for FrameStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "frames: {} total, {} used, {} free ({} KiB free)",
+            self.total,
+            self.used(),
+            self.free,
+            self.free * PAGE_SIZE / 1024
+        )
+    }
+}
+
+/// An allocator of `PAGE_SIZE` physical page frames.
+///
+/// Every frame from the first to the last usable one has a reference count,
+/// stored in a table occupying the first frames of a usable region; a count
+/// of zero marks a free frame. Frames that are not usable, between or inside
+/// the usable regions, keep a count of one forever, as do the table's. Frames
+/// are found by scanning the table from where the last search stopped.
+pub struct Frames {
+    /// The address of the first managed frame.
+    base: usize,
+    /// The reference count of every managed frame.
+    counts: &'static mut [u16],
+    /// The number of usable frames.
+    total: usize,
+    /// The number of free frames.
+    free: usize,
+    /// The index at which the next search for a free frame starts.
+    next: usize,
+}
+
+impl Frames {
+    /// Creates a frame allocator managing the whole frames in the usable
+    /// regions of `map`.
+    ///
+    /// # Safety
+    ///
+    /// The usable memory must be unused and must not be accessed other than
+    /// through frames handed out by this allocator.
+    ///
+    /// # Panics
+    ///
+    /// Panics if no usable region can hold the table of reference counts and
+    /// if there is no frame left besides it.
+    pub unsafe fn new(map: &MemoryMap) -> Frames {
+        let usable = || {
+            map.usable()
+                .map(|(start, end)| (align_up(start, PAGE_SIZE), align_down(end, PAGE_SIZE)))
+                .filter(|&(start, end)| start < end)
+        };
+
+        let base = usable().map(|(start, _)| start).min().unwrap_or(0);
+        let end = usable().map(|(_, end)| end).max().unwrap_or(0);
+        let len = (end - base) / PAGE_SIZE;
+
+        // The table goes at the start of the first region that can hold it
+        let table_frames = align_up(len * core::mem::size_of::<u16>(), PAGE_SIZE) / PAGE_SIZE;
+        let (table, _) = usable()
+            .find(|&(start, end)| (end - start) / PAGE_SIZE >= table_frames)
+            .expect("not enough memory for page frames");
+
+        let counts = core::slice::from_raw_parts_mut(table as *mut u16, len);
+        for count in counts.iter_mut() {
+            *count = 1;
+        }
+
+        let mut total = 0;
+        for (start, end) in usable() {
+            for count in counts[(start - base) / PAGE_SIZE..(end - base) / PAGE_SIZE].iter_mut() {
+                *count = 0;
+            }
+
+            total += (end - start) / PAGE_SIZE;
+        }
+
+        // The table never goes away
+        let first = (table - base) / PAGE_SIZE;
+        for count in counts[first..first + table_frames].iter_mut() {
+            *count = 1;
+        }
+
+        let free = total - table_frames;
+        assert!(free > 0, "not enough memory for page frames");
+
+        return Frames { base, counts, total, free, next: 0 };
+    }
+
+    /// Returns the index of `frame` in the table.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `frame` is not the address of a managed frame.
+    fn index(&self, frame: usize) -> usize {
+        assert!(frame >= self.base && frame % PAGE_SIZE == 0, "not a page frame: {:#x}", frame);
+
+        let i = (frame - self.base) / PAGE_SIZE;
+        assert!(i < self.counts.len(), "not a page frame: {:#x}", frame);
+        return i;
+    }
+
+    /// Returns the address of the frame at index `i`.
+    fn address(&self, i: usize) -> usize {
+        return self.base + i * PAGE_SIZE;
+    }
+
+    /// Allocates `n` contiguous frames, each with one reference, and returns
+    /// the address of the first one. The contents of the frames are
+    /// unspecified. Returns `None` if no such run of free frames exists.
+    pub fn alloc_contiguous(&mut self, n: usize) -> Option<usize> {
+        let len = self.counts.len();
+        if n == 0 || n > self.free {
+            return None;
+        }
+
+        // Look from the hint to the end, then wrap around to the start
+        let mut run = 0;
+        for step in 0..len + n {
+            let i = (self.next + step) % len;
+            if i == 0 {
+                run = 0;
+            }
+
+            if self.counts[i] != 0 {
+                run = 0;
+                continue;
+            }
+
+            run += 1;
+            if run == n {
+                let first = i + 1 - n;
+                for count in self.counts[first..=i].iter_mut() {
+                    *count = 1;
+                }
+
+                self.free -= n;
+                self.next = (i + 1) % len;
+                return Some(self.address(first));
+            }
+        }
+
+        return None;
+    }
+
+    /// Allocates a frame with one reference and returns its address. The
+    /// contents of the frame are unspecified. Returns `None` if every frame
+    /// is in use.
+    pub fn alloc(&mut self) -> Option<usize> {
+        return self.alloc_contiguous(1);
+    }
+
+    /// Takes another reference to `frame`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `frame` is not allocated or has too many references.
+    pub fn share(&mut self, frame: usize) {
+        let i = self.index(frame);
+        assert!(self.counts[i] != 0, "sharing a free frame: {:#x}", frame);
+
+        self.counts[i] = self.counts[i].checked_add(1).expect("too many frame references");
+    }
+
+    /// Gives back a reference to `frame`. Returns `true` if it was the last
+    /// one and the frame is now free.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `frame` is not allocated.
+    pub fn release(&mut self, frame: usize) -> bool {
+        let i = self.index(frame);
+        assert!(self.counts[i] != 0, "releasing a free frame: {:#x}", frame);
+
+        self.counts[i] -= 1;
+        if self.counts[i] != 0 {
+            return false;
+        }
+
+        self.free += 1;
+        return true;
+    }
+
+    /// Returns the number of references to `frame`, zero if it is free.
+    pub fn count(&self, frame: usize) -> usize {
+        return self.counts[self.index(frame)] as usize;
+    }
+
+    /// Returns the number of total and free frames.
+    pub fn stats(&self) -> FrameStats {
+        return FrameStats { total: self.total, free: self.free };
+    }
+}
+
+impl fmt::Debug for Frames {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Frames")
+            .field("base", &self.base)
+            .field("stats", &self.stats())
+            .finish()
+    }
+}
@@ -0,0 +1,142 @@
+use core::fmt;
+
+/// The maximum number of regions in a memory map.
+const MAX_REGIONS: usize = 32;
+
+/// What a region of physical memory may be used for.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum MemoryKind {
+    /// Free memory for the frame allocator.
+    Usable,
+    /// Memory that is in use from boot on, such as the kernel image.
+    Reserved,
+}
+
+/// A region of physical memory from `start` up to (excluding) `end`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct MemoryRegion {
+    pub start: usize,
+    pub end: usize,
+    pub kind: MemoryKind,
+}
+
+impl MemoryRegion {
+    const EMPTY: MemoryRegion = MemoryRegion { start: 0, end: 0, kind: MemoryKind::Reserved };
+}
+
+/// The regions of RAM in the system, sorted by address and not overlapping.
+///
+/// RAM is added as usable, then the parts of it that are already in use are
+/// reserved, splitting the usable regions around them. The map lives on the
+/// stack since it is built before there is a heap.
+pub struct MemoryMap {
+    regions: [MemoryRegion; MAX_REGIONS],
+    len: usize,
+}
+
+impl MemoryMap {
+    /// Returns an empty memory map.
+    pub const fn new() -> MemoryMap {
+        MemoryMap { regions: [MemoryRegion::EMPTY; MAX_REGIONS], len: 0 }
+    }
+
+    /// Inserts `region` at `index`, moving the following regions up.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the map is full.
+    fn insert(&mut self, index: usize, region: MemoryRegion) {
+        assert!(self.len < MAX_REGIONS, "too many memory regions");
+
+        for i in (index..self.len).rev() {
+            self.regions[i + 1] = self.regions[i];
+        }
+
+        self.regions[index] = region;
+        self.len += 1;
+    }
+
+    /// Removes the region at `index`, moving the following regions down.
+    fn remove(&mut self, index: usize) {
+        for i in index + 1..self.len {
+            self.regions[i - 1] = self.regions[i];
+        }
+
+        self.len -= 1;
+    }
+
+    /// Adds the RAM from `start` to `end` as usable. The parts of the range
+    /// that are already in the map are ignored.
+    pub fn add(&mut self, start: usize, end: usize) {
+        let mut start = start;
+        let mut i = 0;
+        while start < end {
+            while i < self.len && self.regions[i].end <= start {
+                i += 1;
+            }
+
+            if i < self.len && self.regions[i].start <= start {
+                start = self.regions[i].end;
+                continue;
+            }
+
+            // Fill the gap up to the next region
+            let gap_end = if i < self.len { core::cmp::min(end, self.regions[i].start) } else { end };
+            self.insert(i, MemoryRegion { start, end: gap_end, kind: MemoryKind::Usable });
+            start = gap_end;
+        }
+    }
+
+    /// Marks the RAM from `start` to `end` reserved. Parts of the range
+    /// outside of the map's RAM are ignored.
+    pub fn reserve(&mut self, start: usize, end: usize) {
+        let mut i = 0;
+        while i < self.len {
+            let region = self.regions[i];
+            if region.kind == MemoryKind::Reserved || region.end <= start || end <= region.start {
+                i += 1;
+                continue;
+            }
+
+            // Replace the region by its parts before, in and after the range
+            self.remove(i);
+            let parts = [
+                (region.start, start, MemoryKind::Usable),
+                (core::cmp::max(region.start, start), core::cmp::min(region.end, end), MemoryKind::Reserved),
+                (end, region.end, MemoryKind::Usable),
+            ];
+
+            for &(part_start, part_end, kind) in parts.iter() {
+                if part_start < part_end {
+                    self.insert(i, MemoryRegion { start: part_start, end: part_end, kind });
+                    i += 1;
+                }
+            }
+        }
+    }
+
+    /// Returns the regions of the map.
+    pub fn regions(&self) -> &[MemoryRegion] {
+        return &self.regions[..self.len];
+    }
+
+    /// Returns the (start address, end address) of every usable region.
+    pub fn usable<'a>(&'a self) -> impl Iterator<Item = (usize, usize)> + 'a {
+        return self.regions()
+            .iter()
+            .filter(|region| region.kind == MemoryKind::Usable)
+            .map(|region| (region.start, region.end));
+    }
+
+    /// Returns the address at which the last region ends, 0 if the map is
+    /// empty.
+    pub fn end(&self) -> usize {
+        return self.regions().last().map(|region| region.end).unwrap_or(0);
+    }
+}
+
+impl fmt::Debug for MemoryMap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.regions()).finish()
+    }
+}
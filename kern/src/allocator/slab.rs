@@ -0,0 +1,259 @@
+use core::fmt;
+use core::marker::PhantomData;
+use core::mem;
+use core::ops::{Deref, DerefMut};
+use core::ptr;
+
+use crate::allocator::linked_list::LinkedList;
+use crate::allocator::util::align_up;
+use crate::allocator::FrameAllocator;
+use crate::mutex::Mutex;
+use crate::param::{PAGE_MASK, PAGE_SIZE};
+use crate::vm::PhysicalAddr;
+
+/// A source of page frames for the slabs of a cache.
+pub trait FrameSource {
+    /// Allocates a frame. Returns `None` if none is left.
+    fn alloc(&self) -> Option<PhysicalAddr>;
+
+    /// Gives back a frame returned by `alloc()`.
+    fn release(&self, frame: PhysicalAddr);
+}
+
+/// Counters describing a slab cache.
+#[derive(Default, Debug, Copy, Clone, PartialEq, Eq)]
+pub struct SlabStats {
+    /// The name of the cache.
+    pub name: &'static str,
+    /// The size of an object slot in bytes.
+    pub object_size: usize,
+    /// The number of slabs, each a page frame.
+    pub slabs: usize,
+    /// The number of object slots in all slabs.
+    pub capacity: usize,
+    /// The number of objects currently allocated.
+    pub in_use: usize,
+    /// The number of objects allocated so far.
+    pub allocs: u64,
+    /// The number of objects freed so far.
+    pub frees: u64,
+}
+
+impl fmt::Display for SlabStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} x {}B in use of {} in {} slabs, allocs: {}, frees: {}",
+            self.name, self.in_use, self.object_size, self.capacity, self.slabs, self.allocs, self.frees
+        )
+    }
+}
+
+/// The header at the start of every slab.
+struct Slab {
+    /// The next slab of the cache.
+    next: *mut Slab,
+    /// The free object slots of this slab.
+    free: LinkedList,
+    /// The number of allocated objects in this slab.
+    in_use: usize,
+}
+
+/// The slabs of a cache, linked through their headers.
+struct Slabs {
+    head: *mut Slab,
+    stats: SlabStats,
+}
+
+unsafe impl Send for Slabs {}
+
+/// A cache of objects of type `T`.
+///
+/// Objects live in slabs: page frames taken from `frames`, usually `FRAMES`,
+/// cut into slots of the object's size after a small header. Allocating an
+/// object takes a free slot from the first slab that has one, and a new slab
+/// is only needed once every slot is used. A slab whose objects have all
+/// been freed is given back unless it is the cache's last one.
+///
+/// Objects are handed out as `SlabBox`es, which return them to the cache
+/// when dropped.
+pub struct SlabCache<T, F: FrameSource + 'static = FrameAllocator> {
+    slabs: Mutex<Slabs>,
+    frames: &'static F,
+    _marker: PhantomData<T>,
+}
+
+impl<T, F: FrameSource> SlabCache<T, F> {
+    /// Returns an empty cache named `name` whose slabs are taken from
+    /// `frames`.
+    pub const fn new(name: &'static str, frames: &'static F) -> SlabCache<T, F> {
+        SlabCache {
+            slabs: Mutex::new(Slabs {
+                head: ptr::null_mut(),
+                stats: SlabStats {
+                    name,
+                    object_size: 0,
+                    slabs: 0,
+                    capacity: 0,
+                    in_use: 0,
+                    allocs: 0,
+                    frees: 0,
+                },
+            }),
+            frames,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Returns the size and alignment of an object slot.
+    fn slot_layout() -> (usize, usize) {
+        let align = core::cmp::max(mem::align_of::<T>(), mem::align_of::<usize>());
+        let size = align_up(core::cmp::max(mem::size_of::<T>(), mem::size_of::<usize>()), align);
+        return (size, align);
+    }
+
+    /// Returns the offset of the first object slot in a slab.
+    fn first_slot() -> usize {
+        return align_up(mem::size_of::<Slab>(), Self::slot_layout().1);
+    }
+
+    /// Returns the number of objects a slab holds.
+    fn slots_per_slab() -> usize {
+        return (PAGE_SIZE - Self::first_slot()) / Self::slot_layout().0;
+    }
+
+    /// Moves `value` into a free slot of the cache and returns it. Returns
+    /// `None` if a new slab was needed but no page frame is left.
+    ///
+    /// # Panics
+    ///
+    /// Panics if an object does not fit in a slab.
+    pub fn alloc(&'static self, value: T) -> Option<SlabBox<T, F>> {
+        assert!(Self::slots_per_slab() > 0, "object too large for a slab");
+
+        let mut slabs = self.slabs.lock();
+
+        let mut slab = slabs.head;
+        while !slab.is_null() && unsafe { (*slab).free.is_empty() } {
+            slab = unsafe { (*slab).next };
+        }
+
+        if slab.is_null() {
+            slab = self.new_slab()?;
+            unsafe {
+                (*slab).next = slabs.head;
+            }
+            slabs.head = slab;
+            slabs.stats.slabs += 1;
+            slabs.stats.capacity += Self::slots_per_slab();
+        }
+
+        let object = unsafe {
+            (*slab).in_use += 1;
+            (*slab).free.pop().expect("slab has a free slot") as *mut T
+        };
+
+        slabs.stats.object_size = Self::slot_layout().0;
+        slabs.stats.in_use += 1;
+        slabs.stats.allocs += 1;
+
+        unsafe {
+            object.write(value);
+        }
+
+        return Some(SlabBox { object, cache: self });
+    }
+
+    /// Takes a page frame and turns it into an empty slab.
+    fn new_slab(&self) -> Option<*mut Slab> {
+        let mut frame = self.frames.alloc()?;
+        let slab = frame.as_mut_ptr() as *mut Slab;
+
+        let (size, _) = Self::slot_layout();
+        let mut free = LinkedList::new();
+        for i in (0..Self::slots_per_slab()).rev() {
+            let slot = frame.as_usize() + Self::first_slot() + i * size;
+            unsafe { free.push(slot as *mut usize) };
+        }
+
+        unsafe {
+            slab.write(Slab { next: ptr::null_mut(), free, in_use: 0 });
+        }
+
+        return Some(slab);
+    }
+
+    /// Drops the object at `object` and returns its slot to its slab.
+    ///
+    /// # Safety
+    ///
+    /// `object` must have been allocated by this cache and not freed since.
+    unsafe fn free(&self, object: *mut T) {
+        ptr::drop_in_place(object);
+
+        let mut slabs = self.slabs.lock();
+        let slab = (object as usize & PAGE_MASK) as *mut Slab;
+
+        (*slab).free.push(object as *mut usize);
+        (*slab).in_use -= 1;
+        slabs.stats.in_use -= 1;
+        slabs.stats.frees += 1;
+
+        if (*slab).in_use > 0 || slabs.stats.slabs == 1 {
+            return;
+        }
+
+        // Unlink the empty slab and give its frame back
+        let mut link: *mut *mut Slab = &mut slabs.head;
+        while *link != slab {
+            link = &mut (**link).next;
+        }
+        *link = (*slab).next;
+
+        slabs.stats.slabs -= 1;
+        slabs.stats.capacity -= Self::slots_per_slab();
+        self.frames.release(PhysicalAddr::from(slab as usize));
+    }
+
+    /// Returns the cache's statistics.
+    pub fn stats(&self) -> SlabStats {
+        let mut stats = self.slabs.lock().stats;
+        stats.object_size = Self::slot_layout().0;
+        return stats;
+    }
+}
+
+/// An object allocated from a `SlabCache`, freed when the box is dropped.
+pub struct SlabBox<T: 'static, F: FrameSource + 'static = FrameAllocator> {
+    object: *mut T,
+    cache: &'static SlabCache<T, F>,
+}
+
+unsafe impl<T: Send, F: FrameSource> Send for SlabBox<T, F> {}
+unsafe impl<T: Sync, F: FrameSource> Sync for SlabBox<T, F> {}
+
+impl<T, F: FrameSource> Deref for SlabBox<T, F> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.object }
+    }
+}
+
+impl<T, F: FrameSource> DerefMut for SlabBox<T, F> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.object }
+    }
+}
+
+impl<T, F: FrameSource> Drop for SlabBox<T, F> {
+    fn drop(&mut self) {
+        unsafe { self.cache.free(self.object) };
+    }
+}
+
+impl<T: fmt::Debug, F: FrameSource> fmt::Debug for SlabBox<T, F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
@@ -79,8 +79,9 @@ mod allocator {
     use crate::allocator::{bin, bump, LocalAlloc};
 
     macro_rules! test_allocators {
-        (@$kind:ident, $name:ident, $mem:expr, |$info:pat| $block:expr) => {
+        (@$kind:ident, $(#[$attr:meta])* $name:ident, $mem:expr, |$info:pat| $block:expr) => {
             #[test]
+            $(#[$attr])*
             fn $name() {
                 let mem: RawVec<u8> = RawVec::with_capacity($mem);
                 let start = mem.ptr() as usize;
@@ -226,7 +227,10 @@ mod allocator {
         }
     });
 
-    test_allocators!(@bin, bin_dealloc_1, 65536, |(_, _, mut a)| {
+    test_allocators!(@bin,
+        // Red zones make the blocks larger than these tests expect
+        #[cfg(not(feature = "debug-heap"))]
+        bin_dealloc_1, 65536, |(_, _, mut a)| {
         let layouts = [
             layout!(16, 16),
             layout!(16, 256),
@@ -289,6 +293,519 @@ mod allocator {
             }
         }
     });
+
+    /// A deterministic pseudo-random sequence for the stress tests.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self, bound: usize) -> usize {
+            self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            ((self.0 >> 33) as usize) % bound
+        }
+    }
+
+    fn fill(ptr: *mut u8, size: usize, value: u8) {
+        unsafe {
+            ::core::ptr::write_bytes(ptr, value, size);
+        }
+    }
+
+    fn check(ptr: *mut u8, size: usize, value: u8) {
+        let bytes = unsafe { ::core::slice::from_raw_parts(ptr, size) };
+        assert!(bytes.iter().all(|&b| b == value), "block at {:x} was overwritten", ptr as usize);
+    }
+
+    test_allocators!(@bin,
+        // Red zones make the blocks larger than these tests expect
+        #[cfg(not(feature = "debug-heap"))]
+        bin_split_large_block, 1 << 16, |(_, _, mut a)| {
+        // Use up the memory in 4KiB blocks, then whatever is left in 64B ones
+        let mut large = vec![];
+        loop {
+            let ptr = a.alloc(layout!(4096, 8));
+            if ptr.is_null() {
+                break;
+            }
+            large.push(ptr);
+        }
+        assert!(large.len() >= 14);
+
+        while !a.alloc(layout!(64, 8)).is_null() {}
+
+        // A freed 4KiB block is split to serve small requests...
+        let block = large.pop().unwrap();
+        a.dealloc(block, layout!(4096, 8));
+
+        let mut small = vec![];
+        for _ in 0..4096 / 64 {
+            let ptr = a.alloc(layout!(64, 8));
+            assert!(!ptr.is_null());
+            assert!(ptr as usize >= block as usize && (ptr as usize) < block as usize + 4096);
+            small.push(ptr);
+        }
+        assert!(a.alloc(layout!(64, 8)).is_null());
+
+        // ...and merged back together once they are all free
+        for ptr in small {
+            a.dealloc(ptr, layout!(64, 8));
+        }
+        assert_eq!(a.alloc(layout!(4096, 8)), block);
+    });
+
+    test_allocators!(@bin,
+        // Red zones make the blocks larger than these tests expect
+        #[cfg(not(feature = "debug-heap"))]
+        bin_coalesce_interleaved, 1 << 16, |(_, _, mut a)| {
+        let initial = format!("{:?}", a);
+
+        let mut ptrs = vec![];
+        loop {
+            let ptr = a.alloc(layout!(256, 16));
+            if ptr.is_null() {
+                break;
+            }
+            ptrs.push(ptr);
+        }
+        assert!(ptrs.len() >= (1 << 16) / 256 - 2);
+
+        // Free every other block: no two free blocks are buddies yet
+        for ptr in ptrs.iter().step_by(2) {
+            a.dealloc(*ptr, layout!(256, 16));
+        }
+        assert!(a.alloc(layout!(512, 16)).is_null());
+
+        for ptr in ptrs.iter().skip(1).step_by(2) {
+            a.dealloc(*ptr, layout!(256, 16));
+        }
+        assert_eq!(format!("{:?}", a), initial);
+    });
+
+    test_allocators!(@bin, bin_alignment_from_large_blocks, 1 << 20, |(_, _, mut a)| {
+        for &align in &[8, 64, 4096, 1 << 16] {
+            let ptrs: Vec<*mut u8> = (0..4).map(|_| a.alloc(layout!(24, align))).collect();
+            for &ptr in &ptrs {
+                assert!(!ptr.is_null());
+                assert_eq!(ptr as usize % align, 0);
+            }
+
+            for ptr in ptrs {
+                a.dealloc(ptr, layout!(24, align));
+            }
+        }
+    });
+
+    test_allocators!(@bin, bin_stress, 1 << 22, |(_, _, mut a)| {
+        let initial = format!("{:?}", a);
+        let mut rng = Rng(0x3210);
+        let mut live: Vec<(*mut u8, Layout, u8)> = vec![];
+
+        for round in 0..20_000 {
+            if live.is_empty() || rng.next(3) != 0 {
+                let max = if rng.next(8) == 0 { 1 << 16 } else { 512 };
+                let size = 1 + rng.next(max);
+                let layout = layout!(size, 1 << rng.next(8));
+                let ptr = a.alloc(layout.clone());
+                if ptr.is_null() {
+                    continue;
+                }
+
+                assert_eq!(ptr as usize % layout.align(), 0);
+                let value = round as u8;
+                fill(ptr, size, value);
+                live.push((ptr, layout, value));
+            } else {
+                let (ptr, layout, value) = live.swap_remove(rng.next(live.len()));
+                check(ptr, layout.size(), value);
+                a.dealloc(ptr, layout);
+            }
+        }
+
+        for (ptr, layout, value) in live {
+            check(ptr, layout.size(), value);
+            a.dealloc(ptr, layout);
+        }
+        assert_eq!(format!("{:?}", a), initial);
+    });
+
+    test_allocators!(@bin, bin_stats, 1 << 16, |(_, _, mut a)| {
+        let ptrs: Vec<*mut u8> = (0..3).map(|_| a.alloc(layout!(100, 8))).collect();
+        a.dealloc(ptrs[0], layout!(100, 8));
+
+        let used: Vec<_> = a.stats().iter().filter(|stats| stats.allocs > 0).collect();
+        assert_eq!(used.len(), 1);
+        assert_eq!(*used[0], bin::BinStats { allocs: 3, frees: 1, in_use: 200, high_water: 300 });
+
+        for &ptr in &ptrs[1..] {
+            a.dealloc(ptr, layout!(100, 8));
+        }
+        assert_eq!(a.stats().iter().map(|stats| stats.in_use).sum::<usize>(), 0);
+        assert!(format!("{:#?}", a).contains("3 allocs, 3 frees, 0 B in use, 300 B high water"));
+    });
+
+    test_allocators!(@bin,
+        #[cfg(feature = "debug-heap")]
+        #[should_panic(expected = "back red zone")]
+        bin_debug_overflow, 1 << 16, |(_, _, mut a)| {
+        let ptr = a.alloc(layout!(24, 8));
+        fill(ptr, 25, 0);
+        a.dealloc(ptr, layout!(24, 8));
+    });
+
+    test_allocators!(@bin,
+        #[cfg(feature = "debug-heap")]
+        #[should_panic(expected = "front red zone")]
+        bin_debug_underflow, 1 << 16, |(_, _, mut a)| {
+        let ptr = a.alloc(layout!(24, 64));
+        fill(ptr.sub(1), 1, 0);
+        a.dealloc(ptr, layout!(24, 64));
+    });
+
+    test_allocators!(@bin,
+        #[cfg(feature = "debug-heap")]
+        #[should_panic(expected = "double free")]
+        bin_debug_double_free, 1 << 16, |(_, _, mut a)| {
+        let ptr = a.alloc(layout!(24, 8));
+        a.dealloc(ptr, layout!(24, 8));
+        a.dealloc(ptr, layout!(24, 8));
+    });
+
+    test_allocators!(@bin,
+        #[cfg(feature = "debug-heap")]
+        bin_debug_poison, 1 << 16, |(_, _, mut a)| {
+        let keep = a.alloc(layout!(24, 8));
+        let ptr = a.alloc(layout!(256, 8));
+        a.dealloc(ptr, layout!(256, 8));
+
+        // Free list links only use the front red zone
+        check(ptr, 256, 0xdd);
+        a.dealloc(keep, layout!(24, 8));
+    });
+}
+
+mod frames {
+    extern crate alloc;
+    use alloc::raw_vec::RawVec;
+
+    use crate::allocator::util::align_up;
+    use crate::allocator::{FrameStats, Frames, MemoryKind, MemoryMap};
+    use crate::param::PAGE_SIZE;
+
+    /// Runs `f` on a frame allocator managing the usable memory of `map`
+    /// offset by the address of `n` frames of memory, and that address.
+    fn with_map<F: FnOnce(Frames, usize)>(n: usize, map: &MemoryMap, f: F) {
+        let mem: RawVec<u8> = RawVec::with_capacity((n + 1) * PAGE_SIZE);
+        let base = align_up(mem.ptr() as usize, PAGE_SIZE);
+
+        let mut memory = MemoryMap::new();
+        for region in map.regions() {
+            memory.add(base + region.start, base + region.end);
+        }
+        for region in map.regions().iter().filter(|region| region.kind == MemoryKind::Reserved) {
+            memory.reserve(base + region.start, base + region.end);
+        }
+
+        let frames = unsafe { Frames::new(&memory) };
+        f(frames, base);
+    }
+
+    /// Runs `f` on a frame allocator managing `n` frames, the first of which
+    /// holds the reference counts, and the address of the first frame.
+    fn with_frames<F: FnOnce(Frames, usize)>(n: usize, f: F) {
+        let mut map = MemoryMap::new();
+        map.add(0, n * PAGE_SIZE);
+        with_map(n, &map, f);
+    }
+
+    #[test]
+    fn test_alloc_all() {
+        with_frames(8, |mut frames, base| {
+            assert_eq!(frames.stats(), FrameStats { total: 8, free: 7 });
+
+            let mut allocated = vec![];
+            while let Some(frame) = frames.alloc() {
+                assert!(frame > base && frame < base + 8 * PAGE_SIZE);
+                assert_eq!(frame % PAGE_SIZE, 0);
+                assert_eq!(frames.count(frame), 1);
+                allocated.push(frame);
+            }
+
+            allocated.sort();
+            allocated.dedup();
+            assert_eq!(allocated.len(), 7);
+            assert_eq!(frames.stats().free, 0);
+            assert_eq!(frames.stats().used(), 8);
+
+            assert!(frames.release(allocated[3]));
+            assert_eq!(frames.count(allocated[3]), 0);
+            assert_eq!(frames.alloc(), Some(allocated[3]));
+        });
+    }
+
+    #[test]
+    fn test_share() {
+        with_frames(4, |mut frames, _| {
+            let frame = frames.alloc().unwrap();
+            frames.share(frame);
+            frames.share(frame);
+            assert_eq!(frames.count(frame), 3);
+            assert_eq!(frames.stats().free, 2);
+
+            assert!(!frames.release(frame));
+            assert!(!frames.release(frame));
+            assert_eq!(frames.stats().free, 2);
+            assert!(frames.release(frame));
+            assert_eq!(frames.stats().free, 3);
+        });
+    }
+
+    #[test]
+    fn test_contiguous() {
+        with_frames(8, |mut frames, base| {
+            let run = frames.alloc_contiguous(3).unwrap();
+            for i in 0..3 {
+                assert_eq!(frames.count(run + i * PAGE_SIZE), 1);
+            }
+
+            // Leave free frames at 4 and 6, 7: only the latter fit two
+            let rest: Vec<usize> = (0..4).map(|_| frames.alloc().unwrap()).collect();
+            assert_eq!(frames.alloc(), None);
+            for &frame in &[rest[0], rest[2], rest[3]] {
+                frames.release(frame);
+            }
+
+            assert_eq!(frames.alloc_contiguous(2), Some(base + 6 * PAGE_SIZE));
+            assert_eq!(frames.alloc_contiguous(2), None);
+            assert_eq!(frames.alloc_contiguous(1), Some(base + 4 * PAGE_SIZE));
+        });
+    }
+
+    #[test]
+    fn test_regions() {
+        // Frames 3 and 4 are reserved, as is the end of frame 2
+        let mut map = MemoryMap::new();
+        map.add(5 * PAGE_SIZE, 10 * PAGE_SIZE);
+        map.add(0, 5 * PAGE_SIZE);
+        map.reserve(3 * PAGE_SIZE - 8, 5 * PAGE_SIZE);
+
+        with_map(10, &map, |mut frames, base| {
+            assert_eq!(frames.stats(), FrameStats { total: 7, free: 6 });
+            assert_eq!(frames.count(base + 2 * PAGE_SIZE), 1);
+            assert_eq!(frames.count(base + 4 * PAGE_SIZE), 1);
+
+            // Runs do not cross the reserved frames
+            assert_eq!(frames.alloc_contiguous(6), None);
+            assert_eq!(frames.alloc_contiguous(5), Some(base + 5 * PAGE_SIZE));
+            assert_eq!(frames.alloc(), Some(base + PAGE_SIZE));
+            assert_eq!(frames.alloc(), None);
+        });
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_release_free_frame() {
+        with_frames(4, |mut frames, _| {
+            let frame = frames.alloc().unwrap();
+            frames.release(frame);
+            frames.release(frame);
+        });
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_foreign_frame() {
+        with_frames(4, |frames, base| {
+            frames.count(base + 4 * PAGE_SIZE);
+        });
+    }
+}
+
+mod slab {
+    extern crate alloc;
+    use alloc::boxed::Box;
+    use alloc::rc::Rc;
+    use core::cell::{Cell, RefCell};
+
+    use crate::allocator::util::align_up;
+    use crate::allocator::{FrameSource, SlabCache, SlabStats};
+    use crate::param::PAGE_SIZE;
+    use crate::vm::PhysicalAddr;
+
+    /// A frame source handing out `n` page frames of host memory, and
+    /// counting the frames given back.
+    struct TestFrames {
+        _mem: Vec<u8>,
+        free: RefCell<Vec<usize>>,
+        released: Cell<usize>,
+    }
+
+    impl TestFrames {
+        fn new(n: usize) -> &'static TestFrames {
+            let mem = vec![0u8; (n + 1) * PAGE_SIZE];
+            let base = align_up(mem.as_ptr() as usize, PAGE_SIZE);
+            let free = (0..n).rev().map(|i| base + i * PAGE_SIZE).collect();
+            let frames = TestFrames { _mem: mem, free: RefCell::new(free), released: Cell::new(0) };
+            return Box::leak(Box::new(frames));
+        }
+    }
+
+    impl FrameSource for TestFrames {
+        fn alloc(&self) -> Option<PhysicalAddr> {
+            return self.free.borrow_mut().pop().map(PhysicalAddr::from);
+        }
+
+        fn release(&self, frame: PhysicalAddr) {
+            assert_eq!(frame.as_usize() % PAGE_SIZE, 0);
+            self.free.borrow_mut().push(frame.as_usize());
+            self.released.set(self.released.get() + 1);
+        }
+    }
+
+    /// An object a quarter of a slab in size, so that three fit in one.
+    type Large = [u64; PAGE_SIZE / 32];
+
+    fn cache<T>(frames: &'static TestFrames) -> &'static SlabCache<T, TestFrames> {
+        return Box::leak(Box::new(SlabCache::new("test", frames)));
+    }
+
+    #[test]
+    fn test_alloc_free() {
+        let frames = TestFrames::new(4);
+        let cache = cache::<u64>(frames);
+
+        let a = cache.alloc(1).unwrap();
+        let b = cache.alloc(2).unwrap();
+        assert_eq!((*a, *b), (1, 2));
+        assert_eq!(&*a as *const u64 as usize & !(PAGE_SIZE - 1), &*b as *const u64 as usize & !(PAGE_SIZE - 1));
+
+        let stats = cache.stats();
+        assert_eq!((stats.slabs, stats.in_use, stats.allocs, stats.frees), (1, 2, 2, 0));
+        assert_eq!(stats.object_size, 8);
+        assert!(stats.capacity > 2);
+
+        // A freed slot is the next one handed out
+        let slot = &*a as *const u64;
+        drop(a);
+        assert_eq!(cache.stats().in_use, 1);
+        let c = cache.alloc(3).unwrap();
+        assert_eq!(&*c as *const u64, slot);
+        assert_eq!(*b, 2);
+
+        drop(b);
+        drop(c);
+        assert_eq!(
+            cache.stats(),
+            SlabStats { name: "test", object_size: 8, slabs: 1, capacity: stats.capacity, in_use: 0, allocs: 3, frees: 3 }
+        );
+
+        // The last slab is kept
+        assert_eq!(frames.released.get(), 0);
+    }
+
+    #[test]
+    fn test_drops_objects() {
+        let cache = cache::<Rc<()>>(TestFrames::new(1));
+
+        let rc = Rc::new(());
+        let object = cache.alloc(rc.clone()).unwrap();
+        assert_eq!(Rc::strong_count(&rc), 2);
+        drop(object);
+        assert_eq!(Rc::strong_count(&rc), 1);
+    }
+
+    #[test]
+    fn test_slab_release() {
+        let frames = TestFrames::new(4);
+        let cache = cache::<Large>(frames);
+
+        let mut objects: Vec<_> = (0..4).map(|i| cache.alloc([i; PAGE_SIZE / 32]).unwrap()).collect();
+        let stats = cache.stats();
+        assert_eq!((stats.slabs, stats.capacity, stats.in_use), (2, 6, 4));
+
+        // The second slab is given back once its only object is freed
+        objects.pop();
+        assert_eq!(frames.released.get(), 1);
+        assert_eq!((cache.stats().slabs, cache.stats().capacity), (1, 3));
+
+        for (i, object) in objects.iter().enumerate() {
+            assert!(object.iter().all(|&word| word == i as u64));
+        }
+
+        // But the first one is kept once empty
+        objects.clear();
+        assert_eq!(frames.released.get(), 1);
+        assert_eq!(cache.stats().slabs, 1);
+        assert_eq!(cache.stats().in_use, 0);
+    }
+
+    #[test]
+    fn test_out_of_frames() {
+        let frames = TestFrames::new(1);
+        let cache = cache::<Large>(frames);
+
+        let objects: Vec<_> = (0..3).map(|_| cache.alloc([0; PAGE_SIZE / 32]).unwrap()).collect();
+        assert!(cache.alloc([0; PAGE_SIZE / 32]).is_none());
+        assert_eq!(cache.stats().slabs, 1);
+        assert_eq!(cache.stats().allocs, 3);
+
+        // A freed slot is enough for the next object
+        drop(objects);
+        assert!(cache.alloc([0; PAGE_SIZE / 32]).is_some());
+    }
+}
+
+mod memory_map {
+    use crate::allocator::{MemoryKind, MemoryMap, MemoryRegion};
+
+    fn regions(map: &MemoryMap) -> Vec<(usize, usize, MemoryKind)> {
+        map.regions().iter().map(|&MemoryRegion { start, end, kind }| (start, end, kind)).collect()
+    }
+
+    #[test]
+    fn test_add() {
+        let mut map = MemoryMap::new();
+        assert_eq!(map.end(), 0);
+
+        map.add(0x4000, 0x8000);
+        map.add(0x1000, 0x2000);
+        map.add(0x1800, 0x5000);
+        map.add(0x3000, 0x3000);
+        assert_eq!(
+            regions(&map),
+            vec![
+                (0x1000, 0x2000, MemoryKind::Usable),
+                (0x2000, 0x4000, MemoryKind::Usable),
+                (0x4000, 0x8000, MemoryKind::Usable),
+            ]
+        );
+        assert_eq!(map.end(), 0x8000);
+    }
+
+    #[test]
+    fn test_reserve() {
+        let mut map = MemoryMap::new();
+        map.add(0, 0x4000);
+        map.add(0x8000, 0x10000);
+
+        map.reserve(0x1000, 0x2000);
+        map.reserve(0x3000, 0x9000);
+        map.reserve(0x1800, 0x2800);
+        map.reserve(0x20000, 0x30000);
+        assert_eq!(
+            regions(&map),
+            vec![
+                (0, 0x1000, MemoryKind::Usable),
+                (0x1000, 0x2000, MemoryKind::Reserved),
+                (0x2000, 0x2800, MemoryKind::Reserved),
+                (0x2800, 0x3000, MemoryKind::Usable),
+                (0x3000, 0x4000, MemoryKind::Reserved),
+                (0x8000, 0x9000, MemoryKind::Reserved),
+                (0x9000, 0x10000, MemoryKind::Usable),
+            ]
+        );
+
+        let usable: Vec<_> = map.usable().collect();
+        assert_eq!(usable, vec![(0, 0x1000), (0x2800, 0x3000), (0x9000, 0x10000)]);
+    }
 }
 
 mod linked_list {
@@ -7,10 +7,10 @@ use shim::ioerr;
 use shim::path::Path;
 
 pub use fat32::traits;
-use fat32::vfat::{Dir, Entry, File, VFat, VFatHandle};
+use fat32::vfat::{CacheStats, Dir, Entry, File, VFat, VFatHandle};
 
 use self::sd::Sd;
-use crate::mutex::Mutex;
+use crate::mutex::{Mutex, MutexGuard};
 
 #[derive(Clone)]
 pub struct PiVFatHandle(Rc<Mutex<VFat<Self>>>);
@@ -38,6 +38,34 @@ impl VFatHandle for PiVFatHandle {
         f(&mut self.0.lock())
     }
 }
+/// A file opened by a process, shared by the file descriptors that refer to
+/// it, including those a forked child inherits. They share its offset, and
+/// every write sees the size and clusters the previous ones left.
+#[derive(Clone)]
+pub struct OpenFile(Rc<Mutex<File<PiVFatHandle>>>);
+
+// Unsound for the same reason as the impls for `PiVFatHandle` above.
+unsafe impl Send for OpenFile {}
+unsafe impl Sync for OpenFile {}
+
+impl OpenFile {
+    /// Returns a new `OpenFile` holding `file`.
+    pub fn new(file: File<PiVFatHandle>) -> OpenFile {
+        return OpenFile(Rc::new(Mutex::new(file)));
+    }
+
+    /// Locks the file for a read, write or seek.
+    pub fn lock(&self) -> MutexGuard<File<PiVFatHandle>> {
+        return self.0.lock();
+    }
+}
+
+impl Debug for OpenFile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "OpenFile")
+    }
+}
+
 pub struct FileSystem(Mutex<Option<PiVFatHandle>>);
 
 impl FileSystem {
@@ -62,6 +90,16 @@ impl FileSystem {
         *self.0.lock() = Some(PiVFatHandle::from(vfat));
     }
 
+    /// Returns the sector cache's counters.
+    pub fn cache_stats(&self) -> CacheStats {
+        return self.0.lock().as_ref().unwrap().lock(|vfat| vfat.cache_stats());
+    }
+
+    /// Writes every dirty cached sector back to the disk.
+    pub fn sync(&self) -> io::Result<()> {
+        return self.0.lock().as_ref().unwrap().lock(|vfat| vfat.flush());
+    }
+
 //     pub unsafe fn vfat(&self) -> &mut VFat<PiVFatHandle> {
 //         self.0.lock().expect("Expected vfat").lock(|vfat| { vfat })
 //     }
@@ -75,4 +113,20 @@ impl fat32::traits::FileSystem for &FileSystem {
     fn open<P: AsRef<Path>>(self, path: P) -> io::Result<Self::Entry> {
         return self.0.lock().as_ref().unwrap().open(path);
     }
+
+    fn create_file<P: AsRef<Path>>(self, path: P) -> io::Result<Self::File> {
+        return self.0.lock().as_ref().unwrap().create_file(path);
+    }
+
+    fn create_dir<P: AsRef<Path>>(self, path: P) -> io::Result<Self::Dir> {
+        return self.0.lock().as_ref().unwrap().create_dir(path);
+    }
+
+    fn remove<P: AsRef<Path>>(self, path: P) -> io::Result<()> {
+        return self.0.lock().as_ref().unwrap().remove(path);
+    }
+
+    fn rename<P: AsRef<Path>, Q: AsRef<Path>>(self, from: P, to: Q) -> io::Result<()> {
+        return self.0.lock().as_ref().unwrap().rename(from, to);
+    }
 }
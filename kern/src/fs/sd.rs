@@ -1,63 +1,36 @@
-use core::time::Duration;
 use shim::io;
-use shim::ioerr;
-use pi::timer;
+use pi::emmc::{self, Emmc, BLOCK_SIZE, MAX_BLOCKS};
 
 use fat32::traits::BlockDevice;
 
-extern "C" {
-    /// A global representing the last SD controller error that occured.
-    static sd_err: i64;
-
-    /// Initializes the SD card controller.
-    ///
-    /// Returns 0 if initialization is successful. If initialization fails,
-    /// returns -1 if a timeout occured, or -2 if an error sending commands to
-    /// the SD controller occured.
-    fn sd_init() -> i32;
-
-    /// Reads sector `n` (512 bytes) from the SD card and writes it to `buffer`.
-    /// It is undefined behavior if `buffer` does not point to at least 512
-    /// bytes of memory. Also, the caller of this function should make sure that
-    /// `buffer` is at least 4-byte aligned.
-    ///
-    /// On success, returns the number of bytes read: a positive number.
-    ///
-    /// On error, returns 0. The true error code is stored in the `sd_err`
-    /// global. `sd_err` will be set to -1 if a timeout occured or -2 if an
-    /// error sending commands to the SD controller occured. Other error codes
-    /// are also possible but defined only as being less than zero.
-    fn sd_readsector(n: i32, buffer: *mut u8) -> i32;
+/// A handle to an SD card controller.
+pub struct Sd {
+    emmc: Emmc,
 }
 
-// FIXME: Define a `#[no_mangle]` `wait_micros` function for use by `libsd`.
-// The `wait_micros` C signature is: `void wait_micros(unsigned int);`
-#[no_mangle]
-fn wait_micros(micros: u32) {
-    let micros = micros * 100;
-    let duration = Duration::from_micros(micros.into());
-    timer::spin_sleep(duration);
+/// Maps an EMMC error to an I/O error.
+fn emmc_error(err: emmc::Error) -> io::Error {
+    match err {
+        emmc::Error::Timeout => io::Error::new(io::ErrorKind::TimedOut, "Sd card timed out"),
+        emmc::Error::Command => io::Error::new(io::ErrorKind::Other, "Error sending commands"),
+    }
 }
 
-/// A handle to an SD card controller.
-#[derive(Debug)]
-pub struct Sd;
-
 impl Sd {
     /// Initializes the SD card controller and returns a handle to it.
     /// The caller should assure that the method is invoked only once during the
     /// kernel initialization. We can enforce the requirement in safe Rust code
     /// with atomic memory access, but we can't use it yet since we haven't
     /// written the memory management unit (MMU).
+    ///
+    /// # Errors
+    ///
+    /// An error of kind `TimedOut` is returned if the controller or the card
+    /// stops responding and of kind `Other` if the card could not be
+    /// initialized.
     pub unsafe fn new() -> Result<Sd, io::Error> {
-        let res = unsafe { sd_init() };
-
-        match res {
-            0 => Ok(Sd),
-            -1 => Err(io::Error::new(io::ErrorKind::Other, "Timeout occurred")),
-            -2 => Err(io::Error::new(io::ErrorKind::Other, "Error sending commands to sd controller")),
-            _ => panic!("Yeah we fucked up"),
-        }
+        let emmc = Emmc::new().map_err(emmc_error)?;
+        return Ok(Sd { emmc });
     }
 }
 
@@ -75,27 +48,94 @@ impl BlockDevice for Sd {
     ///
     /// An error of kind `Other` is returned for all other errors.
     fn read_sector(&mut self, n: u64, buf: &mut [u8]) -> io::Result<usize> {
-        assert_eq!(buf.len() % 4, 0);
+        if buf.len() < BLOCK_SIZE || n > (1 << 31) - 1 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid size of buffer"));
+        }
+
+        self.emmc.read_blocks(n as u32, &mut buf[..BLOCK_SIZE]).map_err(emmc_error)?;
+
+        return Ok(BLOCK_SIZE);
+    }
 
-        if buf.len() < 512 || n > 1<<31 - 1 {
+    /// Reads the consecutive sectors starting at sector `n` into `buf` using
+    /// `CMD18` multi-block transfers (or `CMD17` for a single sector). On
+    /// success, the number of bytes read is returned.
+    ///
+    /// # Errors
+    ///
+    /// An I/O error of kind `InvalidInput` is returned if `buf.len()` is not
+    /// a non-zero multiple of 512 or if any sector is beyond `2^31 - 1`.
+    ///
+    /// The other errors are the same as for `read_sector()`.
+    fn read_sectors(&mut self, n: u64, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.len() == 0 || buf.len() % BLOCK_SIZE != 0 {
             return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid size of buffer"));
         }
 
-        let size = unsafe { sd_readsector(n as i32, &mut buf[0] as *mut u8) };
+        let count = (buf.len() / BLOCK_SIZE) as u64;
+        if n + count - 1 > (1 << 31) - 1 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Sector out of range"));
+        }
 
-        if size == 0 {
-            match unsafe { sd_err } {
-                -1 => return Err(io::Error::new(io::ErrorKind::TimedOut, "Sd card timed out")),
-                -2 => return Err(io::Error::new(io::ErrorKind::Other, "Error sending commands")),
-                _ => return Err(io::Error::new(io::ErrorKind::Other, "Unknown error occurred")),
-            }
+        for (i, chunk) in buf.chunks_mut(MAX_BLOCKS * BLOCK_SIZE).enumerate() {
+            let sector = n + (i * MAX_BLOCKS) as u64;
+            self.emmc.read_blocks(sector as u32, chunk).map_err(emmc_error)?;
         }
 
-        return Ok(size as usize);
+        return Ok(buf.len());
     }
 
-    /// Function to implement writing to SD card
-    fn write_sector(&mut self, _n: u64, _buf: &[u8]) -> io::Result<usize> {
-        unimplemented!("SD card and file system are read only")
+    /// Writes the first 512 bytes of `buf` to sector `n` with `CMD24`. On
+    /// success, the number of bytes written is returned.
+    ///
+    /// # Errors
+    ///
+    /// An I/O error of kind `UnexpectedEof` is returned if `buf.len() < 512`
+    /// and of kind `InvalidInput` if `n > 2^31 - 1`.
+    ///
+    /// An error of kind `TimedOut` is returned if a timeout occurs while
+    /// writing to the SD card.
+    ///
+    /// An error of kind `Other` is returned for all other errors.
+    fn write_sector(&mut self, n: u64, buf: &[u8]) -> io::Result<usize> {
+        if buf.len() < BLOCK_SIZE {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Buffer smaller than a sector"));
+        }
+
+        return self.write_sectors(n, &buf[..BLOCK_SIZE]);
+    }
+
+    /// Writes `buf` to the consecutive sectors starting at sector `n` using
+    /// `CMD25` multi-block transfers (or `CMD24` for a single sector). On
+    /// success, the number of bytes written is returned.
+    ///
+    /// # Errors
+    ///
+    /// An I/O error of kind `InvalidInput` is returned if `buf.len()` is not
+    /// a non-zero multiple of 512 or if any sector is beyond `2^31 - 1`.
+    ///
+    /// The other errors are the same as for `write_sector()`.
+    fn write_sectors(&mut self, n: u64, buf: &[u8]) -> io::Result<usize> {
+        if buf.len() == 0 || buf.len() % BLOCK_SIZE != 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid size of buffer"));
+        }
+
+        let count = (buf.len() / BLOCK_SIZE) as u64;
+        if n + count - 1 > (1 << 31) - 1 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Sector out of range"));
+        }
+
+        for (i, chunk) in buf.chunks(MAX_BLOCKS * BLOCK_SIZE).enumerate() {
+            let sector = n + (i * MAX_BLOCKS) as u64;
+            self.emmc.write_blocks(sector as u32, chunk).map_err(emmc_error)?;
+        }
+
+        return Ok(buf.len());
+    }
+}
+
+impl core::fmt::Debug for Sd {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.debug_struct("Sd").finish()
     }
 }
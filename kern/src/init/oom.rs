@@ -1,6 +1,9 @@
 use core::alloc::Layout;
 
+/// Called when an allocation that cannot fail runs out of kernel heap,
+/// reserve included. Exhausting the main heap only makes the scheduler kill
+/// the largest process at the next switch; see `Allocator::take_exhausted()`.
 #[alloc_error_handler]
-pub fn oom(_layout: Layout) -> ! {
-    panic!("OOM");
+pub fn oom(layout: Layout) -> ! {
+    panic!("OOM: no kernel heap left for {} bytes", layout.size());
 }
@@ -1,3 +1,3 @@
 mod handlers;
 
-pub use self::handlers::{timer_handler};
+pub use self::handlers::{alarm_handler, timer_handler};
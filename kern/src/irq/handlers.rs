@@ -3,18 +3,23 @@ use alloc::string::String;
 use crate::console::{kprintln};
 use crate::shell;
 use crate::SCHEDULER;
+use crate::TIMERS;
 use crate::IRQ;
-use crate::param::{TICK};
 use crate::traps::TrapFrame;
 use crate::process::{State};
 
 use pi::interrupt::{Interrupt, Controller};
 
-use pi::timer;
-
 #[no_mangle]
 pub fn timer_handler(tf: &mut TrapFrame) {
-    timer::tick_in(TICK);
-
     SCHEDULER.switch(State::Ready, tf);
 }
+
+/// Runs the kernel timers that expired. A timer may have woken up a process,
+/// so the scheduler gets to choose again right away rather than at the end
+/// of the current `TICK`.
+pub fn alarm_handler(tf: &mut TrapFrame) {
+    if TIMERS.run_expired() > 0 {
+        SCHEDULER.switch(State::Ready, tf);
+    }
+}
@@ -21,24 +21,28 @@ pub mod mutex;
 pub mod shell;
 pub mod param;
 pub mod process;
+pub mod timers;
 pub mod traps;
 pub mod vm;
 pub mod irq;
 
 use console::kprintln;
 
-use allocator::Allocator;
+use allocator::{Allocator, FrameAllocator};
 use fs::FileSystem;
 use process::GlobalScheduler;
+use timers::GlobalTimers;
 use traps::irq::Irq;
 use vm::VMManager;
 
 #[cfg_attr(not(test), global_allocator)]
 pub static ALLOCATOR: Allocator = Allocator::uninitialized();
+pub static FRAMES: FrameAllocator = FrameAllocator::uninitialized();
 pub static FILESYSTEM: FileSystem = FileSystem::uninitialized();
 pub static SCHEDULER: GlobalScheduler = GlobalScheduler::uninitialized();
 pub static VMM: VMManager = VMManager::uninitialized();
 pub static IRQ: Irq = Irq::uninitialized();
+pub static TIMERS: GlobalTimers = GlobalTimers::uninitialized();
 
 use core::time::Duration;
 use pi::timer;
@@ -58,9 +62,11 @@ fn kmain() -> ! {
     kprintln!("Hello and welcome to hhOS 1.0.0");
 
     unsafe {
+        FRAMES.initialize();
         ALLOCATOR.initialize();
         FILESYSTEM.initialize();
         IRQ.initialize();
+        TIMERS.initialize();
         VMM.initialize();
         SCHEDULER.initialize();
         SCHEDULER.start();
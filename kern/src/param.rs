@@ -20,8 +20,22 @@ const_assert_eq!(
 );
 pub const USER_STACK_BASE: usize = core::usize::MAX & PAGE_MASK; 
 pub const USER_MAX_VM_SIZE: usize = 0x4000_0000;
+/// The maximum size of a user stack. Its first page, at `USER_STACK_BASE`,
+/// is mapped when a program is loaded and the pages below it on demand.
+pub const USER_STACK_SIZE: usize = 16 * PAGE_SIZE;
+const_assert_eq!(USER_STACK_SIZE % PAGE_SIZE, 0);
 const_assert_eq!(USER_IMG_BASE.wrapping_add(USER_MAX_VM_SIZE), 0);
 pub const KERN_STACK_BASE: usize = 0x80_000;
+/// The size of the kernel heap, which is carved out of the page frames.
+pub const KERNEL_HEAP_SIZE: usize = 64 * 1024 * 1024;
+const_assert_eq!(KERNEL_HEAP_SIZE % PAGE_SIZE, 0);
+/// The size of the end of the kernel heap that is only allocated from once
+/// the rest is exhausted, until the largest process is killed to make room.
+pub const KERNEL_HEAP_RESERVE: usize = 2 * 1024 * 1024;
+const_assert_eq!(KERNEL_HEAP_RESERVE % PAGE_SIZE, 0);
+
+/// The maximum number of files a process can have open at once.
+pub const MAX_OPEN_FILES: usize = 16;
 
 /// The `tick` time.
 pub const TICK: Duration = Duration::from_millis(10);
@@ -1,10 +1,17 @@
+pub mod elf;
 mod process;
+mod runqueue;
 mod scheduler;
 mod stack;
 mod state;
+mod text;
+mod waitqueue;
 
-pub use self::process::{Id, Process};
-pub use self::scheduler::GlobalScheduler;
+pub use self::process::{Id, Process, PROCESSES};
+pub use self::scheduler::{GlobalScheduler, IdleStats};
 pub use self::stack::Stack;
-pub use self::state::State;
+pub use self::state::{Event, State};
 pub use crate::param::TICK;
+
+#[cfg(test)]
+mod tests;
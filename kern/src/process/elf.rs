@@ -0,0 +1,209 @@
+use alloc::vec::Vec;
+use core::convert::TryInto;
+
+use kernel_api::{OsError, OsResult};
+use shim::io::{self, SeekFrom};
+
+use crate::vm::PagePerm;
+
+/// The magic bytes at the start of every ELF file.
+const MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+/// `e_ident[EI_CLASS]` of a 64-bit object.
+const CLASS_64: u8 = 2;
+/// `e_ident[EI_DATA]` of a little-endian object.
+const DATA_LSB: u8 = 1;
+/// The only defined ELF version.
+const VERSION_CURRENT: u8 = 1;
+/// `e_type` of an executable file.
+const TYPE_EXEC: u16 = 2;
+/// `e_machine` of an AArch64 object.
+const MACHINE_AARCH64: u16 = 183;
+
+/// Size of the ELF64 file header.
+const HEADER_SIZE: usize = 64;
+/// Size of an ELF64 program header.
+const PROGRAM_HEADER_SIZE: usize = 56;
+/// `p_type` of a loadable segment.
+const PT_LOAD: u32 = 1;
+
+/// Segment flag: executable.
+pub const PF_X: u32 = 1 << 0;
+/// Segment flag: writable.
+pub const PF_W: u32 = 1 << 1;
+/// Segment flag: readable.
+pub const PF_R: u32 = 1 << 2;
+
+/// A loadable (`PT_LOAD`) segment of an ELF executable.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Segment {
+    /// The virtual address the segment is mapped at.
+    pub vaddr: u64,
+    /// The offset of the segment's contents in the file.
+    pub offset: u64,
+    /// The number of bytes of the segment stored in the file.
+    pub file_size: u64,
+    /// The size of the segment in memory. Bytes past `file_size` are zero.
+    pub mem_size: u64,
+    /// The `PF_*` flags of the segment.
+    pub flags: u32,
+}
+
+impl Segment {
+    /// Returns the virtual address one past the end of the segment.
+    pub fn end(&self) -> u64 {
+        return self.vaddr + self.mem_size;
+    }
+
+    /// Returns the page permission matching the segment's flags.
+    pub fn perm(&self) -> PagePerm {
+        return page_perm(self.flags);
+    }
+}
+
+/// Returns the page permission granting the accesses in the `PF_*` `flags`.
+pub fn page_perm(flags: u32) -> PagePerm {
+    match (flags & PF_W != 0, flags & PF_X != 0) {
+        (true, true) => PagePerm::RWX,
+        (true, false) => PagePerm::RW,
+        (false, true) => PagePerm::RX,
+        (false, false) => PagePerm::RO,
+    }
+}
+
+/// A validated ELF64 little-endian AArch64 executable.
+#[derive(Debug)]
+pub struct Elf {
+    entry: u64,
+    segments: Vec<Segment>,
+}
+
+impl Elf {
+    /// Reads and parses the headers of the executable `file`. Only the file
+    /// header and the program headers are read; the segments' contents are
+    /// left in the file.
+    ///
+    /// Every loadable segment is checked to be backed by `file` and to fit
+    /// in the address space, and the entry point must lie in an executable
+    /// segment. Segments that occupy no memory are dropped.
+    ///
+    /// # Errors
+    ///
+    /// Returns `InvalidArgument` if `file` is not a well-formed ELF64
+    /// little-endian AArch64 executable, or the error of a failed read.
+    pub fn read<R: io::Read + io::Seek>(file: &mut R) -> OsResult<Elf> {
+        let size = file.seek(SeekFrom::End(0))?;
+
+        let mut data = [0u8; HEADER_SIZE];
+        read_at(file, size, 0, &mut data)?;
+        if data[0..4] != MAGIC {
+            return Err(OsError::InvalidArgument);
+        }
+
+        if data[4] != CLASS_64 || data[5] != DATA_LSB || data[6] != VERSION_CURRENT {
+            return Err(OsError::InvalidArgument);
+        }
+
+        if read_u16(&data, 16)? != TYPE_EXEC || read_u16(&data, 18)? != MACHINE_AARCH64 {
+            return Err(OsError::InvalidArgument);
+        }
+
+        let entry = read_u64(&data, 24)?;
+        let phoff = read_u64(&data, 32)?;
+        let phentsize = read_u16(&data, 54)? as usize;
+        let phnum = read_u16(&data, 56)? as u64;
+
+        if phnum > 0 && phentsize != PROGRAM_HEADER_SIZE {
+            return Err(OsError::InvalidArgument);
+        }
+
+        let mut segments = Vec::new();
+        for i in 0..phnum {
+            let start = (i * PROGRAM_HEADER_SIZE as u64)
+                .checked_add(phoff)
+                .ok_or(OsError::InvalidArgument)?;
+            let mut header = [0u8; PROGRAM_HEADER_SIZE];
+            read_at(file, size, start, &mut header)?;
+
+            if read_u32(&header, 0)? != PT_LOAD {
+                continue;
+            }
+
+            let segment = Segment {
+                flags: read_u32(&header, 4)?,
+                offset: read_u64(&header, 8)?,
+                vaddr: read_u64(&header, 16)?,
+                file_size: read_u64(&header, 32)?,
+                mem_size: read_u64(&header, 40)?,
+            };
+
+            let file_end = segment.offset.checked_add(segment.file_size);
+            if file_end.map_or(true, |end| end > size) {
+                return Err(OsError::InvalidArgument);
+            }
+            if segment.file_size > segment.mem_size {
+                return Err(OsError::InvalidArgument);
+            }
+            if segment.vaddr.checked_add(segment.mem_size).is_none() {
+                return Err(OsError::InvalidArgument);
+            }
+
+            if segment.mem_size > 0 {
+                segments.push(segment);
+            }
+        }
+
+        let executable = segments.iter().any(|segment| {
+            segment.flags & PF_X != 0 && segment.vaddr <= entry && entry < segment.end()
+        });
+        if !executable {
+            return Err(OsError::InvalidArgument);
+        }
+
+        return Ok(Elf { entry, segments });
+    }
+
+    /// Returns the virtual address of the first instruction.
+    pub fn entry(&self) -> u64 {
+        return self.entry;
+    }
+
+    /// Returns the loadable segments in the order they appear in the file.
+    pub fn segments(&self) -> &[Segment] {
+        return &self.segments;
+    }
+}
+
+/// Reads `buf.len()` bytes at `offset` of `file`, which is `size` bytes long.
+/// Returns `InvalidArgument` if they are out of bounds.
+fn read_at<R: io::Read + io::Seek>(file: &mut R, size: u64, offset: u64, buf: &mut [u8]) -> OsResult<()> {
+    let end = offset.checked_add(buf.len() as u64).ok_or(OsError::InvalidArgument)?;
+    if end > size {
+        return Err(OsError::InvalidArgument);
+    }
+
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(buf)?;
+    return Ok(());
+}
+
+/// Returns the `len` bytes at `offset` of `data`, or `InvalidArgument` if they
+/// are out of bounds.
+fn read_bytes<'a>(data: &'a [u8], offset: usize, len: usize) -> OsResult<&'a [u8]> {
+    let end = offset.checked_add(len).ok_or(OsError::InvalidArgument)?;
+    return data.get(offset..end).ok_or(OsError::InvalidArgument);
+}
+
+fn read_u16(data: &[u8], offset: usize) -> OsResult<u16> {
+    let bytes = read_bytes(data, offset, 2)?;
+    return Ok(u16::from_le_bytes(bytes.try_into().unwrap()));
+}
+
+fn read_u32(data: &[u8], offset: usize) -> OsResult<u32> {
+    let bytes = read_bytes(data, offset, 4)?;
+    return Ok(u32::from_le_bytes(bytes.try_into().unwrap()));
+}
+
+fn read_u64(data: &[u8], offset: usize) -> OsResult<u64> {
+    let bytes = read_bytes(data, offset, 8)?;
+    return Ok(u64::from_le_bytes(bytes.try_into().unwrap()));
+}
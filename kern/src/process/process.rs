@@ -1,34 +1,61 @@
 use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
 use shim::io;
 use shim::path::Path;
 use core::mem;
+use core::time::Duration;
 
 use aarch64;
 
 use crate::param::*;
-use crate::process::{Stack, State};
-use crate::traps::TrapFrame;
+use crate::process::elf::{self, Elf};
+use crate::process::runqueue::{RealTime, Task};
+use crate::process::text::TEXT_CACHE;
+use crate::process::{Event, Stack, State};
+use crate::allocator::{SlabBox, SlabCache};
+use crate::traps::{TrapFrame, TRAP_FRAMES};
 use crate::vm::*;
-use kernel_api::{OsError, OsResult};
+use kernel_api::{OsError, OsResult, PRIORITY_DEFAULT};
 
-use crate::FILESYSTEM;
+use crate::fs::{OpenFile, PiVFatHandle};
+use crate::{FILESYSTEM, FRAMES};
 use fat32::traits::FileSystem as FileSystemTrait;
-use shim::io::Read;
+use fat32::vfat::File;
+use fat32::traits::{File as FileTrait, Metadata};
+use shim::io::{Read, Seek, SeekFrom, Write};
 
 /// Type alias for the type of a process ID.
 pub type Id = u64;
 
+/// The cache holding the processes known to the scheduler.
+pub static PROCESSES: SlabCache<Process> = SlabCache::new("process", &FRAMES);
+
 /// A structure that represents the complete state of a process.
 #[derive(Debug)]
 pub struct Process {
     /// The saved trap frame of a process.
-    pub context: Box<TrapFrame>,
+    pub context: SlabBox<TrapFrame>,
     /// The memory allocation used for the process's stack.
     pub stack: Stack,
     /// The page table describing the Virtual Memory of the process
     pub vmap: Box<UserPageTable>,
     /// The scheduling state of the process.
     pub state: State,
+    /// The files opened by the process, indexed by file descriptor.
+    pub files: Vec<Option<OpenFile>>,
+    /// The ID of the process that forked this one, while it is alive.
+    pub parent: Option<Id>,
+    /// The IDs and exit codes of children that have exited but have not been
+    /// waited for yet.
+    pub exited: Vec<(Id, i32)>,
+    /// The memory regions whose pages are allocated on demand.
+    pub regions: Vec<Region>,
+    /// The static scheduling priority; 0 is the most urgent.
+    pub priority: usize,
+    /// The CPU time reservation if this is a real-time process.
+    pub realtime: Option<RealTime>,
 }
 
 impl Process {
@@ -36,7 +63,7 @@ impl Process {
     /// stack of the default size, and a state of `Ready`.
     ///
     /// If enough memory could not be allocated to start the process, returns
-    /// `None`. Otherwise returns `Some` of the new `Process`.
+    /// `NoMemory`. Otherwise returns `Ok` of the new `Process`.
     pub fn new() -> OsResult<Process> {
         let stack_res = Stack::new();
         let stack;
@@ -51,34 +78,74 @@ impl Process {
         }
 
         let state = State::Ready;
-        let tf = Box::new(TrapFrame::zeroed());
-        let vmap = Box::new(UserPageTable::new()); 
+        let tf = TRAP_FRAMES.alloc(TrapFrame::zeroed()).ok_or(OsError::NoMemory)?;
+        let vmap = Box::new(UserPageTable::new()?);
 
         return Ok(Process {
             context: tf,
             stack: stack,
             state: state,
             vmap: vmap,
+            files: Vec::new(),
+            parent: None,
+            exited: Vec::new(),
+            regions: Vec::new(),
+            priority: PRIORITY_DEFAULT as usize,
+            realtime: None,
         });
     }
 
+    /// Creates a child of this process for the `fork` system call. `tf` is
+    /// the trap frame of this process at the time of the call.
+    ///
+    /// The child shares the process's memory copy-on-write, so a page is
+    /// only copied when one of them first writes to it. It gets the same
+    /// registers except that `x0` is zero, and shares the process's open
+    /// files, offsets included. The child's ID is assigned when it is added
+    /// to the scheduler.
+    ///
+    /// Returns `NoMemory` if the child could not be allocated.
+    pub fn fork(&mut self, tf: &TrapFrame) -> OsResult<Process> {
+        let mut child = Process::new()?;
+
+        child.vmap = Box::new(self.vmap.clone_cow()?);
+        child.files = self.files.clone();
+        child.regions = self.regions.clone();
+        child.parent = Some(tf.tpidr);
+        child.priority = self.priority;
+
+        *child.context = *tf;
+        child.context.ttbr1 = child.vmap.get_baddr().as_u64();
+        child.context.x_regs[0] = 0;
+        child.context.x_regs[7] = OsError::Ok as u64;
+
+        return Ok(child);
+    }
+
     /// Load a program stored in the given path by calling `do_load()` method.
     /// Set trapframe `context` corresponding to the its page table.
     /// `sp` - the address of stack top
-    /// `elr` - the address of image base.
+    /// `elr` - the entry point of the program.
     /// `ttbr0` - the base address of kernel page table
     /// `ttbr1` - the base address of user page table
     /// `spsr` - `F`, `A`, `D` bit should be set.
     ///
     /// Returns Os Error if do_load fails.
     pub fn load<P: AsRef<Path>>(pn: P) -> OsResult<Process> {
+        return Process::load_with_args(pn, &[]);
+    }
+
+    /// Like `load()`, but also passes `args` to the program: they are copied
+    /// to the top of its stack, `x0` is set to their number and `x1` to the
+    /// address of an array of `[address, length]` pairs describing them.
+    ///
+    /// Returns `InvalidArgument` if the arguments do not fit in half of the
+    /// stack page.
+    pub fn load_with_args<P: AsRef<Path>>(pn: P, args: &[String]) -> OsResult<Process> {
         use crate::VMM;
 
-        let mut p = Process::do_load(pn)?;
+        let mut p = Process::do_load(pn, args)?;
 
-        //FIXME: Set trapframe for the process.
-        p.context.sp = Process::get_stack_top().as_u64();
-        p.context.elr = USER_IMG_BASE as u64;
         p.context.spsr = 0x0000_0340;
         p.context.ttbr0 = VMM.get_baddr().as_u64();
         p.context.ttbr1 = p.vmap.get_baddr().as_u64();
@@ -86,33 +153,124 @@ impl Process {
         Ok(p)
     }
 
-    /// Creates a process and open a file with given path.
-    /// Allocates one page for stack with read/write permission, and N pages with read/write/execute
-    /// permission to load file's contents.
-    fn do_load<P: AsRef<Path>>(pn: P) -> OsResult<Process> {
+    /// Creates a process and open a file with given path, which must be an
+    /// ELF64 AArch64 executable. Allocates one page for stack with read/write
+    /// permission, and maps every loadable segment at its virtual address
+    /// with the permission given by its flags, zero-filling the bytes not
+    /// stored in the file. Pushes `args` onto the stack and sets `sp`, `x0`,
+    /// `x1` and `elr` (the entry point) accordingly.
+    ///
+    /// Returns `InvalidArgument` if the file is not a valid executable or a
+    /// segment lies outside of the user image area below the stack.
+    fn do_load<P: AsRef<Path>>(pn: P, args: &[String]) -> OsResult<Process> {
         let mut process = Process::new()?;
 
         // Allocate one page for stack
-        let stack = process.vmap.alloc(Process::get_stack_base(), PagePerm::RW);
+        let stack = process.vmap.alloc(Process::get_stack_base(), PagePerm::RW)?;
+        let (sp, argv) = Process::push_args(stack, args)?;
+        process.context.sp = sp;
+        process.context.x_regs[0] = args.len() as u64;
+        process.context.x_regs[1] = argv;
         
-        let mut file = FILESYSTEM.open_file(pn)?;
+        let mut file = FILESYSTEM.open_file(pn.as_ref())?;
+        let elf = Elf::read(&mut file)?;
+
+        // Pages shared by several segments get the union of their flags
+        let mut pages: BTreeMap<usize, u32> = BTreeMap::new();
+        for segment in elf.segments() {
+            if segment.vaddr < USER_IMG_BASE as u64 || segment.end() > USER_STACK_BASE as u64 {
+                return Err(OsError::InvalidArgument);
+            }
 
-        if file.size > PAGE_SIZE as u64 {
-            unimplemented!("User programs must be less than {} bytes", PAGE_SIZE);
+            let mut page = segment.vaddr as usize & PAGE_MASK;
+            while page < segment.end() as usize {
+                *pages.entry(page).or_insert(0) |= segment.flags;
+                page += PAGE_SIZE;
+            }
+        }
+
+        for (&page, &flags) in pages.iter() {
+            process.vmap.alloc(VirtualAddr::from(page), elf::page_perm(flags))?;
         }
 
-        let mut bytes: u64 = 0;
+        // Read the file-backed part of each segment, a page at a time
+        for segment in elf.segments() {
+            let len = segment.file_size as usize;
+            let mut copied = 0;
+
+            file.seek(SeekFrom::Start(segment.offset))?;
+            while copied < len {
+                let va = segment.vaddr as usize + copied;
+                let offset = va & !PAGE_MASK;
+                let size = core::cmp::min(PAGE_SIZE - offset, len - copied);
 
-        // Read a page at a time
-        while bytes < file.size {
-            let mut page = process.vmap.alloc(VirtualAddr::from(USER_IMG_BASE), PagePerm::RWX);
-            let size = file.read(page)?;
-            bytes += size as u64;
+                let page = process.vmap.get_page(VirtualAddr::from(va & PAGE_MASK))
+                    .expect("segment page is mapped");
+                file.read_exact(&mut page[offset..offset + size])?;
+                copied += size;
+            }
         }
 
+        // Instances of the same program share their read-only pages
+        if let Some(path) = pn.as_ref().to_str() {
+            TEXT_CACHE.prune();
+            for (&page, &flags) in pages.iter() {
+                if flags & elf::PF_W == 0 {
+                    TEXT_CACHE.share(path, VirtualAddr::from(page), &mut process.vmap);
+                }
+            }
+        }
+
+        process.context.elr = elf.entry();
+
+        // The heap starts empty right after the image; the stack grows down
+        // from its first page
+        let heap = pages.keys().next_back().map_or(USER_IMG_BASE, |&page| page + PAGE_SIZE);
+        process.regions.push(Region::new(RegionKind::Heap, heap, heap, PagePerm::RW));
+        process.regions.push(Region::new(
+            RegionKind::Stack,
+            USER_STACK_BASE - (USER_STACK_SIZE - PAGE_SIZE),
+            USER_STACK_BASE,
+            PagePerm::RW,
+        ));
+
         return Ok(process);
     }
 
+    /// Copies `args` to the top of `stack`, the page mapped at
+    /// `get_stack_base()`, followed by an array of their `[address, length]`
+    /// pairs. Returns the resulting stack pointer and the address of the
+    /// array, or the default stack top and zero if `args` is empty.
+    fn push_args(stack: &mut [u8], args: &[String]) -> OsResult<(u64, u64)> {
+        if args.is_empty() {
+            return Ok((Process::get_stack_top().as_u64(), 0));
+        }
+
+        let base = Process::get_stack_base().as_usize();
+        let strings: usize = args.iter().map(|arg| arg.len()).sum();
+        if strings + (args.len() + 1) * 16 > PAGE_SIZE / 2 {
+            return Err(OsError::InvalidArgument);
+        }
+
+        let mut offset = stack.len();
+        let mut pairs = Vec::with_capacity(args.len());
+        for arg in args {
+            offset -= arg.len();
+            stack[offset..offset + arg.len()].copy_from_slice(arg.as_bytes());
+            pairs.push(((base + offset) as u64, arg.len() as u64));
+        }
+
+        offset = (offset & !0xf) - pairs.len() * 16;
+        let argv = offset;
+        for (address, len) in pairs {
+            stack[offset..offset + 8].copy_from_slice(&address.to_le_bytes());
+            stack[offset + 8..offset + 16].copy_from_slice(&len.to_le_bytes());
+            offset += 16;
+        }
+
+        return Ok(((base + argv) as u64, (base + argv) as u64));
+    }
+
     /// Returns the highest `VirtualAddr` that is supported by this system.
     pub fn get_max_va() -> VirtualAddr {
         return VirtualAddr::from(core::usize::MAX);
@@ -137,38 +295,592 @@ impl Process {
         return VirtualAddr::from((top / 16) * 16);
     }
 
-    /// Returns `true` if this process is ready to be scheduled.
+    /// Adds `file` to the process's file table and returns its file
+    /// descriptor, the lowest one that is not in use.
     ///
-    /// This functions returns `true` only if one of the following holds:
+    /// Returns `TooManyFiles` if the process already has `MAX_OPEN_FILES`
+    /// files open.
+    pub fn add_file(&mut self, file: File<PiVFatHandle>) -> OsResult<u64> {
+        let file = OpenFile::new(file);
+        for (fd, slot) in self.files.iter_mut().enumerate() {
+            if slot.is_none() {
+                *slot = Some(file);
+                return Ok(fd as u64);
+            }
+        }
+
+        if self.files.len() >= MAX_OPEN_FILES {
+            return Err(OsError::TooManyFiles);
+        }
+
+        self.files.push(Some(file));
+        return Ok((self.files.len() - 1) as u64);
+    }
+
+    /// Returns the open file with file descriptor `fd`.
     ///
-    ///   * The state is currently `Ready`.
+    /// Returns `BadDescriptor` if `fd` is not an open file descriptor.
+    pub fn file(&self, fd: u64) -> OsResult<&OpenFile> {
+        match self.files.get(fd as usize) {
+            Some(Some(file)) => Ok(file),
+            _ => Err(OsError::BadDescriptor),
+        }
+    }
+
+    /// Removes the file with file descriptor `fd` from the file table and
+    /// returns it, freeing the descriptor for reuse.
     ///
-    ///   * An event being waited for has arrived.
+    /// Returns `BadDescriptor` if `fd` is not an open file descriptor.
+    pub fn remove_file(&mut self, fd: u64) -> OsResult<OpenFile> {
+        match self.files.get_mut(fd as usize) {
+            Some(slot) => slot.take().ok_or(OsError::BadDescriptor),
+            None => Err(OsError::BadDescriptor),
+        }
+    }
+
+    /// Returns the current end of the process's heap, the break.
+    pub fn brk(&self) -> usize {
+        return self.regions.iter()
+            .find(|region| region.kind == RegionKind::Heap)
+            .map_or(0, |heap| heap.end);
+    }
+
+    /// Moves the break to `addr`, growing or shrinking the heap. Pages that
+    /// no longer hold any byte of the heap are unmapped and freed; new ones
+    /// are mapped on demand.
     ///
-    ///     If the process is currently waiting, the corresponding event
-    ///     function is polled to determine if the event being waiting for has
-    ///     occured. If it has, the state is switched to `Ready` and this
-    ///     function returns `true`.
+    /// Returns `NoVmSpace` if `addr` is below the start of the heap or if the
+    /// heap would come closer than a page to another region.
+    pub fn set_brk(&mut self, addr: usize) -> OsResult<()> {
+        let i = self.regions.iter()
+            .position(|region| region.kind == RegionKind::Heap)
+            .ok_or(OsError::NoVmSpace)?;
+        let (heap_start, heap_end) = (self.regions[i].start, self.regions[i].end);
+
+        if addr < heap_start || addr > USER_STACK_BASE {
+            return Err(OsError::NoVmSpace);
+        }
+
+        let new_end = (addr + PAGE_SIZE - 1) & PAGE_MASK;
+        let old_end = (heap_end + PAGE_SIZE - 1) & PAGE_MASK;
+
+        let limit = new_end.saturating_add(PAGE_SIZE);
+        let collides = self.regions.iter().any(|region| {
+            region.kind != RegionKind::Heap && region.start >= heap_start && region.start < limit
+        });
+        if collides {
+            return Err(OsError::NoVmSpace);
+        }
+
+        let mut page = new_end;
+        while page < old_end {
+            self.vmap.dealloc(VirtualAddr::from(page));
+            page += PAGE_SIZE;
+        }
+
+        self.regions[i].end = addr;
+        return Ok(());
+    }
+
+    /// Maps `len` bytes, rounded up to whole pages, of zero-filled memory
+    /// with permission `perm` and returns its address. If `addr` is zero the
+    /// kernel picks the highest free range below the stack; otherwise the
+    /// memory is mapped exactly at `addr`. Pages are allocated on first use.
     ///
-    /// Returns `false` in all other cases.
-    pub fn is_ready(&mut self) -> bool {
-        match self.state {
-            State::Ready => true,
-            State::Waiting(_) => {
-                let mut state = mem::replace(&mut self.state, State::Ready);
+    /// # Errors
+    ///
+    /// Returns `BadAddress` if `addr` is not page aligned, `InvalidArgument`
+    /// if `len` is zero, and `NoVmSpace` if the range is not free or no free
+    /// range is large enough.
+    pub fn mmap(&mut self, addr: usize, len: usize, perm: PagePerm) -> OsResult<usize> {
+        let (start, end) = self.place(addr, len)?;
+        self.regions.push(Region::new(RegionKind::Anonymous, start, end, perm));
+        return Ok(start);
+    }
+
+    /// Like `mmap()`, but maps the contents of the open file `fd` from byte
+    /// `offset` on. Pages are read from the file on first use; bytes past
+    /// the end of the file read as zero. If `perm` is writable, modified
+    /// pages are written back to the file by `msync()` and `munmap()`, and
+    /// when the process exits or executes another program; writes never
+    /// extend the file.
+    ///
+    /// The mapping shares the open file of `fd`, which may be closed
+    /// afterwards, but leaves its offset alone.
+    ///
+    /// # Errors
+    ///
+    /// In addition to the errors of `mmap()`, returns `BadDescriptor` if `fd`
+    /// is not open, `InvalidArgument` if `offset` is not page aligned, and
+    /// `NoAccess` if `perm` is writable but the file is read-only.
+    pub fn mmap_file(&mut self, addr: usize, len: usize, perm: PagePerm, fd: u64, offset: u64) -> OsResult<usize> {
+        let file = self.file(fd)?.clone();
+        if offset % PAGE_SIZE as u64 != 0 {
+            return Err(OsError::InvalidArgument);
+        }
+        if perm.is_writable() && file.lock().metadata.read_only() {
+            return Err(OsError::NoAccess);
+        }
+
+        let (start, end) = self.place(addr, len)?;
+        self.regions.push(Region::file(start, end, perm, file, offset));
+        return Ok(start);
+    }
+
+    /// Unmaps the pages of the mappings in the `len` bytes at `addr`, rounded
+    /// up to whole pages, and frees them after writing modified file pages
+    /// back. Parts of the range that are not mapped are ignored.
+    ///
+    /// Returns `BadAddress` if `addr` is not page aligned, the range leaves
+    /// the user address space or it overlaps the heap or the stack. Nothing
+    /// is unmapped if writing back fails.
+    pub fn munmap(&mut self, addr: usize, len: usize) -> OsResult<()> {
+        let end = self.mapping_range(addr, len)?;
+        self.write_back(addr, end)?;
+
+        self.split_regions(addr);
+        self.split_regions(end);
+
+        let mut i = 0;
+        while i < self.regions.len() {
+            let region = &self.regions[i];
+            if !region.is_mapping() || region.end <= addr || region.start >= end {
+                i += 1;
+                continue;
+            }
+
+            let region = self.regions.remove(i);
+            let mut page = region.start;
+            while page < region.end {
+                self.vmap.dealloc(VirtualAddr::from(page));
+                page += PAGE_SIZE;
+            }
+        }
+
+        return Ok(());
+    }
 
-                match state {
-                    State::Waiting(mut func) => {
-                        if !func(self) {
-                            mem::replace(&mut self.state, State::Waiting(func));
-                            return false;
-                        }
-                        return true;
-                    },
-                    _ => panic!("What happened here")
+    /// Changes the permission of the `len` bytes at `addr`, rounded up to
+    /// whole pages, to `perm`, for both mapped pages and those not yet used.
+    /// Modified pages of file mappings are written back first.
+    ///
+    /// Returns `BadAddress` if `addr` is not page aligned or if the range is
+    /// not entirely covered by mappings, and `NoAccess` if it covers a
+    /// read-only file and `perm` is writable.
+    pub fn mprotect(&mut self, addr: usize, len: usize, perm: PagePerm) -> OsResult<()> {
+        let end = self.mapping_range(addr, len)?;
+
+        let mut covered = addr;
+        while covered < end {
+            let region = self.regions.iter()
+                .find(|region| region.is_mapping() && region.contains(covered))
+                .ok_or(OsError::BadAddress)?;
+
+            let read_only = region.backing.as_ref().map_or(false, |b| b.file.lock().metadata.read_only());
+            if perm.is_writable() && read_only {
+                return Err(OsError::NoAccess);
+            }
+            covered = region.end;
+        }
+
+        self.write_back(addr, end)?;
+        self.split_regions(addr);
+        self.split_regions(end);
+
+        // Written back file pages are clean, so they stay read-only.
+        for region in self.regions.iter_mut() {
+            if !region.is_mapping() || region.start < addr || region.end > end {
+                continue;
+            }
+
+            region.perm = perm;
+            let page_perm = match region.kind {
+                RegionKind::File => perm.read_only(),
+                _ => perm,
+            };
+
+            let mut page = region.start;
+            while page < region.end {
+                self.vmap.set_perm(VirtualAddr::from(page), page_perm);
+                page += PAGE_SIZE;
+            }
+        }
+
+        return Ok(());
+    }
+
+    /// Writes the modified pages of the file mappings in the `len` bytes at
+    /// `addr`, rounded up to whole pages, back to their files and flushes
+    /// them to the disk.
+    ///
+    /// Returns `BadAddress` if `addr` is not page aligned, the range leaves
+    /// the user address space or it overlaps the heap or the stack, and an
+    /// I/O error if writing fails.
+    pub fn msync(&mut self, addr: usize, len: usize) -> OsResult<()> {
+        let end = self.mapping_range(addr, len)?;
+        return self.write_back(addr, end);
+    }
+
+    /// Writes the modified pages of all file mappings back to their files.
+    pub fn sync_mappings(&mut self) -> OsResult<()> {
+        return self.write_back(USER_IMG_BASE, USER_STACK_BASE);
+    }
+
+    /// Writes the modified pages of file mappings in `[start, end)` back and
+    /// marks them clean.
+    ///
+    /// Pages of writable file mappings are first mapped read-only; a write
+    /// makes them writable (see `handle_write_fault()`), so a writable page,
+    /// even one pending copy-on-write, is a modified one.
+    fn write_back(&mut self, start: usize, end: usize) -> OsResult<()> {
+        let vmap = &mut self.vmap;
+        for region in self.regions.iter_mut() {
+            let backing = match region.backing.as_mut() {
+                Some(backing) if region.start < end && start < region.end => backing,
+                _ => continue,
+            };
+
+            let mut written = false;
+            let mut page = core::cmp::max(start, region.start);
+            while page < core::cmp::min(end, region.end) {
+                let va = VirtualAddr::from(page);
+                if vmap.is_writable(va) {
+                    let offset = backing.offset + (page - region.start) as u64;
+                    let data = vmap.get_page(va).expect("mapped page");
+                    write_page(&mut backing.file.lock(), offset, data)?;
+
+                    vmap.set_perm(va, region.perm.read_only());
+                    written = true;
                 }
-            },
-            _ => false
+                page += PAGE_SIZE;
+            }
+
+            if written {
+                backing.file.lock().sync()?;
+            }
+        }
+
+        return Ok(());
+    }
+
+    /// Checks and places the `len` bytes of a new mapping, rounded up to
+    /// whole pages, at `addr` or at a free address if `addr` is zero, and
+    /// returns its start and end. See `mmap()` for the errors.
+    fn place(&self, addr: usize, len: usize) -> OsResult<(usize, usize)> {
+        if addr & !PAGE_MASK != 0 {
+            return Err(OsError::BadAddress);
+        }
+        if len == 0 {
+            return Err(OsError::InvalidArgument);
+        }
+
+        let len = len.checked_add(PAGE_SIZE - 1).ok_or(OsError::NoVmSpace)? & PAGE_MASK;
+        let start = if addr == 0 {
+            self.find_free(len).ok_or(OsError::NoVmSpace)?
+        } else {
+            if !self.is_free(addr, len) {
+                return Err(OsError::NoVmSpace);
+            }
+            addr
+        };
+
+        return Ok((start, start + len));
+    }
+
+    /// Checks that `[addr, addr + len)`, rounded up to whole pages, is a page
+    /// aligned range of user memory that does not overlap the heap or the
+    /// stack, and returns its end.
+    fn mapping_range(&self, addr: usize, len: usize) -> OsResult<usize> {
+        if addr & !PAGE_MASK != 0 || addr < USER_IMG_BASE || len == 0 {
+            return Err(OsError::BadAddress);
+        }
+
+        let end = len.checked_add(PAGE_SIZE - 1)
+            .map(|len| len & PAGE_MASK)
+            .and_then(|len| addr.checked_add(len))
+            .filter(|&end| end <= USER_STACK_BASE)
+            .ok_or(OsError::BadAddress)?;
+
+        let overlaps = self.regions.iter().any(|region| {
+            !region.is_mapping() && region.start < end && addr < region.end
+        });
+        if overlaps {
+            return Err(OsError::BadAddress);
+        }
+
+        return Ok(end);
+    }
+
+    /// Splits the mapping containing `at`, if any, into one region ending
+    /// and one starting at `at`.
+    fn split_regions(&mut self, at: usize) {
+        let region = self.regions.iter_mut().find(|region| {
+            region.is_mapping() && region.start < at && at < region.end
+        });
+
+        if let Some(region) = region {
+            let upper = region.split_off(at);
+            self.regions.push(upper);
+        }
+    }
+
+    /// Returns `true` if the page aligned range `[addr, addr + len)` lies in
+    /// user memory below the stack and overlaps neither a region, the guard
+    /// page above the heap, nor a mapped page of the program image.
+    fn is_free(&self, addr: usize, len: usize) -> bool {
+        let end = match addr.checked_add(len) {
+            Some(end) if addr >= USER_IMG_BASE && end <= USER_STACK_BASE => end,
+            _ => return false,
+        };
+
+        let overlaps = self.regions.iter().any(|region| {
+            let region_end = match region.kind {
+                RegionKind::Heap => ((region.end + PAGE_SIZE - 1) & PAGE_MASK) + PAGE_SIZE,
+                _ => region.end,
+            };
+            region.start < end && addr < region_end
+        });
+        if overlaps {
+            return false;
+        }
+
+        let mut page = addr;
+        while page < end {
+            if self.vmap.is_mapped(VirtualAddr::from(page)) {
+                return false;
+            }
+            page += PAGE_SIZE;
+        }
+
+        return true;
+    }
+
+    /// Returns the start of the highest free range of `len` bytes between
+    /// the heap and the stack, or `None` if there is none.
+    fn find_free(&self, len: usize) -> Option<usize> {
+        let mut regions: Vec<&Region> = self.regions.iter()
+            .filter(|region| region.kind != RegionKind::Heap)
+            .collect();
+        regions.sort_by(|a, b| b.start.cmp(&a.start));
+
+        let mut top = USER_STACK_BASE;
+        for region in regions {
+            if region.end <= top && top - region.end >= len && self.is_free(top - len, len) {
+                return Some(top - len);
+            }
+            top = core::cmp::min(top, region.start);
+        }
+
+        if top >= USER_IMG_BASE + len && self.is_free(top - len, len) {
+            return Some(top - len);
+        }
+
+        return None;
+    }
+
+    /// Handles a translation fault at the user address `va` by mapping a
+    /// page there if `va` lies in one of the process's regions. The page is
+    /// zero-filled, or read from the file of a file mapping; pages of
+    /// writable file mappings are mapped read-only until first written.
+    ///
+    /// Returns `true` if a page was mapped and the access can be retried, or
+    /// `false` if the access is invalid or the file could not be read.
+    /// Returns `NoMemory` if no page frame is left for the page.
+    pub fn handle_fault(&mut self, va: VirtualAddr) -> OsResult<bool> {
+        let va = va.as_usize();
+        let region = match self.regions.iter_mut().find(|region| region.contains(va)) {
+            Some(region) => region,
+            None => return Ok(false),
+        };
+
+        let page = VirtualAddr::from(va & PAGE_MASK);
+        if self.vmap.is_mapped(page) {
+            return Ok(false);
+        }
+
+        let backing = match region.backing.as_mut() {
+            Some(backing) => backing,
+            None => {
+                self.vmap.alloc(page, region.perm)?;
+                return Ok(true);
+            }
+        };
+
+        let offset = backing.offset + (page.as_usize() - region.start) as u64;
+        let data = self.vmap.alloc(page, region.perm.read_only())?;
+        if read_page(&mut backing.file.lock(), offset, data).is_err() {
+            self.vmap.dealloc(page);
+            return Ok(false);
+        }
+
+        return Ok(true);
+    }
+
+    /// Handles a permission fault at the user address `va` caused by a write
+    /// to a page shared copy-on-write, by giving the process its own copy,
+    /// or by the first write to a clean page of a writable file mapping, by
+    /// making the page writable, which marks it as modified.
+    ///
+    /// Returns `true` if the access can be retried, or `false` if it is
+    /// invalid. Returns `NoMemory` if no page frame is left for the copy.
+    pub fn handle_write_fault(&mut self, va: VirtualAddr) -> OsResult<bool> {
+        if self.vmap.copy_on_write(va)? {
+            return Ok(true);
         }
+
+        let va = va.as_usize();
+        let perm = match self.regions.iter().find(|region| region.contains(va)) {
+            Some(region) if region.kind == RegionKind::File && region.perm.is_writable() => region.perm,
+            _ => return Ok(false),
+        };
+
+        let page = VirtualAddr::from(va & PAGE_MASK);
+        if !self.vmap.is_mapped(page) || self.vmap.is_writable(page) {
+            return Ok(false);
+        }
+
+        // A frame shared with another process stays read-only until copied
+        self.vmap.set_perm(page, perm);
+        self.vmap.copy_on_write(page)?;
+        return Ok(true);
+    }
+
+    /// Maps the missing pages of the `len` bytes at the user address `va`
+    /// that lie in the process's regions, as if the process had touched
+    /// them, so that the kernel can access them on its behalf. If `write` is
+    /// set, they are also prepared for writing.
+    ///
+    /// Returns `NoMemory` if no page frame is left for a page. Pages that are
+    /// invalid to access are skipped, and fail the caller's `check_range()`.
+    pub fn populate(&mut self, va: VirtualAddr, len: usize, write: bool) -> OsResult<()> {
+        let start = va.as_usize();
+        let end = match start.checked_add(len) {
+            Some(end) if len > 0 => end,
+            _ => return Ok(()),
+        };
+
+        let mut page = start & PAGE_MASK;
+        while page < end {
+            self.handle_fault(VirtualAddr::from(page))?;
+            if write {
+                self.handle_write_fault(VirtualAddr::from(page))?;
+            }
+            page = match page.checked_add(PAGE_SIZE) {
+                Some(next) => next,
+                None => break,
+            };
+        }
+
+        return Ok(());
+    }
+
+    /// Returns the number of bytes of memory the process holds: its mapped
+    /// pages, counting shared ones in full, its kernel stack and its page
+    /// table.
+    pub fn size(&self) -> usize {
+        return self.vmap.mapped_pages() * PAGE_SIZE + Stack::SIZE + mem::size_of::<PageTable>();
+    }
+
+    /// Removes the exit code of the exited child `pid` from `exited` and
+    /// returns it, or returns `None` if the child has not exited.
+    pub fn take_exit_code(&mut self, pid: Id) -> Option<i32> {
+        let i = self.exited.iter().position(|&(id, _)| id == pid)?;
+        return Some(self.exited.remove(i).1);
+    }
+
+    /// Marks this process ready after the event it waited for occurred at
+    /// `now`, completing the system call that made it wait: `sleep` returns
+    /// the time it slept and `wait` the child's exit code.
+    pub fn wake(&mut self, now: Duration) {
+        match mem::replace(&mut self.state, State::Ready) {
+            State::Waiting(Event::Timer { start, .. }) => {
+                let slept = now.checked_sub(start).unwrap_or_default();
+                self.context.x_regs[0] = slept.as_millis() as u64;
+            }
+            State::Waiting(Event::ChildExit(pid)) => {
+                if let Some(code) = self.take_exit_code(pid) {
+                    self.context.x_regs[0] = code as u64;
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Fills `page` with the bytes of `file` from `offset` on, zero-filling what
+/// lies past the end of the file. The file's offset is left unchanged.
+fn read_page(file: &mut File<PiVFatHandle>, offset: u64, page: &mut [u8]) -> io::Result<()> {
+    for byte in page.iter_mut() {
+        *byte = 0;
+    }
+
+    if offset >= file.size {
+        return Ok(());
+    }
+
+    let pos = file.seek(SeekFrom::Current(0))?;
+    file.seek(SeekFrom::Start(offset))?;
+    let mut read = 0;
+    while read < page.len() {
+        match file.read(&mut page[read..])? {
+            0 => break,
+            n => read += n,
+        }
+    }
+
+    file.seek(SeekFrom::Start(pos))?;
+    return Ok(());
+}
+
+/// Writes the bytes of `page` that lie within `file` to it at `offset`. The
+/// file's offset is left unchanged.
+fn write_page(file: &mut File<PiVFatHandle>, offset: u64, page: &[u8]) -> io::Result<()> {
+    if offset >= file.size {
+        return Ok(());
+    }
+
+    let len = core::cmp::min(page.len() as u64, file.size - offset) as usize;
+    let pos = file.seek(SeekFrom::Current(0))?;
+    file.seek(SeekFrom::Start(offset))?;
+    file.write_all(&page[..len])?;
+    file.seek(SeekFrom::Start(pos))?;
+    return Ok(());
+}
+
+impl Task for Process {
+    fn id(&self) -> Id {
+        return self.context.tpidr;
+    }
+
+    fn priority(&self) -> usize {
+        return self.priority;
+    }
+
+    fn set_priority(&mut self, priority: usize) {
+        self.priority = priority;
+    }
+
+    fn realtime(&self) -> Option<&RealTime> {
+        return self.realtime.as_ref();
+    }
+
+    fn realtime_mut(&mut self) -> Option<&mut RealTime> {
+        return self.realtime.as_mut();
+    }
+
+    fn set_realtime(&mut self, realtime: Option<RealTime>) {
+        self.realtime = realtime;
+    }
+
+    fn context(&mut self) -> &mut TrapFrame {
+        return &mut self.context;
+    }
+
+    fn wake(&mut self, now: Duration) {
+        Process::wake(self, now);
+    }
+
+    fn run(&mut self) {
+        self.state = State::Running;
     }
 }
@@ -0,0 +1,422 @@
+use alloc::collections::vec_deque::VecDeque;
+use alloc::collections::BTreeMap;
+use core::fmt;
+use core::time::Duration;
+
+use kernel_api::{OsError, OsResult, PRIORITY_LOWEST};
+
+use crate::allocator::SlabBox;
+use crate::process::waitqueue::WaitQueue;
+use crate::process::{Event, Id};
+use crate::traps::TrapFrame;
+
+/// The number of priority levels. Level 0 is the most urgent.
+pub const LEVELS: usize = PRIORITY_LOWEST as usize + 1;
+
+/// The number of levels a task is raised by for the time slice following
+/// its wake-up from waiting.
+pub const WAKE_BOOST: usize = 2;
+
+/// The shortest period of a real-time task.
+pub const MIN_PERIOD: Duration = Duration::from_millis(1);
+
+/// The share of the CPU, in millionths, that real-time tasks may reserve in
+/// total. The rest is left to the other tasks, such as the shell.
+pub const MAX_UTILIZATION: u64 = 900_000;
+
+/// The CPU time reservation of a real-time task: `budget` of every `period`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct RealTime {
+    pub period: Duration,
+    pub budget: Duration,
+    /// The end of the current period, which is the task's deadline.
+    pub deadline: Duration,
+    /// The part of the budget not used up in the current period.
+    pub remaining: Duration,
+}
+
+impl RealTime {
+    /// Returns a reservation of `budget` of every `period` whose first period
+    /// starts at `now`.
+    pub fn new(period: Duration, budget: Duration, now: Duration) -> RealTime {
+        return RealTime { period, budget, deadline: now + period, remaining: budget };
+    }
+
+    /// Returns the share of the CPU the reservation takes in millionths,
+    /// rounded up.
+    pub fn utilization(&self) -> u64 {
+        let budget = self.budget.as_micros();
+        let period = self.period.as_micros();
+        return ((budget * 1_000_000 + period - 1) / period) as u64;
+    }
+
+    /// Starts the period `now` is in with the full budget if the current one
+    /// has ended.
+    fn replenish(&mut self, now: Duration) {
+        if now < self.deadline {
+            return;
+        }
+
+        let periods = (now - self.deadline).as_micros() / self.period.as_micros() + 1;
+        self.deadline += Duration::from_micros((periods * self.period.as_micros()) as u64);
+        self.remaining = self.budget;
+    }
+}
+
+/// What the run queue needs to know about the tasks it schedules.
+pub trait Task {
+    /// Returns the task's ID.
+    fn id(&self) -> Id;
+
+    /// Returns the task's static priority, below `LEVELS`.
+    fn priority(&self) -> usize;
+
+    /// Sets the task's static priority.
+    fn set_priority(&mut self, priority: usize);
+
+    /// Returns the task's reservation if it is a real-time task.
+    fn realtime(&self) -> Option<&RealTime>;
+
+    /// Returns the task's reservation if it is a real-time task.
+    fn realtime_mut(&mut self) -> Option<&mut RealTime>;
+
+    /// Makes the task a real-time task with the reservation `realtime`, or a
+    /// normal one if it is `None`.
+    fn set_realtime(&mut self, realtime: Option<RealTime>);
+
+    /// Returns the task's saved trap frame.
+    fn context(&mut self) -> &mut TrapFrame;
+
+    /// Marks the task ready after the event it waited for occurred at `now`.
+    fn wake(&mut self, now: Duration);
+
+    /// Marks the task running.
+    fn run(&mut self);
+}
+
+impl<T: Task> Task for SlabBox<T> {
+    fn id(&self) -> Id {
+        return (**self).id();
+    }
+
+    fn priority(&self) -> usize {
+        return (**self).priority();
+    }
+
+    fn set_priority(&mut self, priority: usize) {
+        (**self).set_priority(priority);
+    }
+
+    fn realtime(&self) -> Option<&RealTime> {
+        return (**self).realtime();
+    }
+
+    fn realtime_mut(&mut self) -> Option<&mut RealTime> {
+        return (**self).realtime_mut();
+    }
+
+    fn set_realtime(&mut self, realtime: Option<RealTime>) {
+        (**self).set_realtime(realtime);
+    }
+
+    fn context(&mut self) -> &mut TrapFrame {
+        return (**self).context();
+    }
+
+    fn wake(&mut self, now: Duration) {
+        (**self).wake(now);
+    }
+
+    fn run(&mut self) {
+        (**self).run();
+    }
+}
+
+/// A multi-level run queue: one FIFO queue of ready tasks per priority
+/// level, and the tasks waiting for an event.
+///
+/// The next task to run is the first one of the most urgent level that has
+/// one, so tasks of equal priority take turns and less urgent ones only run
+/// when no more urgent one is ready. Waiting tasks are set aside until their
+/// event occurs and cost nothing until then. A task that wakes up is moved
+/// `WAKE_BOOST` levels up until it is scheduled out again, so that
+/// interactive tasks get ahead of CPU-bound ones of their priority.
+///
+/// Real-time tasks run ahead of all others, earliest deadline first, for as
+/// long as they have budget left in their current period. Once it is used
+/// up, a real-time task does not run until its next period starts.
+pub struct RunQueue<T> {
+    levels: [VecDeque<T>; LEVELS],
+    /// The waiting tasks, by ID.
+    waiting: BTreeMap<Id, T>,
+    /// The events the waiting tasks wait for.
+    events: WaitQueue,
+    /// The time at which the running task was switched to.
+    started: Duration,
+}
+
+impl<T: Task> RunQueue<T> {
+    /// Returns an empty run queue.
+    pub fn new() -> RunQueue<T> {
+        RunQueue {
+            levels: Default::default(),
+            waiting: BTreeMap::new(),
+            events: WaitQueue::new(),
+            started: Duration::from_secs(0),
+        }
+    }
+
+    /// Adds `task` to the end of the level of its priority.
+    pub fn push(&mut self, task: T) {
+        self.levels[task.priority()].push_back(task);
+    }
+
+    /// Returns the position of the ready task `id` as (level, index).
+    fn position(&self, id: Id) -> Option<(usize, usize)> {
+        for (level, tasks) in self.levels.iter().enumerate() {
+            if let Some(i) = tasks.iter().position(|task| task.id() == id) {
+                return Some((level, i));
+            }
+        }
+
+        return None;
+    }
+
+    /// Removes the task `id` from the queue and returns it.
+    pub fn remove(&mut self, id: Id) -> Option<T> {
+        if let Some(task) = self.waiting.remove(&id) {
+            self.events.remove(id);
+            return Some(task);
+        }
+
+        let (level, i) = self.position(id)?;
+        return self.levels[level].remove(i);
+    }
+
+    /// Returns the task `id`.
+    pub fn get_mut(&mut self, id: Id) -> Option<&mut T> {
+        if self.waiting.contains_key(&id) {
+            return self.waiting.get_mut(&id);
+        }
+
+        let (level, i) = self.position(id)?;
+        return self.levels[level].get_mut(i);
+    }
+
+    /// Returns an iterator over the tasks: the ready ones, most urgent first,
+    /// then the waiting ones.
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        let ready = self.levels.iter().flat_map(|tasks| tasks.iter());
+        return ready.chain(self.waiting.values());
+    }
+
+    /// Returns an iterator over the tasks: the ready ones, most urgent first,
+    /// then the waiting ones.
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut T> {
+        let ready = self.levels.iter_mut().flat_map(|tasks| tasks.iter_mut());
+        return ready.chain(self.waiting.values_mut());
+    }
+
+    /// Returns the number of tasks in the queue.
+    pub fn len(&self) -> usize {
+        return self.levels.iter().map(|tasks| tasks.len()).sum::<usize>() + self.waiting.len();
+    }
+
+    /// Sets the static priority of the task `id` to `priority`, moving it to
+    /// the end of that level if it is ready. Returns `false` if there is no
+    /// such task.
+    pub fn set_priority(&mut self, id: Id, priority: usize) -> bool {
+        if let Some(task) = self.waiting.get_mut(&id) {
+            task.set_priority(priority);
+            return true;
+        }
+
+        let mut task = match self.remove(id) {
+            Some(task) => task,
+            None => return false,
+        };
+
+        task.set_priority(priority);
+        self.push(task);
+        return true;
+    }
+
+    /// Makes the task `id` a real-time task with `budget` of every `period`,
+    /// whose first period starts at `now`, or a normal one again if
+    /// `reservation` is `None`.
+    ///
+    /// Returns `NoEntry` if there is no such task, `InvalidArgument` if the
+    /// period is shorter than `MIN_PERIOD` or the budget is zero or longer
+    /// than the period, and `Oversubscribed` if the real-time tasks would
+    /// reserve more than `MAX_UTILIZATION` of the CPU.
+    pub fn set_realtime(
+        &mut self,
+        id: Id,
+        reservation: Option<(Duration, Duration)>,
+        now: Duration,
+    ) -> OsResult<()> {
+        let realtime = match reservation {
+            Some((period, budget)) => {
+                if period < MIN_PERIOD || budget == Duration::from_secs(0) || budget > period {
+                    return Err(OsError::InvalidArgument);
+                }
+
+                Some(RealTime::new(period, budget, now))
+            }
+            None => None,
+        };
+
+        let reserved: u64 = self.iter()
+            .filter(|task| task.id() != id)
+            .filter_map(|task| task.realtime())
+            .map(|realtime| realtime.utilization())
+            .sum();
+
+        let task = self.get_mut(id).ok_or(OsError::NoEntry)?;
+        if let Some(realtime) = realtime {
+            if reserved + realtime.utilization() > MAX_UTILIZATION {
+                return Err(OsError::Oversubscribed);
+            }
+        }
+
+        task.set_realtime(realtime);
+        return Ok(());
+    }
+
+    /// Wakes up the waiting task `id` at `now` and moves it to the end of the
+    /// level `WAKE_BOOST` above its priority.
+    fn wake_task(&mut self, id: Id, now: Duration) {
+        let mut task = match self.waiting.remove(&id) {
+            Some(task) => task,
+            None => return,
+        };
+
+        task.wake(now);
+        let boosted = task.priority().saturating_sub(WAKE_BOOST);
+        self.levels[boosted].push_back(task);
+    }
+
+    /// Wakes up the tasks waiting for `event`, which occurred at `now`, in
+    /// the order they started waiting.
+    pub fn wake(&mut self, event: Event, now: Duration) {
+        for id in self.events.take(event) {
+            self.wake_task(id, now);
+        }
+    }
+
+    /// Saves `tf` into the task whose ID it holds. If `event` is `Some`, the
+    /// task is set aside to wait for it, otherwise it is moved to the end of
+    /// the level of its priority, ending any wake-up boost. A real-time task
+    /// is charged the time it ran until `now`. Returns the task, or `None` if
+    /// there is no such task.
+    pub fn schedule_out(&mut self, tf: &TrapFrame, now: Duration, event: Option<Event>) -> Option<&mut T> {
+        let mut task = self.remove(tf.tpidr)?;
+        *task.context() = *tf;
+
+        let ran = now.checked_sub(self.started).unwrap_or_default();
+        if let Some(realtime) = task.realtime_mut() {
+            realtime.remaining = realtime.remaining.checked_sub(ran).unwrap_or_default();
+        }
+
+        if let Some(event) = event {
+            self.events.push(tf.tpidr, event);
+            self.waiting.insert(tf.tpidr, task);
+            return self.waiting.get_mut(&tf.tpidr);
+        }
+
+        let level = task.priority();
+        self.levels[level].push_back(task);
+        return self.levels[level].back_mut();
+    }
+
+    /// Returns the ID of the ready real-time task with budget left whose
+    /// deadline is the earliest, if any.
+    fn earliest_deadline(&self) -> Option<Id> {
+        let mut next: Option<(Duration, Id)> = None;
+        for task in self.levels.iter().flat_map(|tasks| tasks.iter()) {
+            let deadline = match task.realtime() {
+                Some(realtime) if realtime.remaining > Duration::from_secs(0) => realtime.deadline,
+                _ => continue,
+            };
+
+            if next.map_or(true, |(earliest, _)| deadline < earliest) {
+                next = Some((deadline, task.id()));
+            }
+        }
+
+        return next.map(|(_, id)| id);
+    }
+
+    /// Returns the earliest deadline of the ready real-time tasks that used up
+    /// their budget, which is when the next of them can run again, if any.
+    pub fn next_release(&self) -> Option<Duration> {
+        return self.levels
+            .iter()
+            .flat_map(|tasks| tasks.iter())
+            .filter_map(|task| task.realtime())
+            .filter(|realtime| realtime.remaining == Duration::from_secs(0))
+            .map(|realtime| realtime.deadline)
+            .min();
+    }
+
+    /// Returns the ID of the first ready normal task of the most urgent
+    /// level, if any.
+    fn first_ready(&self) -> Option<Id> {
+        let mut ready = self.levels.iter().flat_map(|tasks| tasks.iter());
+        return ready.find(|task| task.realtime().is_none()).map(|task| task.id());
+    }
+
+    /// Starts the new periods of real-time tasks that began by `now`. Then
+    /// marks the next task running, the ready real-time task with the
+    /// earliest deadline or else the first ready task of the most urgent
+    /// level, and restores its trap frame into `tf`. Returns its ID, or
+    /// `None` if no task is ready.
+    pub fn switch_to(&mut self, tf: &mut TrapFrame, now: Duration) -> Option<Id> {
+        for task in self.iter_mut() {
+            if let Some(realtime) = task.realtime_mut() {
+                realtime.replenish(now);
+            }
+        }
+
+        let id = match self.earliest_deadline() {
+            Some(id) => id,
+            None => self.first_ready()?,
+        };
+
+        let task = self.get_mut(id).expect("task to switch to");
+        task.run();
+        *tf = *task.context();
+        self.started = now;
+        return Some(id);
+    }
+
+    /// Returns how long the task `id`, switched to at `now`, may run before
+    /// the scheduler has to decide again: at most `tick`, and no longer than
+    /// the task's remaining budget or until the next period of a real-time
+    /// task starts.
+    pub fn slice(&self, id: Id, now: Duration, tick: Duration) -> Duration {
+        let mut slice = tick;
+        for task in self.iter() {
+            let realtime = match task.realtime() {
+                Some(realtime) => realtime,
+                None => continue,
+            };
+
+            if task.id() == id {
+                slice = core::cmp::min(slice, realtime.remaining);
+            }
+
+            if realtime.deadline > now {
+                slice = core::cmp::min(slice, realtime.deadline - now);
+            }
+        }
+
+        return slice;
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for RunQueue<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.levels.iter()).finish()
+    }
+}
@@ -1,18 +1,24 @@
 use alloc::boxed::Box;
-use alloc::collections::vec_deque::VecDeque;
 use core::fmt;
+use core::time::Duration;
 
 use aarch64::*;
 
 use crate::mutex::Mutex;
 use crate::param::{PAGE_MASK, PAGE_SIZE, TICK, USER_IMG_BASE};
-use crate::process::{Id, Process, State};
+use crate::process::runqueue::{RunQueue, LEVELS};
+use crate::process::{Event, Id, Process, State, PROCESSES};
 use crate::traps::TrapFrame;
+use crate::allocator::SlabBox;
+use crate::ALLOCATOR;
+use crate::TIMERS;
 use crate::VMM;
 use crate::IRQ;
 
 use crate::irq::timer_handler;
 
+use kernel_api::{OsError, OsResult};
+
 use shim::path::PathBuf;
 
 use crate::console::{kprintln};
@@ -20,6 +26,9 @@ use crate::console::{kprintln};
 use pi::interrupt::{Interrupt, Controller};
 use pi::timer;
 
+/// The exit code reported to the parent of a process that was killed.
+pub const KILLED: i32 = -1;
+
 /// Process scheduler for the entire machine.
 #[derive(Debug)]
 pub struct GlobalScheduler(Mutex<Option<Scheduler>>);
@@ -41,6 +50,17 @@ impl GlobalScheduler {
     }
 
 
+    /// Enter a critical region and execute the provided closure with the
+    /// currently running process, the one whose ID is saved in `tf`. Returns
+    /// `None` if there is no such process.
+    pub fn with_current<F, R>(&self, tf: &TrapFrame, f: F) -> Option<R>
+    where
+        F: FnOnce(&mut Process) -> R,
+    {
+        self.critical(|scheduler| scheduler.current(tf).map(f))
+    }
+
+
     /// Adds a process to the scheduler's queue and returns that process's ID.
     /// For more details, see the documentation on `Scheduler::add()`.
     pub fn add(&self, process: Process) -> Option<Id> {
@@ -56,16 +76,113 @@ impl GlobalScheduler {
         self.switch_to(tf)
     }
 
+    /// Restores the next ready process's trap frame into `tf`, waiting for
+    /// one if there is none, and returns its ID.
+    ///
+    /// If the kernel heap ran into its reserve since the last switch, the
+    /// process holding the most memory is killed first to make room.
+    ///
+    /// While no process is ready, the CPU idles tickless: the scheduler's
+    /// tick is stopped, or set to the start of the next period of a real-time
+    /// process waiting for its budget, and the CPU sleeps in `wfi` until an
+    /// interrupt is pending, such as the kernel timer of the next sleeper to
+    /// wake up. The time spent idle is added to the `idle_stats()`.
     pub fn switch_to(&self, tf: &mut TrapFrame) -> Id {
+        if ALLOCATOR.take_exhausted() {
+            self.kill_largest();
+        }
+
+        let mut idle_since = None;
         loop {
             let rtn = self.critical(|scheduler| scheduler.switch_to(tf));
             if let Some(id) = rtn {
+                if let Some(since) = idle_since {
+                    let idle = timer::current_time() - since;
+                    self.critical(|scheduler| scheduler.add_idle(idle));
+                }
+
                 return id;
             }
-            aarch64::wfe();
+
+            if idle_since.is_none() {
+                idle_since = Some(timer::current_time());
+            }
+
+            // A real-time process that used up its budget is ready again once
+            // its next period starts, so the tick is kept for that. Sleepers
+            // are woken up by the kernel timers on timer 3.
+            let now = timer::current_time();
+            match self.critical(|scheduler| scheduler.next_release()) {
+                Some(release) if release <= now => continue,
+                Some(release) => timer::tick_in(release - now),
+                None => timer::stop_tick(),
+            }
+
+            // Interrupts are masked here, but a pending one still ends `wfi`.
+            // Expired timers have to be run by hand to wake up sleeping
+            // processes.
+            aarch64::wfi();
+            TIMERS.run_expired();
         }
     }
 
+    /// Returns the counters of the time the CPU had no process to run.
+    pub fn idle_stats(&self) -> IdleStats {
+        self.critical(|scheduler| scheduler.idle_stats())
+    }
+
+    /// Wakes up the processes waiting for `event`, which occurred at `now`.
+    /// For more details, see the documentation on `RunQueue::wake()`.
+    pub fn wake(&self, event: Event, now: Duration) {
+        self.critical(|scheduler| scheduler.wake(event, now))
+    }
+
+    /// Ends the currently running process with exit code `code` and switches
+    /// to the next process, restoring its trap frame into `tf`. Returns the
+    /// next process's ID. For more details, see the documentation on
+    /// `Scheduler::exit()`.
+    pub fn exit(&self, code: i32, tf: &mut TrapFrame) -> Id {
+        self.critical(|scheduler| scheduler.exit(code, tf));
+        self.switch_to(tf)
+    }
+
+    /// Returns the exit code of the current process's child `pid`, if it has
+    /// exited. For more details, see the documentation on
+    /// `Scheduler::child_status()`.
+    pub fn child_status(&self, pid: Id, tf: &TrapFrame) -> OsResult<Option<i32>> {
+        self.critical(|scheduler| scheduler.child_status(pid, tf))
+    }
+
+    /// Returns the priority of the process `pid`, or of the current process
+    /// if `pid` is 0. For more details, see the documentation on
+    /// `Scheduler::priority()`.
+    pub fn priority(&self, pid: Id, tf: &TrapFrame) -> OsResult<usize> {
+        self.critical(|scheduler| scheduler.priority(pid, tf))
+    }
+
+    /// Sets the priority of the process `pid`, or of the current process if
+    /// `pid` is 0. For more details, see the documentation on
+    /// `Scheduler::set_priority()`.
+    pub fn set_priority(&self, pid: Id, priority: usize, tf: &TrapFrame) -> OsResult<()> {
+        self.critical(|scheduler| scheduler.set_priority(pid, priority, tf))
+    }
+
+    /// Makes the current process a real-time process with `budget` of every
+    /// `period`, or a normal one again if `reservation` is `None`. For more
+    /// details, see the documentation on `Scheduler::set_realtime()`.
+    pub fn set_realtime(&self, reservation: Option<(Duration, Duration)>, tf: &TrapFrame) -> OsResult<()> {
+        self.critical(|scheduler| scheduler.set_realtime(reservation, tf))
+    }
+
+    /// Kills the process holding the most memory to make room when memory
+    /// ran out where no error can be reported, and returns its ID. For more
+    /// details, see the documentation on `Scheduler::kill_largest()`.
+    pub fn kill_largest(&self) -> Option<Id> {
+        let (pid, size) = self.critical(|scheduler| scheduler.kill_largest())?;
+        kprintln!("Out of memory: killed process (pid={}) holding {} KiB", pid, size / 1024);
+        return Some(pid);
+    }
+
     /// Kills currently running process and returns that process's ID.
     /// For more details, see the documentaion on `Scheduler::kill()`.
     #[must_use]
@@ -114,12 +231,10 @@ impl GlobalScheduler {
         let mut int_cnt = Controller::new();
         int_cnt.enable(Interrupt::Timer1);
 
-        // Register timer handler
+        // Register timer handler. The timer is started by the first switch
+        // to a process.
         IRQ.register(Interrupt::Timer1, Box::new(timer_handler));
 
-        // Start a timer
-        timer::tick_in(TICK);
-
         // Create the scheduler
         let mut scheduler = Scheduler::new();
         *self.0.lock() = Some(scheduler);
@@ -157,7 +272,7 @@ impl GlobalScheduler {
         use crate::vm::{VirtualAddr, PagePerm};
     
         let mut page = proc.vmap.alloc(
-            VirtualAddr::from(USER_IMG_BASE as u64), PagePerm::RWX);
+            VirtualAddr::from(USER_IMG_BASE as u64), PagePerm::RWX).expect("Expected page");
     
         let text = unsafe {
             core::slice::from_raw_parts(test_user_process as *const u8, 24)
@@ -167,25 +282,87 @@ impl GlobalScheduler {
     }
 }
 
+/// Counters of the time the CPU had no process to run.
+#[derive(Default, Debug, Copy, Clone, PartialEq, Eq)]
+pub struct IdleStats {
+    /// The time since the scheduler started.
+    pub uptime: Duration,
+    /// The time spent idle since the scheduler started.
+    pub idle: Duration,
+    /// The number of times the CPU went idle.
+    pub idle_periods: u64,
+}
+
+impl IdleStats {
+    /// Returns the share of the uptime the CPU was running processes, in
+    /// percent.
+    pub fn utilization(&self) -> u64 {
+        let uptime = self.uptime.as_micros() as u64;
+        if uptime == 0 {
+            return 0;
+        }
+
+        let busy = uptime.saturating_sub(self.idle.as_micros() as u64);
+        return busy * 100 / uptime;
+    }
+}
+
+impl fmt::Display for IdleStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "up {}.{:03}s, idle {}.{:03}s in {} periods, {}% busy",
+            self.uptime.as_secs(),
+            self.uptime.subsec_millis(),
+            self.idle.as_secs(),
+            self.idle.subsec_millis(),
+            self.idle_periods,
+            self.utilization()
+        )
+    }
+}
+
 #[derive(Debug)]
 pub struct Scheduler {
-    processes: VecDeque<Process>,
+    processes: RunQueue<SlabBox<Process>>,
     last_id: Option<Id>,
+    /// The time at which the scheduler started.
+    started: Duration,
+    /// The total time spent idle.
+    idle: Duration,
+    /// The number of times the CPU went idle.
+    idle_periods: u64,
 }
 
 impl Scheduler {
     /// Returns a new `Scheduler` with an empty queue.
     fn new() -> Scheduler {
         return Scheduler {
-            processes: VecDeque::new(),
+            processes: RunQueue::new(),
             last_id: None,
+            started: timer::current_time(),
+            idle: Duration::from_secs(0),
+            idle_periods: 0,
         };
     }
 
+    /// Records a period of `idle` time with no process to run.
+    fn add_idle(&mut self, idle: Duration) {
+        self.idle += idle;
+        self.idle_periods += 1;
+    }
+
+    /// Returns the idle time counters.
+    fn idle_stats(&self) -> IdleStats {
+        let uptime = timer::current_time().checked_sub(self.started).unwrap_or_default();
+        return IdleStats { uptime, idle: self.idle, idle_periods: self.idle_periods };
+    }
+
     /// Adds a process to the scheduler's queue and returns that process's ID if
     /// a new process can be scheduled. The process ID is newly allocated for
-    /// the process and saved in its `trap_frame`. If no further processes can
-    /// be scheduled, returns `None`.
+    /// the process and saved in its `trap_frame`, and the process is moved
+    /// into the `PROCESSES` cache. If no further processes can be scheduled,
+    /// returns `None`.
     ///
     /// It is the caller's responsibility to ensure that the first time `switch`
     /// is called, that process is executing on the CPU.
@@ -204,7 +381,7 @@ impl Scheduler {
         process.context.tpidr = next_id;
 
         // Add to queue
-        self.processes.push_back(process);
+        self.processes.push(PROCESSES.alloc(process)?);
 
         let new_id = Some(next_id);
 
@@ -214,79 +391,190 @@ impl Scheduler {
         return new_id;
     }
 
+    /// Returns the currently running process, the one whose ID is saved in
+    /// `tf`, or `None` if there is no such process.
+    fn current(&mut self, tf: &TrapFrame) -> Option<&mut Process> {
+        return self.processes.get_mut(tf.tpidr).map(|process| &mut **process);
+    }
+
     /// Finds the currently running process, sets the current process's state
     /// to `new_state`, prepares the context switch on `tf` by saving `tf`
     /// into the current process, and push the current process back to the
-    /// end of `processes` queue.
+    /// end of the queue of its priority. A process that is `Waiting` is set
+    /// aside instead until its event occurs. See `RunQueue::schedule_out()`.
     ///
     /// If the `processes` queue is empty or there is no current process,
     /// returns `false`. Otherwise, returns `true`.
     fn schedule_out(&mut self, new_state: State, tf: &mut TrapFrame) -> bool {
-        for i in 0..self.processes.len() {
-            match self.processes.get(i) {
-                Some(process) => {
-                    // If current running process
-                    if process.context.tpidr == tf.tpidr {
-                        let mut current_process = self.processes.remove(i).expect("Expected process");
-
-                        current_process.state = new_state;
-                        current_process.context = Box::new(*tf);
-
-                        self.processes.push_back(current_process);
-                        return true;
-                    }
-                }
-                None => continue,
+        let event = match new_state {
+            State::Waiting(event) => Some(event),
+            _ => None,
+        };
+
+        match self.processes.schedule_out(tf, timer::current_time(), event) {
+            Some(process) => {
+                process.state = new_state;
+                return true;
             }
+            None => return false,
         }
-
-        // Did not find a running process
-        return false;
     }
 
-    /// Finds the next process to switch to, brings the next process to the
-    /// front of the `processes` queue, changes the next process's state to
-    /// `Running`, and performs context switch by restoring the next process`s
-    /// trap frame into `tf`.
+    /// Finds the next process to switch to, the real-time one with the
+    /// earliest deadline or else the first ready one of the most urgent
+    /// priority, changes the next process's state to `Running`, and performs
+    /// context switch by restoring the next process`s trap frame into `tf`.
+    /// The timer is set to end the process's time slice, which is shorter
+    /// than a `TICK` if its real-time budget runs out or another real-time
+    /// process's period starts before. See `RunQueue::switch_to()`.
     ///
     /// If there is no process to switch to, returns `None`. Otherwise, returns
     /// `Some` of the next process`s process ID.
     fn switch_to(&mut self, tf: &mut TrapFrame) -> Option<Id> {
-        for (i, process) in self.processes.iter_mut().enumerate() {
-            if process.is_ready() {
-                let mut ready_process = self.processes.remove(i).expect("Expected process");
-                *tf = *ready_process.context;
+        let now = timer::current_time();
+        let id = self.processes.switch_to(tf, now)?;
 
-                ready_process.state = State::Running;
+        timer::tick_in(self.processes.slice(id, now, TICK));
+        return Some(id);
+    }
 
-                let pid = ready_process.context.tpidr;
-                self.processes.push_front(ready_process);
+    /// Returns when the next real-time process that used up its budget can
+    /// run again, if any. See `RunQueue::next_release()`.
+    fn next_release(&self) -> Option<Duration> {
+        return self.processes.next_release();
+    }
+
+    /// Returns the priority of the process `pid`, or of the current process
+    /// if `pid` is 0.
+    ///
+    /// Returns `NoEntry` if there is no such process.
+    fn priority(&mut self, pid: Id, tf: &TrapFrame) -> OsResult<usize> {
+        let pid = if pid == 0 { tf.tpidr } else { pid };
+        let process = self.processes.get_mut(pid).ok_or(OsError::NoEntry)?;
+        return Ok(process.priority);
+    }
 
-                return Some(pid);
+    /// Sets the priority of the process `pid`, or of the current process if
+    /// `pid` is 0, to `priority`. A process can only change its own priority
+    /// and those of its children. A priority can always be lowered, but only
+    /// raised as high as the priority of the process's parent, so a process
+    /// without a parent cannot raise its priority.
+    ///
+    /// Returns `InvalidArgument` if `priority` is not a valid priority,
+    /// `NoEntry` if there is no such process or it is not a child, and
+    /// `NoAccess` if the priority would be raised above the parent's.
+    fn set_priority(&mut self, pid: Id, priority: usize, tf: &TrapFrame) -> OsResult<()> {
+        if priority >= LEVELS {
+            return Err(OsError::InvalidArgument);
+        }
+
+        let pid = if pid == 0 { tf.tpidr } else { pid };
+        let process = self.processes.get_mut(pid).ok_or(OsError::NoEntry)?;
+        if pid != tf.tpidr && process.parent != Some(tf.tpidr) {
+            return Err(OsError::NoEntry);
+        }
+
+        if priority < process.priority {
+            let parent = process.parent.and_then(|parent| self.processes.get_mut(parent));
+            match parent {
+                Some(parent) if priority >= parent.priority => {}
+                _ => return Err(OsError::NoAccess),
             }
         }
-        return None;
+
+        self.processes.set_priority(pid, priority);
+        return Ok(());
     }
 
-    /// Kills currently running process by scheduling out the current process
-    /// as `Dead` state. Removes the dead process from the queue, drop the
-    /// dead process's instance, and returns the dead process's process ID.
+    /// Wakes up the processes waiting for `event`, which occurred at `now`.
+    fn wake(&mut self, event: Event, now: Duration) {
+        self.processes.wake(event, now);
+    }
+
+    /// Makes the current process a real-time process with `budget` of every
+    /// `period`, starting now, or a normal one again if `reservation` is
+    /// `None`. Real-time processes are scheduled earliest deadline first,
+    /// ahead of all other processes, and are stopped once they used up their
+    /// budget for the current period.
+    ///
+    /// Returns `Oversubscribed` if the real-time processes would reserve too
+    /// much of the CPU. For the other errors, see `RunQueue::set_realtime()`.
+    fn set_realtime(&mut self, reservation: Option<(Duration, Duration)>, tf: &TrapFrame) -> OsResult<()> {
+        return self.processes.set_realtime(tf.tpidr, reservation, timer::current_time());
+    }
+
+    /// Kills currently running process with the exit code `KILLED`. Removes
+    /// the process from the queue, drop the process's instance, and returns
+    /// the dead process's process ID.
     fn kill(&mut self, tf: &mut TrapFrame) -> Option<Id> {
-        if !self.schedule_out(State::Dead, tf) {
-            return None;
+        return self.exit(KILLED, tf);
+    }
+
+    /// Removes the currently running process from the queue and drops it,
+    /// releasing its memory and open files. If its parent is alive, `code` is
+    /// recorded in the parent's `exited` list for `wait`; the process's own
+    /// children lose their parent.
+    ///
+    /// Returns the process's ID, or `None` if there is no current process.
+    fn exit(&mut self, code: i32, tf: &TrapFrame) -> Option<Id> {
+        return self.remove(tf.tpidr, code);
+    }
+
+    /// Kills the process holding the most memory, as measured by
+    /// `Process::size()`, with the exit code `KILLED`. Returns its ID and
+    /// size, or `None` if there are no processes.
+    fn kill_largest(&mut self) -> Option<(Id, usize)> {
+        let (pid, size) = self.processes
+            .iter()
+            .map(|process| (process.context.tpidr, process.size()))
+            .max_by_key(|&(_, size)| size)?;
+
+        self.remove(pid, KILLED)?;
+        return Some((pid, size));
+    }
+
+    /// Removes the process `pid` from the queue and drops it, as described
+    /// for `exit()`. Returns `pid`, or `None` if there is no such process.
+    fn remove(&mut self, pid: Id, code: i32) -> Option<Id> {
+        let mut process = self.processes.remove(pid)?;
+
+        // Nobody is left to report a failure to.
+        let _ = process.sync_mappings();
+
+        for other in self.processes.iter_mut() {
+            if other.parent == Some(pid) {
+                other.parent = None;
+            }
         }
 
-        for (i, process) in self.processes.iter_mut().enumerate() {
-            match process.state {
-                State::Dead => {
-                    let dead_process = self.processes.remove(i).expect("Expected process");
-                    return Some(dead_process.context.tpidr);
-                },
-                _ => continue,
+        if let Some(parent) = process.parent {
+            if let Some(parent) = self.processes.get_mut(parent) {
+                parent.exited.push((pid, code));
             }
+
+            self.processes.wake(Event::ChildExit(pid), timer::current_time());
+        }
+
+        return Some(pid);
+    }
+
+    /// Returns `Some` of the exit code of the current process's child `pid`
+    /// if the child has exited, removing it from the `exited` list, or `None`
+    /// if the child is still alive.
+    ///
+    /// Returns `NoEntry` if `pid` is not a child of the current process.
+    fn child_status(&mut self, pid: Id, tf: &TrapFrame) -> OsResult<Option<i32>> {
+        let current = self.current(tf).ok_or(OsError::NoEntry)?;
+        if let Some(code) = current.take_exit_code(pid) {
+            return Ok(Some(code));
+        }
+
+        let parent = Some(tf.tpidr);
+        if self.processes.iter().any(|p| p.context.tpidr == pid && p.parent == parent) {
+            return Ok(None);
         }
 
-        return None;
+        return Err(OsError::NoEntry);
     }
 }
 
@@ -27,8 +27,11 @@ impl Stack {
     /// fails for some other reason, returns `None`.
     pub fn new() -> Option<Stack> {
         let raw_ptr = unsafe {
-            let raw_ptr: *mut u8 = ALLOCATOR.alloc(Stack::layout());
-            assert!(!raw_ptr.is_null());
+            let raw_ptr: *mut u8 = ALLOCATOR.try_alloc(Stack::layout());
+            if raw_ptr.is_null() {
+                return None;
+            }
+
             raw_ptr.write_bytes(0, Self::SIZE);
             raw_ptr
         };
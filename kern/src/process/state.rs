@@ -1,22 +1,25 @@
 use core::fmt;
+use core::time::Duration;
 
-use alloc::boxed::Box;
+use crate::process::Id;
 
-use crate::process::Process;
-
-/// Type of a function used to determine if a process is ready to be scheduled
-/// again. The scheduler calls this function when it is the process's turn to
-/// execute. If the function returns `true`, the process is scheduled. If it
-/// returns `false`, the process is not scheduled, and this function will be
-/// called on the next time slice.
-pub type EventPollFn = Box<dyn FnMut(&mut Process) -> bool + Send>;
+/// An event a process can wait for.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Event {
+    /// The kernel timer set by `sleep` reaching `deadline`, for a process
+    /// that went to sleep at `start`.
+    Timer { start: Duration, deadline: Duration },
+    /// The exit of the child with the given ID.
+    ChildExit(Id),
+}
 
 /// The scheduling state of a process.
 pub enum State {
     /// The process is ready to be scheduled.
     Ready,
-    /// The process is waiting on an event to occur before it can be scheduled.
-    Waiting(EventPollFn),
+    /// The process is waiting on an event to occur before it can be
+    /// scheduled. It is woken up by whoever makes the event occur.
+    Waiting(Event),
     /// The process is currently running.
     Running,
     /// The process is currently dead (ready to be reclaimed).
@@ -28,7 +31,7 @@ impl fmt::Debug for State {
         match *self {
             State::Ready => write!(f, "State::Ready"),
             State::Running => write!(f, "State::Running"),
-            State::Waiting(_) => write!(f, "State::Waiting"),
+            State::Waiting(ref event) => write!(f, "State::Waiting({:?})", event),
             State::Dead => write!(f, "State::Dead"),
         }
     }
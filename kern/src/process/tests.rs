@@ -0,0 +1,584 @@
+mod elf {
+    use crate::param::PAGE_SIZE;
+    use crate::process::elf::*;
+    use crate::vm::PagePerm;
+    use kernel_api::{OsError, OsResult};
+    use shim::io::Cursor;
+
+    const BASE: u64 = 0xffff_ffff_c000_0000;
+    const PAYLOAD: u64 = 0x100;
+
+    const PT_LOAD: u32 = 1;
+    const PT_NOTE: u32 = 4;
+
+    /// A program header: (type, flags, offset, vaddr, file size, mem size).
+    type Header = (u32, u32, u64, u64, u64, u64);
+
+    /// Builds an ELF64 AArch64 executable with entry point `entry` and the
+    /// program headers `headers`, followed by `payload` at offset `PAYLOAD`.
+    fn image(entry: u64, headers: &[Header], payload: &[u8]) -> Vec<u8> {
+        let mut data = vec![0u8; PAYLOAD as usize + payload.len()];
+
+        data[0..4].copy_from_slice(b"\x7fELF");
+        data[4] = 2; // 64-bit
+        data[5] = 1; // little-endian
+        data[6] = 1; // version
+        data[16..18].copy_from_slice(&2u16.to_le_bytes()); // executable
+        data[18..20].copy_from_slice(&183u16.to_le_bytes()); // aarch64
+        data[20..24].copy_from_slice(&1u32.to_le_bytes());
+        data[24..32].copy_from_slice(&entry.to_le_bytes());
+        data[32..40].copy_from_slice(&64u64.to_le_bytes());
+        data[52..54].copy_from_slice(&64u16.to_le_bytes());
+        data[54..56].copy_from_slice(&56u16.to_le_bytes());
+        data[56..58].copy_from_slice(&(headers.len() as u16).to_le_bytes());
+
+        for (i, &(kind, flags, offset, vaddr, file_size, mem_size)) in headers.iter().enumerate() {
+            let ph = &mut data[64 + i * 56..64 + (i + 1) * 56];
+            ph[0..4].copy_from_slice(&kind.to_le_bytes());
+            ph[4..8].copy_from_slice(&flags.to_le_bytes());
+            ph[8..16].copy_from_slice(&offset.to_le_bytes());
+            ph[16..24].copy_from_slice(&vaddr.to_le_bytes());
+            ph[24..32].copy_from_slice(&vaddr.to_le_bytes());
+            ph[32..40].copy_from_slice(&file_size.to_le_bytes());
+            ph[40..48].copy_from_slice(&mem_size.to_le_bytes());
+            ph[48..56].copy_from_slice(&0x10000u64.to_le_bytes());
+        }
+
+        data[PAYLOAD as usize..].copy_from_slice(payload);
+        data
+    }
+
+    /// A text segment holding the 8 payload bytes at `BASE` and a data
+    /// segment holding the next 8 with 0x100 bytes of .bss one page higher.
+    fn sample() -> Vec<u8> {
+        image(BASE + 4, &[
+            (PT_LOAD, PF_R | PF_X, PAYLOAD, BASE, 8, 8),
+            (PT_LOAD, PF_R | PF_W, PAYLOAD + 8, BASE + 0x10000, 8, 0x100),
+        ], b"textcodedatadata")
+    }
+
+    /// A user program linked with the layout of `user/fib`. See
+    /// `ext/elf/exit.rs` for its source and how it was built.
+    const EXIT_ELF: &[u8] = include_bytes!("../../../ext/elf/exit.elf");
+
+    fn u64_at(data: &[u8], offset: usize) -> u64 {
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(&data[offset..offset + 8]);
+        return u64::from_le_bytes(bytes);
+    }
+
+    fn parse(data: &[u8]) -> OsResult<Elf> {
+        return Elf::read(&mut Cursor::new(data));
+    }
+
+    /// Returns the bytes of `segment` stored in the image `data`.
+    fn contents<'a>(data: &'a [u8], segment: &Segment) -> &'a [u8] {
+        let start = segment.offset as usize;
+        return &data[start..start + segment.file_size as usize];
+    }
+
+    fn assert_invalid(data: &[u8]) {
+        match parse(data) {
+            Err(OsError::InvalidArgument) => {}
+            other => panic!("expected InvalidArgument, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_sample() {
+        let data = sample();
+        let elf = parse(&data).expect("valid image");
+
+        assert_eq!(elf.entry(), BASE + 4);
+        assert_eq!(elf.segments(), &[
+            Segment { vaddr: BASE, offset: PAYLOAD, file_size: 8, mem_size: 8, flags: PF_R | PF_X },
+            Segment { vaddr: BASE + 0x10000, offset: PAYLOAD + 8, file_size: 8, mem_size: 0x100, flags: PF_R | PF_W },
+        ]);
+
+        let text = &elf.segments()[0];
+        let bss = &elf.segments()[1];
+        assert_eq!(contents(&data, text), b"textcode");
+        assert_eq!(contents(&data, bss), b"datadata");
+        assert_eq!(text.end(), BASE + 8);
+        assert_eq!(bss.end(), BASE + 0x10100);
+        assert_eq!(text.perm(), PagePerm::RX);
+        assert_eq!(bss.perm(), PagePerm::RW);
+    }
+
+    #[test]
+    fn test_parse_user_program() {
+        let elf = parse(EXIT_ELF).expect("valid image");
+        assert_eq!(elf.entry(), BASE);
+
+        let perms: Vec<PagePerm> = elf.segments().iter().map(|segment| segment.perm()).collect();
+        assert_eq!(perms, vec![PagePerm::RX, PagePerm::RO, PagePerm::RW]);
+
+        // Every loadable segment is page-aligned, so its offset in the file
+        // and its address are congruent modulo the page size
+        let phoff = u64_at(EXIT_ELF, 32) as usize;
+        let phnum = u16::from_le_bytes([EXIT_ELF[56], EXIT_ELF[57]]) as usize;
+        let aligns: Vec<u64> = (0..phnum)
+            .map(|i| &EXIT_ELF[phoff + i * 56..phoff + (i + 1) * 56])
+            .filter(|ph| ph[0..4] == PT_LOAD.to_le_bytes())
+            .map(|ph| u64_at(ph, 48))
+            .collect();
+        assert_eq!(aligns, vec![PAGE_SIZE as u64; 3]);
+
+        for segment in elf.segments() {
+            assert_eq!(segment.offset % PAGE_SIZE as u64, segment.vaddr % PAGE_SIZE as u64);
+            assert_eq!(contents(EXIT_ELF, segment).len() as u64, segment.file_size);
+        }
+
+        // The text ends in `svc 3; brk 1`, the data segment has .bss
+        let (text, rodata, data) = (&elf.segments()[0], &elf.segments()[1], &elf.segments()[2]);
+        assert_eq!(&contents(EXIT_ELF, text)[8..], &[0x61, 0x00, 0x00, 0xd4, 0x20, 0x00, 0x20, 0xd4]);
+        assert_eq!(contents(EXIT_ELF, rodata), b"exit.elf rodata\n");
+        assert_eq!(contents(EXIT_ELF, data), &0x1234u64.to_le_bytes());
+        assert!(data.mem_size > data.file_size);
+    }
+
+    #[test]
+    fn test_skips_other_segments() {
+        let data = image(BASE, &[
+            (PT_NOTE, PF_R, PAYLOAD, 0, 4, 4),
+            (PT_LOAD, PF_R, PAYLOAD, BASE + 0x20000, 0, 0),
+            (PT_LOAD, PF_R | PF_X, PAYLOAD, BASE, 4, 4),
+        ], b"code");
+        let elf = parse(&data).expect("valid image");
+
+        assert_eq!(elf.segments().len(), 1);
+        assert_eq!(elf.segments()[0].vaddr, BASE);
+    }
+
+    #[test]
+    fn test_page_perm() {
+        assert_eq!(page_perm(PF_R), PagePerm::RO);
+        assert_eq!(page_perm(PF_R | PF_W), PagePerm::RW);
+        assert_eq!(page_perm(PF_R | PF_X), PagePerm::RX);
+        assert_eq!(page_perm(PF_R | PF_W | PF_X), PagePerm::RWX);
+    }
+
+    #[test]
+    fn test_rejects_bad_header() {
+        assert_invalid(&[]);
+        assert_invalid(&sample()[..40]);
+
+        let corruptions: &[(usize, u8)] = &[
+            (0, 0x7e), // magic
+            (4, 1),    // 32-bit
+            (5, 2),    // big-endian
+            (6, 0),    // version
+            (16, 3),   // shared object
+            (18, 62),  // x86-64
+            (54, 32),  // program header size
+        ];
+
+        for &(offset, value) in corruptions {
+            let mut data = sample();
+            data[offset] = value;
+            assert_invalid(&data);
+        }
+    }
+
+    #[test]
+    fn test_rejects_bad_program_headers() {
+        // Program headers past the end of the file
+        let mut data = sample();
+        data[32..40].copy_from_slice(&0x1000u64.to_le_bytes());
+        assert_invalid(&data);
+
+        let mut data = sample();
+        data[32..40].copy_from_slice(&core::u64::MAX.to_le_bytes());
+        assert_invalid(&data);
+
+        // Segment contents past the end of the file
+        assert_invalid(&image(BASE, &[(PT_LOAD, PF_X, PAYLOAD, BASE, 8, 8)], b"code"));
+        assert_invalid(&image(BASE, &[(PT_LOAD, PF_X, core::u64::MAX, BASE, 4, 4)], b"code"));
+
+        // More bytes in the file than in memory
+        assert_invalid(&image(BASE, &[(PT_LOAD, PF_X, PAYLOAD, BASE, 4, 2)], b"code"));
+
+        // Segment wrapping around the address space
+        assert_invalid(&image(BASE, &[(PT_LOAD, PF_X, PAYLOAD, BASE, 4, core::u64::MAX)], b"code"));
+    }
+
+    #[test]
+    fn test_rejects_bad_entry() {
+        // Entry in a writable but not executable segment
+        assert_invalid(&image(BASE, &[(PT_LOAD, PF_R | PF_W, PAYLOAD, BASE, 4, 4)], b"code"));
+
+        // Entry past the end of the executable segment
+        assert_invalid(&image(BASE + 4, &[(PT_LOAD, PF_R | PF_X, PAYLOAD, BASE, 4, 4)], b"code"));
+
+        // No loadable segment at all
+        assert_invalid(&image(BASE, &[], b""));
+    }
+}
+
+mod runqueue {
+    use core::time::Duration;
+
+    use crate::process::runqueue::*;
+    use crate::process::{Event, Id};
+    use crate::traps::TrapFrame;
+    use kernel_api::OsError;
+
+    #[derive(Debug)]
+    struct FakeTask {
+        context: TrapFrame,
+        priority: usize,
+        running: bool,
+        realtime: Option<RealTime>,
+        /// The time of the last wake-up.
+        woken: Option<Duration>,
+    }
+
+    impl FakeTask {
+        fn new(id: Id, priority: usize) -> FakeTask {
+            let mut context = TrapFrame::zeroed();
+            context.tpidr = id;
+            return FakeTask { context, priority, running: false, realtime: None, woken: None };
+        }
+    }
+
+    impl Task for FakeTask {
+        fn id(&self) -> Id {
+            return self.context.tpidr;
+        }
+
+        fn priority(&self) -> usize {
+            return self.priority;
+        }
+
+        fn set_priority(&mut self, priority: usize) {
+            self.priority = priority;
+        }
+
+        fn realtime(&self) -> Option<&RealTime> {
+            return self.realtime.as_ref();
+        }
+
+        fn realtime_mut(&mut self) -> Option<&mut RealTime> {
+            return self.realtime.as_mut();
+        }
+
+        fn set_realtime(&mut self, realtime: Option<RealTime>) {
+            self.realtime = realtime;
+        }
+
+        fn context(&mut self) -> &mut TrapFrame {
+            return &mut self.context;
+        }
+
+        fn wake(&mut self, now: Duration) {
+            self.woken = Some(now);
+        }
+
+        fn run(&mut self) {
+            self.running = true;
+        }
+    }
+
+    fn ms(ms: u64) -> Duration {
+        return Duration::from_millis(ms);
+    }
+
+    fn sleep(until: u64) -> Event {
+        return Event::Timer { start: ms(0), deadline: ms(until) };
+    }
+
+    /// Returns a queue of normal tasks with the given priorities, numbered
+    /// from 1.
+    fn queue(priorities: &[usize]) -> RunQueue<FakeTask> {
+        let mut queue = RunQueue::new();
+        for (i, &priority) in priorities.iter().enumerate() {
+            queue.push(FakeTask::new(i as Id + 1, priority));
+        }
+
+        return queue;
+    }
+
+    /// Runs the queue for `slices` time slices, starting with the task whose
+    /// ID is in `tf`, and returns the IDs of the tasks that ran.
+    fn run(queue: &mut RunQueue<FakeTask>, tf: &mut TrapFrame, slices: usize) -> Vec<Id> {
+        let mut ran = Vec::new();
+        for _ in 0..slices {
+            queue.schedule_out(tf, ms(0), None);
+            ran.push(queue.switch_to(tf, ms(0)).expect("a ready task"));
+        }
+
+        return ran;
+    }
+
+    /// Runs the queue from `now` for as long as `end`, each task for its full
+    /// time slice, and returns the IDs of the tasks that ran with the time
+    /// they were switched to.
+    fn run_until(queue: &mut RunQueue<FakeTask>, mut now: Duration, end: Duration) -> Vec<(Id, u64)> {
+        let mut tf = TrapFrame::zeroed();
+        let mut ran = Vec::new();
+        while now < end {
+            let id = queue.switch_to(&mut tf, now).expect("a ready task");
+            ran.push((id, now.as_millis() as u64));
+
+            now += queue.slice(id, now, ms(10));
+            queue.schedule_out(&tf, now, None);
+        }
+
+        return ran;
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let mut queue = queue(&[4, 4, 4]);
+
+        let mut tf = TrapFrame::zeroed();
+        assert_eq!(queue.switch_to(&mut tf, ms(0)), Some(1));
+        assert_eq!(tf.tpidr, 1);
+        assert_eq!(run(&mut queue, &mut tf, 5), vec![2, 3, 1, 2, 3]);
+        assert_eq!(queue.len(), 3);
+    }
+
+    #[test]
+    fn test_saves_context() {
+        let mut queue = queue(&[4, 4]);
+
+        let mut tf = TrapFrame::zeroed();
+        assert_eq!(queue.switch_to(&mut tf, ms(0)), Some(1));
+        tf.elr = 0x1234;
+
+        queue.schedule_out(&tf, ms(0), None).expect("task 1");
+        assert_eq!(queue.switch_to(&mut tf, ms(0)), Some(2));
+        assert_eq!(tf.elr, 0);
+
+        queue.schedule_out(&tf, ms(0), None).expect("task 2");
+        assert_eq!(queue.switch_to(&mut tf, ms(0)), Some(1));
+        assert_eq!(tf.elr, 0x1234);
+        assert!(queue.get_mut(1).unwrap().running);
+    }
+
+    #[test]
+    fn test_strict_priority() {
+        let mut queue = queue(&[6, 2, 2]);
+
+        let mut tf = TrapFrame::zeroed();
+        assert_eq!(queue.switch_to(&mut tf, ms(0)), Some(2));
+        assert_eq!(run(&mut queue, &mut tf, 4), vec![3, 2, 3, 2]);
+
+        // The less urgent task runs once the others are gone
+        queue.remove(2).expect("task 2");
+        queue.remove(3).expect("task 3");
+        let mut tf = TrapFrame::zeroed();
+        assert_eq!(queue.switch_to(&mut tf, ms(0)), Some(1));
+        assert!(queue.schedule_out(&TrapFrame { tpidr: 9, ..TrapFrame::zeroed() }, ms(0), None).is_none());
+    }
+
+    #[test]
+    fn test_wait_for_event() {
+        let mut queue = queue(&[4, 4, 4]);
+
+        // Waiting tasks are not scheduled
+        let mut tf = TrapFrame::zeroed();
+        assert_eq!(queue.switch_to(&mut tf, ms(0)), Some(1));
+        queue.schedule_out(&tf, ms(0), Some(Event::ChildExit(9))).expect("task 1");
+        assert_eq!(queue.switch_to(&mut tf, ms(0)), Some(2));
+        assert_eq!(run(&mut queue, &mut tf, 2), vec![3, 2]);
+        assert_eq!(queue.len(), 3);
+
+        queue.wake(Event::ChildExit(8), ms(5));
+        assert_eq!(queue.get_mut(1).unwrap().woken, None);
+
+        // The woken task gets ahead of the others, then returns to the end of
+        // its own level
+        queue.wake(Event::ChildExit(9), ms(5));
+        assert_eq!(queue.get_mut(1).unwrap().woken, Some(ms(5)));
+        assert_eq!(run(&mut queue, &mut tf, 4), vec![1, 3, 2, 1]);
+        assert_eq!(queue.get_mut(1).unwrap().priority, 4);
+
+        // Nothing is left waiting for the event
+        queue.get_mut(1).unwrap().woken = None;
+        queue.wake(Event::ChildExit(9), ms(10));
+        assert_eq!(queue.get_mut(1).unwrap().woken, None);
+    }
+
+    #[test]
+    fn test_wait_for_timer() {
+        let mut queue = queue(&[4, 4, 4]);
+
+        let mut tf = TrapFrame::zeroed();
+        assert_eq!(queue.switch_to(&mut tf, ms(0)), Some(1));
+        queue.schedule_out(&tf, ms(0), Some(sleep(25))).expect("task 1");
+        assert_eq!(queue.switch_to(&mut tf, ms(0)), Some(2));
+        queue.schedule_out(&tf, ms(0), Some(sleep(15))).expect("task 2");
+        assert_eq!(queue.switch_to(&mut tf, ms(0)), Some(3));
+
+        // Time passing wakes nobody up, only the timer of each sleep does
+        queue.schedule_out(&tf, ms(30), None);
+        assert_eq!(queue.switch_to(&mut tf, ms(30)), Some(3));
+
+        queue.wake(sleep(15), ms(31));
+        assert_eq!(queue.get_mut(1).unwrap().woken, None);
+        assert_eq!(queue.get_mut(2).unwrap().woken, Some(ms(31)));
+        queue.schedule_out(&tf, ms(31), None);
+        assert_eq!(queue.switch_to(&mut tf, ms(31)), Some(2));
+    }
+
+    #[test]
+    fn test_wait_boost_saturates() {
+        let mut queue = queue(&[0, 1]);
+
+        // Task 2 wakes up at the end of level 0
+        let mut tf = TrapFrame { tpidr: 2, ..TrapFrame::zeroed() };
+        queue.schedule_out(&tf, ms(0), Some(sleep(0))).expect("task 2");
+        queue.wake(sleep(0), ms(0));
+        assert_eq!(queue.switch_to(&mut tf, ms(0)), Some(1));
+        assert_eq!(run(&mut queue, &mut tf, 3), vec![2, 1, 1]);
+    }
+
+    #[test]
+    fn test_remove_waiting() {
+        let mut queue = queue(&[4, 4]);
+
+        let mut tf = TrapFrame::zeroed();
+        assert_eq!(queue.switch_to(&mut tf, ms(0)), Some(1));
+        queue.schedule_out(&tf, ms(0), Some(sleep(10))).expect("task 1");
+        assert_eq!(queue.switch_to(&mut tf, ms(0)), Some(2));
+        queue.schedule_out(&tf, ms(0), Some(Event::ChildExit(1))).expect("task 2");
+        assert_eq!(queue.switch_to(&mut tf, ms(0)), None);
+
+        // A waiting task keeps waiting with a new priority
+        assert!(queue.set_priority(2, 0));
+        assert_eq!(queue.switch_to(&mut tf, ms(0)), None);
+
+        assert_eq!(queue.remove(1).map(|task| task.id()), Some(1));
+        assert_eq!(queue.switch_to(&mut tf, ms(20)), None);
+        assert_eq!(queue.len(), 1);
+        assert_eq!(queue.iter().map(|task| task.id()).collect::<Vec<_>>(), vec![2]);
+
+        queue.wake(Event::ChildExit(1), ms(20));
+        assert_eq!(queue.switch_to(&mut tf, ms(20)), Some(2));
+    }
+
+    #[test]
+    fn test_set_priority() {
+        let mut queue = queue(&[4, 4]);
+        assert!(!queue.set_priority(3, 0));
+
+        assert!(queue.set_priority(2, 0));
+        assert_eq!(queue.get_mut(2).unwrap().priority, 0);
+
+        let mut tf = TrapFrame::zeroed();
+        assert_eq!(queue.switch_to(&mut tf, ms(0)), Some(2));
+        assert_eq!(run(&mut queue, &mut tf, 2), vec![2, 2]);
+
+        assert!(queue.set_priority(2, LEVELS - 1));
+        assert_eq!(run(&mut queue, &mut tf, 2), vec![1, 1]);
+        assert_eq!(queue.iter().map(|task| task.id()).collect::<Vec<_>>(), vec![1, 2]);
+    }
+
+    #[test]
+    fn test_realtime_admission() {
+        let mut queue = queue(&[4, 4, 4]);
+
+        let invalid = [
+            (ms(0), ms(0)),
+            (Duration::from_micros(999), ms(1)),
+            (ms(10), ms(0)),
+            (ms(10), ms(11)),
+        ];
+
+        for &reservation in invalid.iter() {
+            assert_eq!(queue.set_realtime(1, Some(reservation), ms(0)), Err(OsError::InvalidArgument));
+        }
+
+        assert_eq!(queue.set_realtime(4, Some((ms(10), ms(1))), ms(0)), Err(OsError::NoEntry));
+
+        assert_eq!(queue.set_realtime(1, Some((ms(10), ms(5))), ms(0)), Ok(()));
+        assert_eq!(queue.set_realtime(2, Some((ms(100), ms(40))), ms(0)), Ok(()));
+        assert_eq!(queue.set_realtime(3, Some((ms(100), ms(1))), ms(0)), Err(OsError::Oversubscribed));
+
+        // Changing a reservation only counts it once
+        assert_eq!(queue.set_realtime(2, Some((ms(100), ms(30))), ms(0)), Ok(()));
+        assert_eq!(queue.set_realtime(3, Some((ms(100), ms(10))), ms(0)), Ok(()));
+        assert_eq!(queue.get_mut(3).unwrap().realtime.unwrap().utilization(), 100_000);
+
+        assert_eq!(queue.set_realtime(1, None, ms(0)), Ok(()));
+        assert!(queue.get_mut(1).unwrap().realtime.is_none());
+        assert_eq!(queue.set_realtime(3, Some((ms(100), ms(60))), ms(0)), Ok(()));
+    }
+
+    #[test]
+    fn test_realtime_long_period() {
+        let mut queue = queue(&[4, 4]);
+
+        // Scaling the budget to millionths does not overflow
+        let period = Duration::from_micros(1 << 45);
+        assert_eq!(RealTime::new(period, period, ms(0)).utilization(), 1_000_000);
+        assert_eq!(queue.set_realtime(1, Some((period, period)), ms(0)), Err(OsError::Oversubscribed));
+
+        let half = Duration::from_micros(1 << 44);
+        assert_eq!(queue.set_realtime(1, Some((period, half)), ms(0)), Ok(()));
+        assert_eq!(queue.set_realtime(2, Some((period, half)), ms(0)), Err(OsError::Oversubscribed));
+    }
+
+    #[test]
+    fn test_realtime_budget() {
+        let mut queue = queue(&[0, 4]);
+        queue.set_realtime(2, Some((ms(20), ms(5))), ms(0)).unwrap();
+
+        // The real-time task runs first for its budget, then waits for its
+        // next period while the other one runs
+        let ran = run_until(&mut queue, ms(0), ms(60));
+        assert_eq!(ran, vec![(2, 0), (1, 5), (1, 15), (2, 20), (1, 25), (1, 35), (2, 40), (1, 45), (1, 55)]);
+    }
+
+    #[test]
+    fn test_realtime_earliest_deadline_first() {
+        let mut queue = queue(&[4, 4, 4]);
+        queue.set_realtime(2, Some((ms(30), ms(10))), ms(0)).unwrap();
+        queue.set_realtime(3, Some((ms(20), ms(5))), ms(0)).unwrap();
+
+        let ran = run_until(&mut queue, ms(0), ms(40));
+        assert_eq!(ran, vec![(3, 0), (2, 5), (1, 15), (3, 20), (1, 25), (2, 30)]);
+    }
+
+    #[test]
+    fn test_realtime_exhausted_alone() {
+        let mut queue = queue(&[4]);
+        queue.set_realtime(1, Some((ms(20), ms(5))), ms(0)).unwrap();
+        assert_eq!(queue.next_release(), None);
+
+        let mut tf = TrapFrame::zeroed();
+        assert_eq!(queue.switch_to(&mut tf, ms(0)), Some(1));
+        queue.schedule_out(&tf, ms(5), None).expect("task 1");
+
+        // Nothing can run until the next period starts
+        assert_eq!(queue.switch_to(&mut tf, ms(5)), None);
+        assert_eq!(queue.next_release(), Some(ms(20)));
+        assert_eq!(queue.switch_to(&mut tf, ms(19)), None);
+        assert_eq!(queue.switch_to(&mut tf, ms(20)), Some(1));
+        assert_eq!(queue.next_release(), None);
+    }
+
+    #[test]
+    fn test_realtime_waiting() {
+        let mut queue = queue(&[4, 4]);
+        queue.set_realtime(2, Some((ms(100), ms(5))), ms(0)).unwrap();
+
+        let mut tf = TrapFrame::zeroed();
+        assert_eq!(queue.switch_to(&mut tf, ms(0)), Some(2));
+        queue.schedule_out(&tf, ms(0), Some(sleep(10))).expect("task 2");
+
+        // The real-time task runs as soon as it wakes up
+        assert_eq!(run_until(&mut queue, ms(0), ms(10)), vec![(1, 0)]);
+        queue.wake(sleep(10), ms(10));
+        assert_eq!(run_until(&mut queue, ms(10), ms(30)), vec![(2, 10), (1, 15), (1, 25)]);
+
+        // Its periods go on while it waits
+        assert_eq!(queue.switch_to(&mut tf, ms(150)), Some(2));
+        queue.schedule_out(&tf, ms(151), Some(sleep(400))).expect("task 2");
+        assert_eq!(queue.switch_to(&mut tf, ms(250)), Some(1));
+
+        let realtime = queue.get_mut(2).unwrap().realtime.unwrap();
+        assert_eq!((realtime.deadline, realtime.remaining), (ms(300), ms(5)));
+    }
+}
@@ -0,0 +1,77 @@
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::mutex::Mutex;
+use crate::param::PAGE_SIZE;
+use crate::vm::{PhysicalAddr, UserPageTable, VirtualAddr};
+use crate::FRAMES;
+
+/// The read-only pages of the programs loaded so far, so that several
+/// instances of the same program share one copy of its text.
+pub static TEXT_CACHE: TextCache = TextCache::new();
+
+/// Frames of read-only program pages, indexed by program path and virtual
+/// address. The cache holds a reference to each frame.
+pub struct TextCache(Mutex<Option<BTreeMap<(String, usize), PhysicalAddr>>>);
+
+impl TextCache {
+    /// Returns an empty cache.
+    pub const fn new() -> TextCache {
+        TextCache(Mutex::new(None))
+    }
+
+    /// Shares the read-only page just loaded from the program `path` at the
+    /// page-aligned address `va` of `vmap`: if the cache holds a frame for
+    /// it with identical contents, that frame replaces the loaded one.
+    /// Otherwise the loaded frame is cached for later instances.
+    ///
+    /// Comparing the contents keeps a program that changed on disk from
+    /// picking up stale pages.
+    pub fn share(&self, path: &str, va: VirtualAddr, vmap: &mut UserPageTable) {
+        let loaded = vmap.frame(va).expect("loaded page is mapped");
+        let key = (String::from(path), va.as_usize());
+
+        let mut cache = self.0.lock();
+        let cache = cache.get_or_insert_with(BTreeMap::new);
+
+        if let Some(&cached) = cache.get(&key) {
+            if contents(cached) == contents(loaded) {
+                vmap.share_frame(va, cached);
+                return;
+            }
+        }
+
+        FRAMES.share(loaded);
+        if let Some(stale) = cache.insert(key, loaded) {
+            FRAMES.release(stale);
+        }
+    }
+
+    /// Drops the cached frames that no process maps anymore.
+    pub fn prune(&self) {
+        let mut cache = self.0.lock();
+        let cache = match cache.as_mut() {
+            Some(cache) => cache,
+            None => return,
+        };
+
+        let unused: Vec<_> = cache.iter()
+            .filter(|&(_, &frame)| FRAMES.count(frame) == 1)
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        for key in unused {
+            if let Some(frame) = cache.remove(&key) {
+                FRAMES.release(frame);
+            }
+        }
+    }
+}
+
+/// Returns the contents of the page frame `frame`.
+fn contents<'a>(frame: PhysicalAddr) -> &'a [u8] {
+    unsafe {
+        return core::slice::from_raw_parts(frame.as_ptr(), PAGE_SIZE);
+    }
+}
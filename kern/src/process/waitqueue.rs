@@ -0,0 +1,50 @@
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec::Vec;
+
+use crate::process::{Event, Id};
+
+/// The tasks waiting for events, keyed by event.
+///
+/// Every event maps to the tasks that wait for it. Nothing is ever polled: a
+/// task only leaves the queue when whoever makes its event occur wakes it
+/// up, such as the kernel timer of a `sleep`.
+#[derive(Debug)]
+pub struct WaitQueue {
+    /// The tasks waiting for each event, in the order they started waiting.
+    events: BTreeMap<Event, VecDeque<Id>>,
+}
+
+impl WaitQueue {
+    /// Returns an empty wait queue.
+    pub fn new() -> WaitQueue {
+        WaitQueue { events: BTreeMap::new() }
+    }
+
+    /// Adds the task `id` waiting for `event`.
+    pub fn push(&mut self, id: Id, event: Event) {
+        self.events.entry(event).or_insert_with(VecDeque::new).push_back(id);
+    }
+
+    /// Removes the tasks waiting for `event` and returns their IDs in the
+    /// order they started waiting.
+    pub fn take(&mut self, event: Event) -> VecDeque<Id> {
+        return self.events.remove(&event).unwrap_or_default();
+    }
+
+    /// Removes the task `id` from the queue, whatever it waits for.
+    pub fn remove(&mut self, id: Id) {
+        for waiters in self.events.values_mut() {
+            waiters.retain(|&waiter| waiter != id);
+        }
+
+        let unused: Vec<Event> = self.events
+            .iter()
+            .filter(|(_, waiters)| waiters.is_empty())
+            .map(|(&event, _)| event)
+            .collect();
+
+        for event in unused {
+            self.events.remove(&event);
+        }
+    }
+}
@@ -18,6 +18,10 @@ use fat32::vfat::{Dir, Entry, File, VFat, VFatHandle};
 use crate::console::{kprint, kprintln, CONSOLE};
 use crate::ALLOCATOR;
 use crate::FILESYSTEM;
+use crate::FRAMES;
+use crate::SCHEDULER;
+use crate::process::PROCESSES;
+use crate::traps::TRAP_FRAMES;
 
 use kernel_api::*;
 
@@ -183,6 +187,69 @@ impl Shell {
     }
 
 
+    /// Handler for `cache`
+    fn cache_handler(&self, args: &Vec<&str>) {
+        if args.len() > 1 {
+            kprintln!("cache: too many arguments");
+            return;
+        }
+
+        kprintln!("{}", FILESYSTEM.cache_stats());
+    }
+
+    /// Handler for `free`
+    fn free_handler(&self, args: &Vec<&str>) {
+        if args.len() > 1 {
+            kprintln!("free: too many arguments");
+            return;
+        }
+
+        kprintln!("{}", FRAMES.stats());
+    }
+
+    /// Handler for `heap`
+    fn heap_handler(&self, args: &Vec<&str>) {
+        if args.len() > 1 {
+            kprintln!("heap: too many arguments");
+            return;
+        }
+
+        kprintln!("{:#?}", ALLOCATOR);
+    }
+
+    /// Handler for `slabs`
+    fn slabs_handler(&self, args: &Vec<&str>) {
+        if args.len() > 1 {
+            kprintln!("slabs: too many arguments");
+            return;
+        }
+
+        kprintln!("{}", TRAP_FRAMES.stats());
+        kprintln!("{}", PROCESSES.stats());
+    }
+
+    /// Handler for `uptime`
+    fn uptime_handler(&self, args: &Vec<&str>) {
+        if args.len() > 1 {
+            kprintln!("uptime: too many arguments");
+            return;
+        }
+
+        kprintln!("{}", SCHEDULER.idle_stats());
+    }
+
+    /// Handler for `sync`
+    fn sync_handler(&self, args: &Vec<&str>) {
+        if args.len() > 1 {
+            kprintln!("sync: too many arguments");
+            return;
+        }
+
+        if let Err(e) = FILESYSTEM.sync() {
+            kprintln!("sync: {:?}", e);
+        }
+    }
+
     /// Starts a shell using `prefix` as the prefix for each line. This function
     /// never returns.
     pub fn shell(&mut self) {
@@ -225,6 +292,12 @@ impl Shell {
                                     },
                                     &"cat" => self.cat_handler(&command.args),
                                     &"sleep" => self.sleep_handler(&command.args),
+                                    &"cache" => self.cache_handler(&command.args),
+                                    &"sync" => self.sync_handler(&command.args),
+                                    &"free" => self.free_handler(&command.args),
+                                    &"heap" => self.heap_handler(&command.args),
+                                    &"slabs" => self.slabs_handler(&command.args),
+                                    &"uptime" => self.uptime_handler(&command.args),
                                     &"exit" => { 
                                         kprintln!("Exiting shell...");
                                         return; 
@@ -0,0 +1,178 @@
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::fmt;
+use core::time::Duration;
+
+use pi::interrupt::{Controller, Interrupt};
+use pi::timer;
+
+use crate::irq::alarm_handler;
+use crate::mutex::Mutex;
+use crate::IRQ;
+
+/// A function run by the timer interrupt once its deadline passed. It is
+/// given the time at which it runs.
+pub type TimerCallback = Box<dyn FnOnce(Duration) + Send>;
+
+/// The ID of a pending timer.
+pub type TimerId = u64;
+
+/// Deadlines closer than this to the current time are moved back to it, as
+/// the counter might pass them before the compare register is written.
+const MIN_DELAY: Duration = Duration::from_micros(20);
+
+/// A callback waiting for its deadline.
+struct Timer {
+    deadline: Duration,
+    id: TimerId,
+    callback: TimerCallback,
+}
+
+/// The pending timers, sorted by deadline.
+pub struct Timers {
+    /// The timers, latest deadline first, so that the next ones to expire
+    /// are popped off the end.
+    pending: Vec<Timer>,
+    /// The ID of the last timer scheduled.
+    last_id: TimerId,
+}
+
+impl Timers {
+    /// Returns an empty list of timers.
+    fn new() -> Timers {
+        return Timers { pending: Vec::new(), last_id: 0 };
+    }
+
+    /// Adds a timer running `callback` at `deadline` and returns its ID.
+    /// Timers with the same deadline run in the order they were added.
+    fn insert(&mut self, deadline: Duration, callback: TimerCallback) -> TimerId {
+        self.last_id += 1;
+
+        let i = self.pending
+            .iter()
+            .position(|timer| timer.deadline <= deadline)
+            .unwrap_or(self.pending.len());
+        self.pending.insert(i, Timer { deadline, id: self.last_id, callback });
+        return self.last_id;
+    }
+
+    /// Removes the timer `id`. Returns `false` if there is no such timer.
+    fn cancel(&mut self, id: TimerId) -> bool {
+        match self.pending.iter().position(|timer| timer.id == id) {
+            Some(i) => {
+                self.pending.remove(i);
+                return true;
+            }
+            None => return false,
+        }
+    }
+
+    /// Removes the next timer if its deadline passed by `now` and returns its
+    /// callback.
+    fn expired(&mut self, now: Duration) -> Option<TimerCallback> {
+        if self.next_deadline()? > now {
+            return None;
+        }
+
+        return self.pending.pop().map(|timer| timer.callback);
+    }
+
+    /// Returns the earliest deadline of the pending timers, if any.
+    fn next_deadline(&self) -> Option<Duration> {
+        return self.pending.last().map(|timer| timer.deadline);
+    }
+}
+
+impl fmt::Debug for Timers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(self.pending.iter().rev().map(|timer| (timer.id, timer.deadline)))
+            .finish()
+    }
+}
+
+/// The kernel timers, shared by every subsystem that needs to run code at a
+/// given time.
+///
+/// The earliest deadline is programmed into the compare register of system
+/// timer 3, so a callback runs within microseconds of its deadline, with no
+/// relation to the scheduler's `TICK` on timer 1.
+#[derive(Debug)]
+pub struct GlobalTimers(Mutex<Option<Timers>>);
+
+impl GlobalTimers {
+    /// Returns an uninitialized set of timers.
+    pub const fn uninitialized() -> GlobalTimers {
+        GlobalTimers(Mutex::new(None))
+    }
+
+    /// Initializes the timers and enables the timer 3 interrupt.
+    ///
+    /// The caller should assure that `IRQ.initialize()` has been called before
+    /// calling this function.
+    pub fn initialize(&self) {
+        *self.0.lock() = Some(Timers::new());
+
+        IRQ.register(Interrupt::Timer3, Box::new(alarm_handler));
+        Controller::new().enable(Interrupt::Timer3);
+    }
+
+    /// Enter a critical region and execute the provided closure with the
+    /// timers.
+    fn critical<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut Timers) -> R,
+    {
+        let mut guard = self.0.lock();
+        f(guard.as_mut().expect("timers uninitialized"))
+    }
+
+    /// Runs `callback` from the timer interrupt once the system timer reaches
+    /// `deadline`, or as soon as possible if it already has. Returns the
+    /// timer's ID, for `cancel()`.
+    pub fn schedule_at(&self, deadline: Duration, callback: TimerCallback) -> TimerId {
+        let id = self.critical(|timers| timers.insert(deadline, callback));
+        self.program();
+        return id;
+    }
+
+    /// Removes the pending timer `id` without running it. Returns `false` if
+    /// there is no such timer, because it already ran or was cancelled.
+    pub fn cancel(&self, id: TimerId) -> bool {
+        let cancelled = self.critical(|timers| timers.cancel(id));
+        self.program();
+        return cancelled;
+    }
+
+    /// Runs the callbacks of the timers whose deadline passed, earliest
+    /// first, then programs the next deadline. Callbacks may schedule new
+    /// timers. Returns the number of callbacks that ran.
+    pub fn run_expired(&self) -> usize {
+        let mut ran = 0;
+        loop {
+            let now = timer::current_time();
+            let callback = match self.critical(|timers| timers.expired(now)) {
+                Some(callback) => callback,
+                None => break,
+            };
+
+            callback(now);
+            ran += 1;
+        }
+
+        self.program();
+        return ran;
+    }
+
+    /// Sets the timer 3 match to the earliest deadline, or clears it if
+    /// there are no timers.
+    fn program(&self) {
+        match self.critical(|timers| timers.next_deadline()) {
+            Some(deadline) => {
+                let earliest = timer::current_time() + MIN_DELAY;
+                timer::alarm_at(core::cmp::max(deadline, earliest));
+            }
+            None => timer::clear_alarm(),
+        }
+    }
+}
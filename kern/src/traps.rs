@@ -3,16 +3,21 @@ mod syndrome;
 mod syscall;
 
 pub mod irq;
-pub use self::frame::TrapFrame;
+pub use self::frame::{TrapFrame, TRAP_FRAMES};
 
 use pi::interrupt::{Controller, Interrupt};
 
-use self::syndrome::Syndrome;
+use self::syndrome::{Fault, Syndrome};
 use self::syscall::handle_syscall;
 
 use crate::console::{kprintln};
 use crate::shell;
 use crate::IRQ;
+use crate::SCHEDULER;
+use crate::vm::VirtualAddr;
+use kernel_api::OsError;
+
+use aarch64::FAR_EL1;
 
 use alloc::string::String;
 
@@ -49,7 +54,8 @@ pub struct Info {
 pub extern "C" fn handle_exception(info: Info, esr: u32, tf: &mut TrapFrame) {
     match info.kind {
         Kind::Synchronous => {
-            match Syndrome::from(esr) {
+            let syndrome = Syndrome::from(esr);
+            match syndrome {
                 Syndrome::Brk(n) => {
                     kprintln!("Handling brk({})", n);
                     let mut shell = shell::Shell::new(String::from("[debug]> "));
@@ -64,7 +70,10 @@ pub extern "C" fn handle_exception(info: Info, esr: u32, tf: &mut TrapFrame) {
                 Syndrome::WfiWfe => {
                     kprintln!("No more instructions remaining...");
                 }
-                syndrome => unimplemented!("Unimplemented synchronous exception, here is the info...\nInfo: {:?}\nSyndrome: {:?}\nTF: {:?}", info, syndrome, tf)
+                Syndrome::DataAbort { .. } | Syndrome::InstructionAbort { .. } => {
+                    handle_abort(info, syndrome, tf);
+                }
+                _ => unimplemented!("Unimplemented synchronous exception, here is the info...\nInfo: {:?}\nSyndrome: {:?}\nTF: {:?}", info, syndrome, tf)
             }
         },
         Kind::Irq => {
@@ -78,3 +87,53 @@ pub extern "C" fn handle_exception(info: Info, esr: u32, tf: &mut TrapFrame) {
         _ => unimplemented!("Unimplemented exception, here is the info...\nInfo: {:?}", info)
     }
 }
+
+/// Handles a data or instruction abort described by `syndrome`.
+///
+/// A translation fault taken from user space at an address inside one of the
+/// process's regions is resolved by mapping a page there, after which the
+/// faulting instruction is retried, and so is a write that takes a
+/// permission fault on a copy-on-write page or a writable file mapping once
+/// the page is made writable. If no page frame is left for it, the process
+/// holding the most memory is killed and the instruction retried. Any other
+/// user abort kills only the faulting process, with a diagnostic, and
+/// switches `tf` to the next one; an abort in the kernel is fatal.
+fn handle_abort(info: Info, syndrome: Syndrome, tf: &mut TrapFrame) {
+    let far = unsafe { FAR_EL1.get() };
+
+    if info.source != Source::LowerAArch64 {
+        panic!("Kernel abort: {:?} at {:#x} (elr={:#x})", syndrome, far, tf.elr);
+    }
+
+    let va = VirtualAddr::from(far);
+    let handled = match syndrome {
+        Syndrome::DataAbort { kind: Fault::Translation, .. }
+        | Syndrome::InstructionAbort { kind: Fault::Translation, .. } => {
+            SCHEDULER.with_current(tf, |process| process.handle_fault(va))
+        }
+        Syndrome::DataAbort { kind: Fault::Permission, wnr: true, .. } => {
+            SCHEDULER.with_current(tf, |process| process.handle_write_fault(va))
+        }
+        _ => None,
+    };
+    match handled {
+        Some(Ok(true)) => return,
+        Some(Err(OsError::NoMemory)) => {
+            // There is nobody to report the error to: make room and let the
+            // access fault again, unless the faulting process was the one
+            // killed.
+            if let Some(pid) = SCHEDULER.kill_largest() {
+                if pid == tf.tpidr {
+                    SCHEDULER.switch_to(tf);
+                }
+                return;
+            }
+        }
+        _ => {}
+    }
+
+    let pid = SCHEDULER.kill(tf).expect("Expected faulting process");
+    kprintln!("Killed process (pid={}): {:?} at FAR_EL1={:#x}, ELR={:#x}", pid, syndrome, far, tf.elr);
+
+    SCHEDULER.switch_to(tf);
+}
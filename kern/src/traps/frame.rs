@@ -1,5 +1,11 @@
 use core::fmt;
 
+use crate::allocator::SlabCache;
+use crate::FRAMES;
+
+/// The cache holding the saved trap frames of processes.
+pub static TRAP_FRAMES: SlabCache<TrapFrame> = SlabCache::new("trap_frame", &FRAMES);
+
 #[repr(C)]
 #[derive(Default, Copy, Clone, Debug)]
 pub struct TrapFrame {
@@ -17,7 +17,7 @@ impl From<u32> for Fault {
         let bits: u8 = val as u8 & 0b111111;
         match (bits) {
             0b000000..=0b000011 => Fault::AddressSize,
-            0b000010..=0b000111 => Fault::Translation,
+            0b000100..=0b000111 => Fault::Translation,
             0b001001..=0b001011 => Fault::AccessFlag,
             0b001101..=0b001111 => Fault::Permission,
             0b100001 => Fault::Alignment,
@@ -39,7 +39,7 @@ pub enum Syndrome {
     MsrMrsSystem,
     InstructionAbort { kind: Fault, level: u8 },
     PCAlignmentFault,
-    DataAbort { kind: Fault, level: u8 },
+    DataAbort { kind: Fault, level: u8, wnr: bool },
     SpAlignmentFault,
     TrappedFpu,
     SError,
@@ -64,11 +64,14 @@ impl From<u32> for Syndrome {
             0b010010 => Syndrome::Hvc(esr as u16),
             0b010011 => Syndrome::Smc(esr as u16),
             0b011000 => Syndrome::MsrMrsSystem,
-            0b100000 => Syndrome::InstructionAbort { kind: Fault::from(esr), level: 0 },
-            0b100001 => Syndrome::InstructionAbort { kind: Fault::from(esr), level: 1 },
+            0b100000..=0b100001 => Syndrome::InstructionAbort { kind: Fault::from(esr), level: esr as u8 & 0b11 },
             0b100010 => Syndrome::PCAlignmentFault,
-            0b100100 => Syndrome::DataAbort { kind: Fault::from(esr), level: 0 },
-            0b100101 => Syndrome::DataAbort { kind: Fault::from(esr), level: 1 },
+            0b100100..=0b100101 => Syndrome::DataAbort {
+                kind: Fault::from(esr),
+                level: esr as u8 & 0b11,
+                // ISS.WnR: the abort was caused by a write
+                wnr: esr & (1 << 6) != 0,
+            },
             0b100110 => Syndrome::SpAlignmentFault,
             0b101000 => Syndrome::TrappedFpu,
             0b101100 => Syndrome::TrappedFpu,
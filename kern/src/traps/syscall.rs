@@ -1,13 +1,21 @@
 use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::mem;
 use core::time::Duration;
 
 use crate::console::{CONSOLE, kprint, kprintln};
-use crate::process::{Process, State};
+use crate::fs::PiVFatHandle;
+use crate::param::PAGE_SIZE;
+use crate::process::{Event, Process, State};
 use crate::traps::TrapFrame;
-use crate::SCHEDULER;
+use crate::vm::{PagePerm, VirtualAddr};
+use crate::{FILESYSTEM, SCHEDULER, TIMERS};
+use fat32::traits::{Entry, File as FileTrait, FileSystem, Metadata};
+use fat32::vfat::File;
 use kernel_api::*;
 use pi::timer;
-use crate::param::{TICK};
+use shim::io::{self, Read, Seek, SeekFrom, Write};
 
 /// Sleep for `ms` milliseconds.
 ///
@@ -17,22 +25,14 @@ use crate::param::{TICK};
 /// parameter: the approximate true elapsed time from when `sleep` was called to
 /// when `sleep` returned.
 pub fn sys_sleep(ms: u32, tf: &mut TrapFrame) {
-    let start = timer::current_time().as_millis() as u32;
-    let boxed_fn = Box::new(move |p: &mut Process| {
-        let time = timer::current_time().as_millis() as u32;
-        if time - start >= ms {
-            p.context.x_regs[0] = (time - start) as u64;
-            return true;
-        }
-        return false;
-    });
+    let start = timer::current_time();
+    let deadline = start + Duration::from_millis(ms as u64);
+    let event = Event::Timer { start, deadline };
 
     kprintln!("Sleeping process (pid={}) for {}ms", tf.tpidr, ms);
 
-    // Give new process correct time
-    timer::tick_in(TICK);
-
-    SCHEDULER.switch(State::Waiting(boxed_fn), tf);
+    TIMERS.schedule_at(deadline, Box::new(move |now| SCHEDULER.wake(event, now)));
+    SCHEDULER.switch(State::Waiting(event), tf);
 }
 
 /// Returns current time.
@@ -51,11 +51,196 @@ pub fn sys_time(tf: &mut TrapFrame) {
 
 /// Kills current process.
 ///
-/// This system call does not take paramer and does not return any value.
-pub fn sys_exit(tf: &mut TrapFrame) {
-    timer::tick_in(TICK);
+/// This system call takes one parameter: the exit code reported to the
+/// parent by `wait`. It does not return.
+pub fn sys_exit(code: i32, tf: &mut TrapFrame) {
+    SCHEDULER.exit(code, tf);
+}
+
+/// Creates a copy of the current process.
+///
+/// This system call does not take parameter.
+///
+/// In addition to the usual status value, this system call returns one
+/// parameter: the child's process ID in the parent, and zero in the child.
+pub fn sys_fork(tf: &mut TrapFrame) {
+    let result = SCHEDULER
+        .with_current(tf, |process| process.fork(tf))
+        .unwrap_or(Err(OsError::Unknown))
+        .and_then(|child| SCHEDULER.add(child).ok_or(OsError::NoMemory));
+
+    set_result(result, tf);
+}
+
+/// Replaces the program of the current process.
+///
+/// This system call takes four parameters: the address and the length of an
+/// absolute path, and the address and the number of `[address, length]`
+/// pairs describing the arguments. The process keeps its ID, parent and open
+/// files.
+///
+/// It does not return on success; otherwise it returns the usual status value.
+pub fn sys_exec(path: u64, len: u64, argv: u64, argc: u64, tf: &mut TrapFrame) {
+    let args = with_process(tf, |process| {
+        let path = unsafe { user_str(process, path, len)? };
+        let args = unsafe { user_args(process, argv, argc)? };
+        return Ok((String::from(path), args));
+    });
+
+    let result = args.and_then(|(path, args)| Process::load_with_args(&path, &args));
+    let loaded = match result {
+        Ok(loaded) => loaded,
+        Err(e) => return set_result(Err(e), tf),
+    };
+
+    let Process { mut context, vmap, regions, .. } = loaded;
+    context.tpidr = tf.tpidr;
+    let new_tf = *context;
 
-    SCHEDULER.switch(State::Dead, tf);
+    let result = with_process(tf, move |process| {
+        // The old program's file mappings go away; failing to write them back
+        // does not keep the new one from running.
+        let _ = process.sync_mappings();
+        process.vmap = vmap;
+        process.regions = regions;
+        process.context = context;
+        return Ok(());
+    });
+
+    match result {
+        Ok(()) => *tf = new_tf,
+        Err(e) => set_result(Err(e), tf),
+    }
+}
+
+/// Waits for a child process to exit.
+///
+/// This system call takes one parameter: the ID of the child to wait for.
+///
+/// In addition to the usual status value, this system call returns one
+/// parameter: the child's exit code.
+pub fn sys_wait(pid: u64, tf: &mut TrapFrame) {
+    match SCHEDULER.child_status(pid, tf) {
+        Ok(Some(code)) => set_result(Ok(code as u64), tf),
+        Ok(None) => {
+            SCHEDULER.switch(State::Waiting(Event::ChildExit(pid)), tf);
+        }
+        Err(e) => set_result(Err(e), tf),
+    }
+}
+
+/// Moves the end of the heap.
+///
+/// This system call takes one parameter: the new end of the heap (the break),
+/// or zero to leave it unchanged.
+///
+/// In addition to the usual status value, this system call returns one
+/// parameter: the break after the call.
+pub fn sys_brk(addr: u64, tf: &mut TrapFrame) {
+    let result = with_process(tf, |process| {
+        if addr != 0 {
+            process.set_brk(addr as usize)?;
+        }
+        return Ok(process.brk() as u64);
+    });
+
+    set_result(result, tf);
+}
+
+/// Maps anonymous memory.
+///
+/// This system call takes three parameters: the page-aligned address to map
+/// the memory at (or zero to let the kernel choose), the number of bytes to
+/// map and the `PROT_*` protection flags.
+///
+/// In addition to the usual status value, this system call returns one
+/// parameter: the start of the mapping.
+pub fn sys_mmap(addr: u64, len: u64, prot: u64, tf: &mut TrapFrame) {
+    let result = with_process(tf, |process| {
+        let perm = prot_perm(prot)?;
+        return Ok(process.mmap(addr as usize, len as usize, perm)? as u64);
+    });
+
+    set_result(result, tf);
+}
+
+/// Maps the contents of an open file.
+///
+/// This system call takes five parameters: the page-aligned address to map
+/// the file at (or zero to let the kernel choose), the number of bytes to
+/// map, the `PROT_*` protection flags, the file descriptor and the
+/// page-aligned offset in the file to start at.
+///
+/// In addition to the usual status value, this system call returns one
+/// parameter: the start of the mapping.
+pub fn sys_mmap_file(addr: u64, len: u64, prot: u64, fd: u64, offset: u64, tf: &mut TrapFrame) {
+    let result = with_process(tf, |process| {
+        let perm = prot_perm(prot)?;
+        return Ok(process.mmap_file(addr as usize, len as usize, perm, fd, offset)? as u64);
+    });
+
+    set_result(result, tf);
+}
+
+/// Writes modified pages of file mappings back to their files.
+///
+/// This system call takes two parameters: the page-aligned start of the
+/// memory and its length in bytes.
+///
+/// It only returns the usual status value.
+pub fn sys_msync(addr: u64, len: u64, tf: &mut TrapFrame) {
+    let result = with_process(tf, |process| {
+        process.msync(addr as usize, len as usize)?;
+        return Ok(0);
+    });
+
+    set_result(result, tf);
+}
+
+/// Unmaps memory mapped with `mmap`.
+///
+/// This system call takes two parameters: the page-aligned start of the
+/// memory to unmap and its length in bytes.
+///
+/// It only returns the usual status value.
+pub fn sys_munmap(addr: u64, len: u64, tf: &mut TrapFrame) {
+    let result = with_process(tf, |process| {
+        process.munmap(addr as usize, len as usize)?;
+        return Ok(0);
+    });
+
+    set_result(result, tf);
+}
+
+/// Changes the protection of memory mapped with `mmap`.
+///
+/// This system call takes three parameters: the page-aligned start of the
+/// memory, its length in bytes and the new `PROT_*` protection flags.
+///
+/// It only returns the usual status value.
+pub fn sys_mprotect(addr: u64, len: u64, prot: u64, tf: &mut TrapFrame) {
+    let result = with_process(tf, |process| {
+        let perm = prot_perm(prot)?;
+        process.mprotect(addr as usize, len as usize, perm)?;
+        return Ok(0);
+    });
+
+    set_result(result, tf);
+}
+
+/// Maps `PROT_*` flags to a page permission. Every page is readable, so
+/// `PROT_READ` is required.
+fn prot_perm(prot: u64) -> OsResult<PagePerm> {
+    if prot & PROT_READ == 0 || prot & !(PROT_READ | PROT_WRITE | PROT_EXEC) != 0 {
+        return Err(OsError::InvalidArgument);
+    }
+
+    match (prot & PROT_WRITE != 0, prot & PROT_EXEC != 0) {
+        (true, true) => Ok(PagePerm::RWX),
+        (true, false) => Ok(PagePerm::RW),
+        (false, true) => Ok(PagePerm::RX),
+        (false, false) => Ok(PagePerm::RO),
+    }
 }
 
 /// Write to console.
@@ -77,14 +262,294 @@ pub fn sys_getpid(tf: &mut TrapFrame) {
     tf.x_regs[0] = tf.tpidr as u64;
 }
 
+/// Returns the scheduling priority of a process.
+///
+/// This system call takes one parameter: the ID of the process, or 0 for the
+/// current process.
+///
+/// In addition to the usual status value, this system call returns one
+/// parameter: the priority, from `PRIORITY_HIGHEST` to `PRIORITY_LOWEST`.
+pub fn sys_getpriority(pid: u64, tf: &mut TrapFrame) {
+    let result = SCHEDULER.priority(pid, tf).map(|priority| priority as u64);
+    set_result(result, tf);
+}
+
+/// Sets the scheduling priority of the current process or one of its
+/// children. The priority can be lowered, but raised no higher than the
+/// priority of the process's parent.
+///
+/// This system call takes two parameters: the ID of the process, or 0 for
+/// the current process, and the priority, from `PRIORITY_HIGHEST` to
+/// `PRIORITY_LOWEST`.
+///
+/// It only returns the usual status value.
+pub fn sys_setpriority(pid: u64, priority: u64, tf: &mut TrapFrame) {
+    let result = SCHEDULER.set_priority(pid, priority as usize, tf);
+    set_result(result.map(|_| 0), tf);
+}
+
+/// Makes the current process a real-time process.
+///
+/// This system call takes two parameters: the period and the budget, the CPU
+/// time reserved in every period, both in microseconds. A period of 0 makes
+/// the process a normal process again.
+///
+/// It only returns the usual status value.
+pub fn sys_set_realtime(period: u64, budget: u64, tf: &mut TrapFrame) {
+    let reservation = match period {
+        0 => None,
+        _ => Some((Duration::from_micros(period), Duration::from_micros(budget))),
+    };
+
+    let result = SCHEDULER.set_realtime(reservation, tf);
+    set_result(result.map(|_| 0), tf);
+}
+
+/// Opens a file.
+///
+/// This system call takes three parameters: the address and the length of an
+/// absolute path, and a bitwise OR of the `OPEN_*` flags. With `OPEN_CREATE`,
+/// a missing file is created; with `OPEN_TRUNCATE`, the file is truncated to
+/// zero length.
+///
+/// In addition to the usual status value, this system call returns one
+/// parameter: the new file descriptor.
+pub fn sys_open(path: u64, len: u64, flags: u64, tf: &mut TrapFrame) {
+    let result = with_process(tf, |process| {
+        let path = unsafe { user_str(process, path, len)? };
+        let file = open_file(path, flags)?;
+        return process.add_file(file);
+    });
+
+    set_result(result, tf);
+}
+
+/// Closes a file descriptor.
+///
+/// This system call takes one parameter: the file descriptor to close.
+///
+/// It only returns the usual status value.
+pub fn sys_close(fd: u64, tf: &mut TrapFrame) {
+    let result = with_process(tf, |process| {
+        process.remove_file(fd)?;
+        return Ok(0);
+    });
+
+    set_result(result, tf);
+}
+
+/// Reads from a file.
+///
+/// This system call takes three parameters: the file descriptor, and the
+/// address and the length of the buffer to read into.
+///
+/// In addition to the usual status value, this system call returns one
+/// parameter: the number of bytes read, which is zero at the end of the file.
+pub fn sys_read(fd: u64, buf: u64, len: u64, tf: &mut TrapFrame) {
+    let result = with_process(tf, |process| {
+        let buf = unsafe { user_slice_mut(process, buf, len)? };
+        let read = process.file(fd)?.lock().read(buf)?;
+        return Ok(read as u64);
+    });
+
+    set_result(result, tf);
+}
+
+/// Writes to a file.
+///
+/// This system call takes three parameters: the file descriptor, and the
+/// address and the length of the buffer to write.
+///
+/// In addition to the usual status value, this system call returns one
+/// parameter: the number of bytes written.
+pub fn sys_write_fd(fd: u64, buf: u64, len: u64, tf: &mut TrapFrame) {
+    let result = with_process(tf, |process| {
+        let buf = unsafe { user_slice(process, buf, len)? };
+        let written = process.file(fd)?.lock().write(buf)?;
+        return Ok(written as u64);
+    });
+
+    set_result(result, tf);
+}
+
+/// Moves the offset of a file.
+///
+/// This system call takes three parameters: the file descriptor, one of the
+/// `SEEK_*` values, and a signed offset relative to that position.
+///
+/// In addition to the usual status value, this system call returns one
+/// parameter: the new offset from the start of the file.
+pub fn sys_seek(fd: u64, whence: u64, offset: i64, tf: &mut TrapFrame) {
+    let result = with_process(tf, |process| {
+        let pos = match whence {
+            SEEK_START if offset >= 0 => SeekFrom::Start(offset as u64),
+            SEEK_CURRENT => SeekFrom::Current(offset),
+            SEEK_END => SeekFrom::End(offset),
+            _ => return Err(OsError::InvalidArgument),
+        };
+
+        return Ok(process.file(fd)?.lock().seek(pos)?);
+    });
+
+    set_result(result, tf);
+}
+
+/// Returns information about a file or directory.
+///
+/// This system call takes three parameters: the address and the length of an
+/// absolute path, and the address of a `Stat` to fill in.
+///
+/// It only returns the usual status value.
+pub fn sys_stat(path: u64, len: u64, stat: u64, tf: &mut TrapFrame) {
+    let result = with_process(tf, |process| {
+        let path = unsafe { user_str(process, path, len)? };
+        if stat % mem::align_of::<Stat>() as u64 != 0 {
+            return Err(OsError::BadAddress);
+        }
+        let out = unsafe { user_slice_mut(process, stat, mem::size_of::<Stat>() as u64)? };
+
+        let entry = FILESYSTEM.open(path)?;
+        let info = Stat {
+            size: entry.as_file().map(|file| file.size()).unwrap_or(0),
+            directory: entry.is_dir(),
+            read_only: entry.metadata().read_only(),
+        };
+
+        unsafe {
+            *(out.as_mut_ptr() as *mut Stat) = info;
+        }
+        return Ok(0);
+    });
+
+    set_result(result, tf);
+}
+
+/// Opens the file at `path` according to the `OPEN_*` bits in `flags`.
+fn open_file(path: &str, flags: u64) -> OsResult<File<PiVFatHandle>> {
+    let mut file = match FILESYSTEM.open(path) {
+        Ok(entry) => entry.into_file().ok_or(OsError::InvalidArgument)?,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound && flags & OPEN_CREATE != 0 => {
+            FILESYSTEM.create_file(path)?
+        }
+        Err(e) => return Err(OsError::from(e)),
+    };
+
+    if flags & OPEN_TRUNCATE != 0 {
+        file.set_len(0)?;
+    }
+
+    return Ok(file);
+}
+
+/// Runs `f` on the process that issued the system call in `tf`.
+fn with_process<F, R>(tf: &TrapFrame, f: F) -> OsResult<R>
+where
+    F: FnOnce(&mut Process) -> OsResult<R>,
+{
+    return SCHEDULER.with_current(tf, f).unwrap_or(Err(OsError::Unknown));
+}
+
+/// Returns the `len` bytes of user memory at `va` after checking that they
+/// are mapped in the process's page table, mapping pages of its regions that
+/// have not been touched yet. Returns `NoMemory` if no page frame is left
+/// for one of them.
+///
+/// The caller must ensure `process` is the one whose page table is currently
+/// installed in `TTBR1`, which holds while handling its system call.
+unsafe fn user_slice<'a>(process: &mut Process, va: u64, len: u64) -> OsResult<&'a [u8]> {
+    process.populate(VirtualAddr::from(va), len as usize, false)?;
+    process.vmap.check_range(VirtualAddr::from(va), len as usize, false)?;
+    return Ok(core::slice::from_raw_parts(va as *const u8, len as usize));
+}
+
+/// Like `user_slice()`, but the memory must also be writable by the user.
+unsafe fn user_slice_mut<'a>(process: &mut Process, va: u64, len: u64) -> OsResult<&'a mut [u8]> {
+    process.populate(VirtualAddr::from(va), len as usize, true)?;
+    process.vmap.check_range(VirtualAddr::from(va), len as usize, true)?;
+    return Ok(core::slice::from_raw_parts_mut(va as *mut u8, len as usize));
+}
+
+/// Like `user_slice()`, but the memory must also hold valid UTF-8.
+unsafe fn user_str<'a>(process: &mut Process, va: u64, len: u64) -> OsResult<&'a str> {
+    let bytes = user_slice(process, va, len)?;
+    return core::str::from_utf8(bytes).map_err(|_| OsError::InvalidArgument);
+}
+
+/// Copies the `argc` strings described by the `[address, length]` pairs at
+/// `argv` in user memory into the kernel.
+///
+/// Returns `InvalidArgument`, before copying anything, if the strings take
+/// more than the half of a stack page `Process::load_with_args()` accepts.
+/// The same requirement as for `user_slice()` applies.
+unsafe fn user_args(process: &mut Process, argv: u64, argc: u64) -> OsResult<Vec<String>> {
+    if argc > MAX_ARGS as u64 || argv % 8 != 0 {
+        return Err(OsError::InvalidArgument);
+    }
+
+    let pairs = user_slice(process, argv, argc * 16)?;
+    let total = pairs.chunks(16).fold(0u64, |total, pair| {
+        total.saturating_add(*(pair.as_ptr().add(8) as *const u64))
+    });
+    if total > (PAGE_SIZE / 2) as u64 {
+        return Err(OsError::InvalidArgument);
+    }
+
+    let mut args = Vec::with_capacity(argc as usize);
+    for pair in pairs.chunks(16) {
+        let address = *(pair.as_ptr() as *const u64);
+        let len = *(pair.as_ptr().add(8) as *const u64);
+        args.push(String::from(user_str(process, address, len)?));
+    }
+
+    return Ok(args);
+}
+
+/// Stores the result of a system call in `tf`: on success, the returned value
+/// in `x0` and `OsError::Ok` in `x7`; on failure, only the error in `x7`.
+fn set_result(result: OsResult<u64>, tf: &mut TrapFrame) {
+    match result {
+        Ok(value) => {
+            tf.x_regs[0] = value;
+            tf.x_regs[7] = OsError::Ok as u64;
+        }
+        Err(e) => {
+            tf.x_regs[7] = e as u64;
+        }
+    }
+}
+
 pub fn handle_syscall(num: u16, tf: &mut TrapFrame) {
     use crate::console::kprintln;
-    match num {
-        1 => sys_sleep(tf.x_regs[0] as u32, tf),
-        2 => sys_time(tf),
-        3 => sys_exit(tf),
-        4 => sys_write(tf.x_regs[0] as u8, tf),
-        5 => sys_getpid(tf),
-        _ => unimplemented!("Unimplemented syscall"),
+
+    // Report success unless the system call says otherwise.
+    tf.x_regs[7] = OsError::Ok as u64;
+
+    let x = tf.x_regs;
+    match num as usize {
+        NR_SLEEP => sys_sleep(x[0] as u32, tf),
+        NR_TIME => sys_time(tf),
+        NR_EXIT => sys_exit(x[0] as i32, tf),
+        NR_WRITE => sys_write(x[0] as u8, tf),
+        NR_GETPID => sys_getpid(tf),
+        NR_OPEN => sys_open(x[0], x[1], x[2], tf),
+        NR_CLOSE => sys_close(x[0], tf),
+        NR_READ => sys_read(x[0], x[1], x[2], tf),
+        NR_WRITE_FD => sys_write_fd(x[0], x[1], x[2], tf),
+        NR_SEEK => sys_seek(x[0], x[1], x[2] as i64, tf),
+        NR_STAT => sys_stat(x[0], x[1], x[2], tf),
+        NR_FORK => sys_fork(tf),
+        NR_EXEC => sys_exec(x[0], x[1], x[2], x[3], tf),
+        NR_WAIT => sys_wait(x[0], tf),
+        NR_BRK => sys_brk(x[0], tf),
+        NR_MMAP => sys_mmap(x[0], x[1], x[2], tf),
+        NR_MUNMAP => sys_munmap(x[0], x[1], tf),
+        NR_MPROTECT => sys_mprotect(x[0], x[1], x[2], tf),
+        NR_MMAP_FILE => sys_mmap_file(x[0], x[1], x[2], x[3], x[4], tf),
+        NR_MSYNC => sys_msync(x[0], x[1], tf),
+        NR_GETPRIORITY => sys_getpriority(x[0], tf),
+        NR_SETPRIORITY => sys_setpriority(x[0], x[1], tf),
+        NR_SET_REALTIME => sys_set_realtime(x[0], x[1], tf),
+        // Unknown system calls fail instead of bringing down the kernel
+        _ => set_result(Err(OsError::InvalidArgument), tf),
     }
 }
@@ -5,9 +5,11 @@ use aarch64::*;
 
 mod address;
 mod pagetable;
+mod region;
 
 pub use self::address::{PhysicalAddr, VirtualAddr};
 pub use self::pagetable::*;
+pub use self::region::{Backing, Region, RegionKind};
 use crate::param::{KERNEL_MASK_BITS, USER_MASK_BITS};
 
 /// Thread-safe (locking) wrapper around a kernel page table.
@@ -1,18 +1,20 @@
+use core::alloc::Layout;
 use core::iter::Chain;
 use core::ops::{Deref, DerefMut};
 use core::slice::Iter;
 
 use alloc::boxed::Box;
 use alloc::fmt;
-use core::alloc::{GlobalAlloc, Layout};
 
 use crate::allocator;
 use crate::param::*;
 use crate::vm::{PhysicalAddr, VirtualAddr};
+use crate::FRAMES;
 use crate::ALLOCATOR;
 use crate::console::kprintln;
 
 use aarch64::vmsa::*;
+use kernel_api::{OsError, OsResult};
 use shim::const_assert_size;
 
 #[repr(C)]
@@ -22,10 +24,6 @@ const_assert_size!(Page, PAGE_SIZE);
 impl Page {
     pub const SIZE: usize = PAGE_SIZE;
     pub const ALIGN: usize = PAGE_SIZE;
-
-    fn layout() -> Layout {
-        unsafe { Layout::from_size_align_unchecked(Self::SIZE, Self::ALIGN) }
-    }
 }
 
 #[repr(C)]
@@ -50,6 +48,10 @@ impl L2PageTable {
     }
 }
 
+/// Bit of the software-defined `SW` field of an L3 entry marking a page that
+/// is writable but mapped read-only because its frame is shared.
+const SW_COW: u64 = 0b0001;
+
 #[derive(Copy, Clone)]
 pub struct L3Entry(RawL3Entry);
 
@@ -72,9 +74,21 @@ impl L3Entry {
         }
     
         return Some(PhysicalAddr::from(
-            self.0.get_value(RawL3Entry::ADDR)
+            self.0.get_masked(RawL3Entry::ADDR)
         ));
     }
+
+    /// Returns `true` if the L3Entry is valid and maps a page that is only
+    /// read-only because its frame is shared copy-on-write.
+    fn is_cow(&self) -> bool {
+        return self.is_valid() && self.0.get_value(RawL3Entry::SW) & SW_COW != 0;
+    }
+
+    /// Returns `true` if the L3Entry is valid and its `AP` field grants read
+    /// and write access to EL0. Otherwise, return `false`.
+    fn is_user_writable(&self) -> bool {
+        return self.is_valid() && self.0.get_value(RawL3Entry::AP) == EntryPerm::USER_RW;
+    }
 }
 
 #[repr(C)]
@@ -107,13 +121,22 @@ pub struct PageTable {
 }
 
 impl PageTable {
-    /// Returns a new `Box` containing `PageTable`.
+    /// Returns a new `Box` containing `PageTable`, or `None` if the kernel
+    /// heap has no room for it.
     /// Entries in L2PageTable should be initialized properly before return.
-    fn new(perm: u64) -> Box<PageTable> {
-        let mut pt = Box::new(PageTable {
-            l2: L2PageTable::new(),
-            l3: [L3PageTable::new(), L3PageTable::new()],
-        });
+    fn new(perm: u64) -> Option<Box<PageTable>> {
+        let mut pt = unsafe {
+            let raw = ALLOCATOR.try_alloc(Layout::new::<PageTable>()) as *mut PageTable;
+            if raw.is_null() {
+                return None;
+            }
+
+            raw.write(PageTable {
+                l2: L2PageTable::new(),
+                l3: [L3PageTable::new(), L3PageTable::new()],
+            });
+            Box::from_raw(raw)
+        };
         
         // Initialize L2PageTable entries "properly"?
         for i in 0..2 {
@@ -127,7 +150,7 @@ impl PageTable {
             pt.l2.entries[i].set_masked(pt.l3[i].as_ptr().as_u64(), RawL2Entry::ADDR);
         }
 
-        return pt;
+        return Some(pt);
     }
 
     /// Returns the (L2index, L3index) extracted from the given virtual address.
@@ -167,6 +190,13 @@ impl PageTable {
         return !self.is_valid(va);
     }
 
+    /// Returns the L3Entry indicated by the given virtual address.
+    fn get_entry(&self, va: VirtualAddr) -> L3Entry {
+        let (l2_i, l3_i) = PageTable::locate(va);
+
+        return self.l3[l2_i].entries[l3_i];
+    }
+
     /// Set the given RawL3Entry `entry` to the L3Entry indicated by the given virtual
     /// address.
     pub fn set_entry(&mut self, va: VirtualAddr, entry: RawL3Entry) -> &mut Self {
@@ -201,40 +231,27 @@ impl KernPageTable {
     /// Returns a new `KernPageTable`. `KernPageTable` should have a `Pagetable`
     /// created with `KERN_RW` permission.
     ///
-    /// Set L3entry of ARM physical address starting at 0x00000000 for RAM and
-    /// physical address range from `IO_BASE` to `IO_BASE_END` for peripherals.
+    /// Set L3entry of ARM physical address for every region of RAM in the
+    /// memory map, usable or reserved, and physical address range from
+    /// `IO_BASE` to `IO_BASE_END` for peripherals.
     /// Each L3 entry should have correct value for lower attributes[10:0] as well
     /// as address[47:16]. Refer to the definition of `RawL3Entry` in `vmsa.rs` for
     /// more details.
     pub fn new() -> KernPageTable {
-        let mut pt = PageTable::new(0b00);
-
-        let starting_address = 0;
-
-        let (_, ending_address) = allocator::memory_map().expect("Expected start and end address");
-
-        let mut curr_address = starting_address;
+        let mut pt = PageTable::new(0b00).expect("failed to allocate the kernel page table");
+
+        let map = allocator::memory_map().expect("Expected a memory map");
+        for region in map.regions() {
+            let mut curr_address = region.start & PAGE_MASK;
+            while curr_address < region.end {
+                pt.set_entry(VirtualAddr::from(curr_address), kern_entry(curr_address, 0b000, 0b11));
+                curr_address += PAGE_SIZE;
+            }
+        }
 
+        let mut curr_address = IO_BASE;
         while curr_address <= IO_BASE_END - PAGE_SIZE {
-            if curr_address <= ending_address - PAGE_SIZE || curr_address >= IO_BASE {
-                let mut entry = RawL3Entry::new(0);
-                entry.set_value(0b1, RawL3Entry::VALID);
-                entry.set_value(0b1, RawL3Entry::TYPE);
-
-                if curr_address <= ending_address - PAGE_SIZE {
-                    entry.set_value(0b000, RawL3Entry::ATTR);
-                    entry.set_value(0b11, RawL3Entry::SH);
-                } else if curr_address >= IO_BASE {
-                    entry.set_value(0b001, RawL3Entry::ATTR);
-                    entry.set_value(0b10, RawL3Entry::SH);
-                }
-
-                entry.set_value(0b00, RawL3Entry::AP);
-                entry.set_value(0b1, RawL3Entry::AF);
-                entry.set_masked(curr_address as u64, RawL3Entry::ADDR);
-
-                pt.set_entry(VirtualAddr::from(curr_address), entry);
-            }
+            pt.set_entry(VirtualAddr::from(curr_address), kern_entry(curr_address, 0b001, 0b10));
             curr_address += PAGE_SIZE;
         }
 
@@ -242,51 +259,111 @@ impl KernPageTable {
     }
 }
 
+/// Returns a valid kernel L3 entry identity mapping the page at `address`
+/// with memory attribute index `attr` and shareability `sh`.
+fn kern_entry(address: usize, attr: u64, sh: u64) -> RawL3Entry {
+    let mut entry = RawL3Entry::new(0);
+    entry.set_value(0b1, RawL3Entry::VALID);
+    entry.set_value(0b1, RawL3Entry::TYPE);
+    entry.set_value(attr, RawL3Entry::ATTR);
+    entry.set_value(sh, RawL3Entry::SH);
+    entry.set_value(0b00, RawL3Entry::AP);
+    entry.set_value(0b1, RawL3Entry::AF);
+    entry.set_masked(address as u64, RawL3Entry::ADDR);
+
+    return entry;
+}
+
+/// Access permission of a user page. Every user page is readable; none is
+/// executable by the kernel.
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum PagePerm {
     RW,
     RO,
+    RX,
     RWX,
 }
 
+impl PagePerm {
+    /// Returns `true` if the permission allows writes.
+    pub fn is_writable(&self) -> bool {
+        return *self == PagePerm::RW || *self == PagePerm::RWX;
+    }
+
+    /// Returns this permission without write access.
+    pub fn read_only(&self) -> PagePerm {
+        match *self {
+            PagePerm::RW | PagePerm::RO => PagePerm::RO,
+            PagePerm::RX | PagePerm::RWX => PagePerm::RX,
+        }
+    }
+
+    /// Sets the `AP`, `UXN` and `PXN` fields of `entry` to grant this
+    /// permission to EL0.
+    fn set_bits(&self, entry: &mut RawL3Entry) {
+        let (ap, uxn) = match *self {
+            PagePerm::RW => (EntryPerm::USER_RW, 1),
+            PagePerm::RO => (EntryPerm::USER_RO, 1),
+            PagePerm::RX => (EntryPerm::USER_RO, 0),
+            PagePerm::RWX => (EntryPerm::USER_RW, 0),
+        };
+
+        entry.set_value(ap, RawL3Entry::AP);
+        entry.set_value(uxn, RawL3Entry::UXN);
+        entry.set_value(0b1, RawL3Entry::PXN);
+    }
+}
+
+/// Sets the permission bits of the valid user page entry `entry` to `perm`.
+/// A writable page whose frame is shared is mapped read-only and marked
+/// copy-on-write instead.
+fn set_user_perm(entry: &mut RawL3Entry, perm: PagePerm) {
+    let frame = PhysicalAddr::from(entry.get_masked(RawL3Entry::ADDR));
+    let cow = perm.is_writable() && FRAMES.count(frame) > 1;
+
+    if cow {
+        perm.read_only().set_bits(entry);
+        entry.set_value(entry.get_value(RawL3Entry::SW) | SW_COW, RawL3Entry::SW);
+    } else {
+        perm.set_bits(entry);
+        entry.set_value(entry.get_value(RawL3Entry::SW) & !SW_COW, RawL3Entry::SW);
+    }
+}
+
 pub struct UserPageTable(Box<PageTable>);
 
 impl UserPageTable {
     /// Returns a new `UserPageTable` containing a `PageTable` created with
     /// `USER_RW` permission.
-    pub fn new() -> UserPageTable {
-        let pt = PageTable::new(0b01);
+    ///
+    /// Returns `NoMemory` if the kernel heap has no room for the table.
+    pub fn new() -> OsResult<UserPageTable> {
+        let pt = PageTable::new(0b01).ok_or(OsError::NoMemory)?;
 
-        return UserPageTable(pt);
+        return Ok(UserPageTable(pt));
     }
 
-    /// Allocates a page and set an L3 entry translates given virtual address to the
-    /// physical address of the allocated page. Returns the allocated page.
+    /// Allocates a zero-filled page and set an L3 entry translates given virtual
+    /// address to the physical address of the allocated page. Returns the
+    /// allocated page.
     ///
     /// # Panics
     /// Panics if the virtual address is lower than `USER_IMG_BASE`.
     /// Panics if the virtual address has already been allocated.
-    /// Panics if allocator fails to allocate a page.
     ///
-    /// TODO. use Result<T> and make it failurable
-    /// TODO. use perm properly
-    pub fn alloc(&mut self, va: VirtualAddr, _perm: PagePerm) -> &mut [u8] {
+    /// The page is mapped with the `AP`, `UXN` and `PXN` bits matching `perm`.
+    ///
+    /// Returns `NoMemory` if no page frame is left.
+    pub fn alloc(&mut self, va: VirtualAddr, perm: PagePerm) -> OsResult<&mut [u8]> {
         let va_val = va.as_usize();
 
         if va_val < USER_IMG_BASE {
             panic!("Cannot access that memory as a user!");
         }
 
-        let mut page;
+        let mut page = FRAMES.alloc().ok_or(OsError::NoMemory)?;
 
-        unsafe {
-            page = ALLOCATOR.alloc(Page::layout());
-        }
-
-        if page == core::ptr::null_mut() {
-            panic!("Allocating the page table failed!");
-        }
-
-        let page_address = page as u64;
+        let page_address = page.as_u64();
 
         let mut entry = RawL3Entry::new(0);
 
@@ -294,16 +371,259 @@ impl UserPageTable {
         entry.set_value(0b1, RawL3Entry::VALID);
         entry.set_value(0b1, RawL3Entry::TYPE);
         entry.set_value(0b000, RawL3Entry::ATTR);
-        entry.set_value(0b01, RawL3Entry::AP);
         entry.set_value(0b11, RawL3Entry::SH);
         entry.set_value(0b1, RawL3Entry::AF);
         entry.set_masked(page_address, RawL3Entry::ADDR);
+        perm.set_bits(&mut entry);
 
         // Set entry in page table
-        self.set_entry(VirtualAddr::from(va_val - USER_IMG_BASE), entry);
+        self.update_entry(va_val, entry);
+
+        unsafe {
+            return Ok(core::slice::from_raw_parts_mut(page.as_mut_ptr(), PAGE_SIZE));
+        }
+    }
+
+    /// Sets the entry of the page at the page-aligned user virtual address
+    /// `va` to `entry`. If a page was mapped there, its TLB entries are
+    /// invalidated, as this table may be the one in use: the kernel changes
+    /// it on behalf of the running process, such as when a system call
+    /// copies a page on write.
+    fn update_entry(&mut self, va: usize, entry: RawL3Entry) {
+        let offset = VirtualAddr::from(va - USER_IMG_BASE);
+        let mapped = self.get_entry(offset).is_valid();
+        self.set_entry(offset, entry);
+
+        if mapped {
+            aarch64::tlb_invalidate_va(va as u64);
+        }
+    }
+
+    /// Unmaps the page at the page-aligned user virtual address `va` and
+    /// releases its frame, which is freed unless another table shares it.
+    /// Does nothing if no page is mapped there.
+    pub fn dealloc(&mut self, va: VirtualAddr) {
+        let va_val = va.as_usize();
+        if va_val < USER_IMG_BASE {
+            return;
+        }
+
+        let offset = VirtualAddr::from(va_val - USER_IMG_BASE);
+        if let Some(frame) = self.get_entry(offset).get_page_addr() {
+            self.update_entry(va_val, RawL3Entry::new(0));
+            FRAMES.release(frame);
+        }
+    }
+
+    /// Changes the permission of the page mapped at the page-aligned user
+    /// virtual address `va`. A shared page made writable stays read-only
+    /// until copied on write. Does nothing if no page is mapped there.
+    pub fn set_perm(&mut self, va: VirtualAddr, perm: PagePerm) {
+        let va_val = va.as_usize();
+        if va_val < USER_IMG_BASE {
+            return;
+        }
+
+        let offset = VirtualAddr::from(va_val - USER_IMG_BASE);
+        let entry = self.get_entry(offset);
+        if entry.is_valid() {
+            let mut raw = entry.0;
+            set_user_perm(&mut raw, perm);
+            self.update_entry(va_val, raw);
+        }
+    }
+
+    /// Returns `true` if the page mapped at the page-aligned user virtual
+    /// address `va` is writable by the user, possibly after being copied on
+    /// write.
+    pub fn is_writable(&self, va: VirtualAddr) -> bool {
+        let va_val = va.as_usize();
+        if va_val < USER_IMG_BASE {
+            return false;
+        }
+
+        let entry = self.get_entry(VirtualAddr::from(va_val - USER_IMG_BASE));
+        return entry.is_user_writable() || entry.is_cow();
+    }
+
+    /// Returns `true` if a page is mapped at the page-aligned user virtual
+    /// address `va`.
+    pub fn is_mapped(&self, va: VirtualAddr) -> bool {
+        let va_val = va.as_usize();
+        if va_val < USER_IMG_BASE {
+            return false;
+        }
+
+        return self.is_valid(VirtualAddr::from(va_val - USER_IMG_BASE));
+    }
+
+    /// Returns the number of pages mapped in the table.
+    pub fn mapped_pages(&self) -> usize {
+        return (&**self).into_iter().filter(|entry| entry.is_valid()).count();
+    }
+
+    /// Returns the page mapped at the page-aligned user virtual address `va`,
+    /// or `None` if no page is mapped there.
+    pub fn get_page(&mut self, va: VirtualAddr) -> Option<&mut [u8]> {
+        let va_val = va.as_usize();
+        if va_val < USER_IMG_BASE {
+            return None;
+        }
+
+        let mut addr = self.get_entry(VirtualAddr::from(va_val - USER_IMG_BASE)).get_page_addr()?;
 
         unsafe {
-            return core::slice::from_raw_parts_mut(page, PAGE_SIZE);
+            return Some(core::slice::from_raw_parts_mut(addr.as_mut_ptr(), PAGE_SIZE));
+        }
+    }
+
+    /// Returns the frame mapped at the page-aligned user virtual address
+    /// `va`, or `None` if no page is mapped there.
+    pub fn frame(&self, va: VirtualAddr) -> Option<PhysicalAddr> {
+        let va_val = va.as_usize();
+        if va_val < USER_IMG_BASE {
+            return None;
+        }
+
+        return self.get_entry(VirtualAddr::from(va_val - USER_IMG_BASE)).get_page_addr();
+    }
+
+    /// Maps `frame`, taking a reference to it, at the page-aligned user
+    /// virtual address `va` in place of the page mapped there, which is
+    /// released. The permission of the page is kept.
+    ///
+    /// # Panics
+    ///
+    /// Panics if no page is mapped at `va`.
+    pub fn share_frame(&mut self, va: VirtualAddr, frame: PhysicalAddr) {
+        let offset = VirtualAddr::from(va.as_usize() - USER_IMG_BASE);
+        let entry = self.get_entry(offset);
+        let old = entry.get_page_addr().expect("page is mapped");
+
+        FRAMES.share(frame);
+        let mut raw = entry.0;
+        raw.set_masked(frame.as_u64(), RawL3Entry::ADDR);
+        self.update_entry(va.as_usize(), raw);
+        FRAMES.release(old);
+    }
+
+    /// Returns a new `UserPageTable` that maps the same virtual addresses as
+    /// this one to the same frames. Writable pages become read-only in both
+    /// tables and are copied by `copy_on_write()` when either side first
+    /// writes to them.
+    ///
+    /// Returns `NoMemory` if the kernel heap has no room for the new table.
+    pub fn clone_cow(&mut self) -> OsResult<UserPageTable> {
+        let mut table = UserPageTable::new()?;
+
+        for l2_i in 0..self.l3.len() {
+            for l3_i in 0..self.l3[l2_i].entries.len() {
+                let entry = &mut self.l3[l2_i].entries[l3_i];
+                let frame = match entry.get_page_addr() {
+                    Some(frame) => frame,
+                    None => continue,
+                };
+
+                FRAMES.share(frame);
+                if entry.is_user_writable() {
+                    let perm = match entry.0.get_value(RawL3Entry::UXN) {
+                        0 => PagePerm::RWX,
+                        _ => PagePerm::RW,
+                    };
+
+                    let mut raw = entry.0;
+                    set_user_perm(&mut raw, perm);
+                    *entry = L3Entry(raw);
+
+                    // This table may be the one in use
+                    let va = USER_IMG_BASE + (l2_i << 29 | l3_i << 16);
+                    aarch64::tlb_invalidate_va(va as u64);
+                }
+
+                table.l3[l2_i].entries[l3_i] = *entry;
+            }
+        }
+
+        return Ok(table);
+    }
+
+    /// Resolves a write to the copy-on-write page at the user virtual
+    /// address `va`: the page gets a private copy of its frame, unless no
+    /// other table shares the frame anymore, and becomes writable again.
+    ///
+    /// Returns `Ok(true)` if the write can be retried, `Ok(false)` if `va`
+    /// is not in a copy-on-write page, and `NoMemory` if the copy could not
+    /// be allocated.
+    pub fn copy_on_write(&mut self, va: VirtualAddr) -> OsResult<bool> {
+        let va_val = va.as_usize();
+        if va_val < USER_IMG_BASE {
+            return Ok(false);
+        }
+
+        let offset = VirtualAddr::from((va_val & PAGE_MASK) - USER_IMG_BASE);
+        let entry = self.get_entry(offset);
+        if !entry.is_cow() {
+            return Ok(false);
+        }
+
+        let mut raw = entry.0;
+        let frame = entry.get_page_addr().expect("page is mapped");
+        if FRAMES.count(frame) > 1 {
+            let mut copy = FRAMES.alloc().ok_or(OsError::NoMemory)?;
+            unsafe {
+                core::ptr::copy_nonoverlapping(frame.as_ptr(), copy.as_mut_ptr(), PAGE_SIZE);
+            }
+
+            raw.set_masked(copy.as_u64(), RawL3Entry::ADDR);
+            FRAMES.release(frame);
+        }
+
+        raw.set_value(EntryPerm::USER_RW, RawL3Entry::AP);
+        raw.set_value(raw.get_value(RawL3Entry::SW) & !SW_COW, RawL3Entry::SW);
+        self.update_entry(va_val & PAGE_MASK, raw);
+
+        return Ok(true);
+    }
+
+    /// Checks that the `len` bytes starting at the user virtual address `va`
+    /// lie in pages mapped by this table, so that the kernel can access them
+    /// on behalf of the process. If `write` is `true`, the pages must also be
+    /// writable by the user.
+    ///
+    /// # Errors
+    ///
+    /// Returns `BadAddress` if the range is not entirely inside the user
+    /// address space or touches an unmapped page, and `NoAccess` if `write`
+    /// is `true` and a page is read-only.
+    pub fn check_range(&self, va: VirtualAddr, len: usize, write: bool) -> OsResult<()> {
+        if len == 0 {
+            return Ok(());
+        }
+
+        let start = va.as_usize();
+        if start < USER_IMG_BASE {
+            return Err(OsError::BadAddress);
+        }
+
+        let end = match start.checked_add(len - 1) {
+            Some(end) => end,
+            None => return Err(OsError::BadAddress),
+        };
+
+        let mut page = start & PAGE_MASK;
+        loop {
+            let entry = self.get_entry(VirtualAddr::from(page - USER_IMG_BASE));
+            if !entry.is_valid() {
+                return Err(OsError::BadAddress);
+            }
+            if write && !entry.is_user_writable() {
+                return Err(OsError::NoAccess);
+            }
+
+            if page >= end & PAGE_MASK {
+                return Ok(());
+            }
+            page += PAGE_SIZE;
         }
     }
 }
@@ -340,12 +660,8 @@ impl DerefMut for UserPageTable {
 impl Drop for UserPageTable {
     fn drop(&mut self) {
         for entry in self.into_iter() {
-            if entry.is_valid() {
-                let mut address = entry.get_page_addr().expect("Expected address");
-                let physical_pointer = address.as_mut_ptr();
-                unsafe {
-                    ALLOCATOR.dealloc(physical_pointer, Page::layout());
-                }
+            if let Some(frame) = entry.get_page_addr() {
+                FRAMES.release(frame);
             }
         }
     }
@@ -0,0 +1,72 @@
+use crate::fs::OpenFile;
+use crate::vm::PagePerm;
+
+/// The purpose of a `Region`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum RegionKind {
+    /// The part of the user stack below its first page.
+    Stack,
+    /// The memory between the end of the program image and the break.
+    Heap,
+    /// Zero-filled memory mapped with `mmap`.
+    Anonymous,
+    /// The contents of a file mapped with `mmap`.
+    File,
+}
+
+/// The part of a file a `File` region maps, starting at byte `offset`.
+#[derive(Debug, Clone)]
+pub struct Backing {
+    pub file: OpenFile,
+    pub offset: u64,
+}
+
+/// A range of user virtual memory `[start, end)` that a process may access
+/// with permission `perm`. Pages of a region are only allocated when the
+/// process first touches them.
+#[derive(Debug, Clone)]
+pub struct Region {
+    pub start: usize,
+    pub end: usize,
+    pub perm: PagePerm,
+    pub kind: RegionKind,
+    /// The file contents of a `File` region.
+    pub backing: Option<Backing>,
+}
+
+impl Region {
+    /// Returns a new `Region` of kind `kind` covering `[start, end)`.
+    pub fn new(kind: RegionKind, start: usize, end: usize, perm: PagePerm) -> Region {
+        return Region { start, end, perm, kind, backing: None };
+    }
+
+    /// Returns a new `File` region covering `[start, end)` that maps the
+    /// contents of `file` from byte `offset` on.
+    pub fn file(start: usize, end: usize, perm: PagePerm, file: OpenFile, offset: u64) -> Region {
+        let backing = Some(Backing { file, offset });
+        return Region { start, end, perm, kind: RegionKind::File, backing };
+    }
+
+    /// Returns `true` if the region was created by `mmap`.
+    pub fn is_mapping(&self) -> bool {
+        return self.kind == RegionKind::Anonymous || self.kind == RegionKind::File;
+    }
+
+    /// Splits the region at `at`, which must lie strictly inside it: the
+    /// region is shrunk to end at `at` and the part above it is returned.
+    pub fn split_off(&mut self, at: usize) -> Region {
+        let mut upper = self.clone();
+        upper.start = at;
+        if let Some(backing) = upper.backing.as_mut() {
+            backing.offset += (at - self.start) as u64;
+        }
+
+        self.end = at;
+        return upper;
+    }
+
+    /// Returns `true` if `va` lies in the region.
+    pub fn contains(&self, va: usize) -> bool {
+        return self.start <= va && va < self.end;
+    }
+}
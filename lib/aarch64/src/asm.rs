@@ -29,6 +29,22 @@ pub fn isb() {
     unsafe { asm!("isb" :::: "volatile") };
 }
 
+/// Invalidates the EL1 TLB entries of the virtual address `va` on every
+/// core, once the preceding page table writes are visible.
+#[inline(always)]
+pub fn tlb_invalidate_va(va: u64) {
+    // The operand holds VA[55:12] in its low 44 bits
+    let page = (va >> 12) & ((1 << 44) - 1);
+    unsafe {
+        asm!("dsb ishst
+              tlbi vae1is, $0
+              dsb ish
+              isb"
+             :: "r"(page)
+             :: "volatile");
+    }
+}
+
 /// Set Event
 #[inline(always)]
 pub fn sev() {
@@ -58,6 +58,9 @@ defbit!(RawL2Entry, [
 ]);
 
 defbit!(RawL3Entry, [
+    SW    [58-55],
+    UXN   [54-54],
+    PXN   [53-53],
     ADDR  [47-16],
 
     AF    [10-10],
@@ -170,6 +173,18 @@ impl fmt::Debug for RawL3Entry {
             _ => "????-??",
         })?;
 
+        if self.get_value(RawL3Entry::UXN) == 1 {
+            write!(f, "|UXN")?;
+        }
+
+        if self.get_value(RawL3Entry::PXN) == 1 {
+            write!(f, "|PXN")?;
+        }
+
+        if self.get_value(RawL3Entry::SW) != 0 {
+            write!(f, "|SW={:x}", self.get_value(RawL3Entry::SW))?;
+        }
+
         // NS    [05-05],
 
         write!(f, "-> {:08x} ({:x})",
@@ -486,3 +486,601 @@ fn my_test() {
 
     hash_file(&mut hash, entry).expect("successful hash");
 }
+
+/// A block device over an in-memory image that can be shared between several
+/// `VFat` instances to check what actually reached the "disk".
+#[derive(Clone)]
+struct SharedDevice(Arc<Mutex<Cursor<Vec<u8>>>>);
+
+impl SharedDevice {
+    fn new(image: Vec<u8>) -> SharedDevice {
+        SharedDevice(Arc::new(Mutex::new(Cursor::new(image))))
+    }
+}
+
+impl BlockDevice for SharedDevice {
+    fn read_sector(&mut self, n: u64, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().read_sector(n, buf)
+    }
+
+    fn write_sector(&mut self, n: u64, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write_sector(n, buf)
+    }
+}
+
+const MOCK_SECTORS: usize = 2048;
+const MOCK_SECTORS_PER_FAT: u32 = 16;
+
+/// Builds a 1MiB image holding an MBR and a single FAT32 partition starting
+/// at sector 1 with 512 byte clusters. The root directory holds one empty
+/// file, `EMPTY.TXT`.
+fn mock_vfat_image() -> Vec<u8> {
+    let mut image = vec![0u8; MOCK_SECTORS * 512];
+
+    // MBR: one FAT32 (LBA) partition
+    image[446 + 4] = 0x0C;
+    image[446 + 8..446 + 12].copy_from_slice(&1u32.to_le_bytes());
+    image[446 + 12..446 + 16].copy_from_slice(&(MOCK_SECTORS as u32 - 1).to_le_bytes());
+    image[510..512].copy_from_slice(&[0x55, 0xAA]);
+
+    // EBPB
+    let ebpb = &mut image[512..1024];
+    ebpb[11..13].copy_from_slice(&512u16.to_le_bytes());
+    ebpb[13] = 1;
+    ebpb[14..16].copy_from_slice(&32u16.to_le_bytes());
+    ebpb[16] = 2;
+    ebpb[32..36].copy_from_slice(&(MOCK_SECTORS as u32 - 1).to_le_bytes());
+    ebpb[36..40].copy_from_slice(&MOCK_SECTORS_PER_FAT.to_le_bytes());
+    ebpb[44..48].copy_from_slice(&2u32.to_le_bytes());
+    ebpb[66] = 0x29;
+    ebpb[510..512].copy_from_slice(&[0x55, 0xAA]);
+
+    // Both FATs: reserved entries and the root directory's single cluster
+    for fat in 0..2 {
+        let start = (1 + 32 + fat * MOCK_SECTORS_PER_FAT as usize) * 512;
+        image[start..start + 4].copy_from_slice(&0x0FFFFFF8u32.to_le_bytes());
+        image[start + 4..start + 8].copy_from_slice(&0x0FFFFFFFu32.to_le_bytes());
+        image[start + 8..start + 12].copy_from_slice(&0x0FFFFFFFu32.to_le_bytes());
+    }
+
+    // Root directory (cluster 2) with an empty file
+    let root = (1 + 32 + 2 * MOCK_SECTORS_PER_FAT as usize) * 512;
+    image[root..root + 11].copy_from_slice(b"EMPTY   TXT");
+    image[root + 11] = 0x20;
+
+    image
+}
+
+fn mock_clock() -> vfat::Timestamp {
+    vfat::Timestamp::new(vfat::Date::new(2020, 5, 18), vfat::Time::new(13, 37, 42))
+}
+
+fn mock_vfat(device: SharedDevice) -> StdVFatHandle {
+    let vfat = VFat::<StdVFatHandle>::from(device).expect("failed to initialize VFAT from mock image");
+    vfat.lock(|vfat| vfat.set_clock(mock_clock));
+    vfat
+}
+
+fn pattern(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 7 + i / 251) as u8).collect()
+}
+
+fn read_all<T: File>(file: &mut T) -> Vec<u8> {
+    let mut data = Vec::new();
+    file.read_to_end(&mut data).expect("read file");
+    data
+}
+
+#[test]
+fn test_write_extends_file() {
+    let vfat = mock_vfat(SharedDevice::new(mock_vfat_image()));
+
+    let mut file = vfat.open_file("/EMPTY.TXT").expect("file exists");
+    assert_eq!(file.size(), 0);
+
+    let data = pattern(3000);
+    file.write_all(&data[..100]).expect("write");
+    file.write_all(&data[100..]).expect("write");
+    assert_eq!(file.size(), 3000);
+
+    file.seek(io::SeekFrom::Start(0)).expect("seek");
+    assert_eq!(read_all(&mut file), data);
+
+    // The directory entry is updated without reopening the file system
+    let mut file = vfat.open_file("/EMPTY.TXT").expect("file exists");
+    assert_eq!(file.size(), 3000);
+    assert_eq!(read_all(&mut file), data);
+}
+
+#[test]
+fn test_write_overwrites_in_place() {
+    let vfat = mock_vfat(SharedDevice::new(mock_vfat_image()));
+
+    let mut file = vfat.open_file("/EMPTY.TXT").expect("file exists");
+    let mut data = pattern(2048);
+    file.write_all(&data).expect("write");
+
+    // Overwrite a range spanning a cluster boundary
+    file.seek(io::SeekFrom::Start(500)).expect("seek");
+    file.write_all(&[0xAB; 100]).expect("write");
+    data[500..600].copy_from_slice(&[0xAB; 100]);
+    assert_eq!(file.size(), 2048);
+
+    // Append at the end of a full cluster chain
+    file.seek(io::SeekFrom::End(0)).expect("seek");
+    file.write_all(&[0xCD; 10]).expect("write");
+    data.extend_from_slice(&[0xCD; 10]);
+
+    file.seek(io::SeekFrom::Start(0)).expect("seek");
+    assert_eq!(read_all(&mut file), data);
+    assert!(file.seek(io::SeekFrom::Start(data.len() as u64 + 1)).is_err());
+}
+
+#[test]
+fn test_write_persists_after_sync() {
+    let device = SharedDevice::new(mock_vfat_image());
+    let vfat = mock_vfat(device.clone());
+
+    let data = pattern(1500);
+    let mut file = vfat.open_file("/EMPTY.TXT").expect("file exists");
+    file.write_all(&data).expect("write");
+
+    // Nothing reaches the disk before a sync
+    let other = mock_vfat(device.clone());
+    assert_eq!(other.open_file("/EMPTY.TXT").expect("file exists").size(), 0);
+
+    file.sync().expect("sync");
+
+    let other = mock_vfat(device.clone());
+    let entry = other.open("/EMPTY.TXT").expect("entry exists");
+    let modified = entry.metadata().modified();
+    assert_eq!((modified.year(), modified.month(), modified.day()), (2020, 5, 18));
+    assert_eq!((modified.hour(), modified.minute(), modified.second()), (13, 37, 42));
+
+    let mut file = entry.into_file().expect("is a file");
+    assert_eq!(file.size(), 1500);
+    assert_eq!(read_all(&mut file), data);
+}
+
+#[test]
+fn test_set_len_truncates_and_extends() {
+    let device = SharedDevice::new(mock_vfat_image());
+    let vfat = mock_vfat(device.clone());
+
+    let data = pattern(5 * 512);
+    let mut file = vfat.open_file("/EMPTY.TXT").expect("file exists");
+    file.write_all(&data).expect("write");
+
+    file.set_len(600).expect("truncate");
+    assert_eq!(file.size(), 600);
+
+    file.set_len(2000).expect("extend");
+    file.flush().expect("flush");
+
+    let mut expected = data[..600].to_vec();
+    expected.resize(2000, 0);
+
+    let mut file = mock_vfat(device.clone()).open_file("/EMPTY.TXT").expect("file exists");
+    assert_eq!(file.size(), 2000);
+    assert_eq!(read_all(&mut file), expected);
+
+    file.set_len(0).expect("truncate");
+    file.sync().expect("sync");
+    let mut file = mock_vfat(device).open_file("/EMPTY.TXT").expect("file exists");
+    assert_eq!(file.size(), 0);
+    assert_eq!(read_all(&mut file), Vec::<u8>::new());
+}
+
+#[test]
+fn test_truncate_frees_clusters() {
+    let vfat = mock_vfat(SharedDevice::new(mock_vfat_image()));
+    let mut file = vfat.open_file("/EMPTY.TXT").expect("file exists");
+
+    // Repeatedly filling the disk only works if truncation frees clusters
+    let chunk = pattern(64 * 1024);
+    for _ in 0..3 {
+        loop {
+            match file.write(&chunk) {
+                Ok(_) => continue,
+                Err(e) => {
+                    assert_eq!(e.kind(), io::ErrorKind::Other);
+                    break;
+                }
+            }
+        }
+        assert!(file.size() > 900 * 1024);
+        file.set_len(0).expect("truncate");
+    }
+}
+
+#[test]
+fn test_write_std_file_device() {
+    let path = ::std::env::temp_dir().join(format!("fat32-write-{}.img", ::std::process::id()));
+    ::std::fs::write(&path, mock_vfat_image()).expect("write image");
+
+    let open = || {
+        let file = ::std::fs::OpenOptions::new().read(true).write(true).open(&path).expect("open image");
+        VFat::<StdVFatHandle>::from(file).expect("failed to initialize VFAT from image")
+    };
+
+    let data = pattern(4000);
+    let mut file = open().open_file("/EMPTY.TXT").expect("file exists");
+    file.write_all(&data).expect("write");
+    file.sync().expect("sync");
+
+    let mut file = open().open_file("/EMPTY.TXT").expect("file exists");
+    assert_eq!(read_all(&mut file), data);
+
+    ::std::fs::remove_file(&path).expect("remove image");
+}
+
+/// Returns the raw bytes of `cluster` in a mock image.
+fn raw_cluster(device: &SharedDevice, cluster: usize) -> Vec<u8> {
+    let start = (1 + 32 + 2 * MOCK_SECTORS_PER_FAT as usize + cluster - 2) * 512;
+    device.0.lock().unwrap().get_ref()[start..start + 512].to_vec()
+}
+
+fn entry_names(vfat: &StdVFatHandle, path: &str) -> Vec<String> {
+    vfat.open_dir(path)
+        .expect("directory exists")
+        .entries()
+        .expect("entries")
+        .map(|entry| entry.name().to_string())
+        .collect()
+}
+
+#[test]
+fn test_create_file_long_name() {
+    let device = SharedDevice::new(mock_vfat_image());
+    let vfat = mock_vfat(device.clone());
+
+    let data = pattern(700);
+    let mut file = vfat.create_file("/A long file name.text").expect("create");
+    file.write_all(&data).expect("write");
+    file.sync().expect("sync");
+
+    let other = mock_vfat(device.clone());
+    assert_eq!(entry_names(&other, "/"), vec!["EMPTY.TXT", "A long file name.text"]);
+    let mut file = other.open_file("/a LONG file NAME.text").expect("file exists");
+    assert_eq!(read_all(&mut file), data);
+
+    // Two LFN entries, last part first, followed by the 8.3 entry
+    let root = raw_cluster(&device, 2);
+    assert_eq!(root[32], 0x42);
+    assert_eq!(root[32 + 11], 0x0F);
+    assert_eq!(root[64], 0x01);
+    assert_eq!(&root[96..96 + 11], b"ALONGF~1TEX");
+    let checksum = root[96..96 + 11].iter().fold(0u8, |sum, &c| {
+        ((sum & 1) << 7).wrapping_add(sum >> 1).wrapping_add(c)
+    });
+    assert_eq!(root[32 + 13], checksum);
+    assert_eq!(root[64 + 13], checksum);
+}
+
+#[test]
+fn test_create_file_short_names() {
+    let device = SharedDevice::new(mock_vfat_image());
+    let vfat = mock_vfat(device.clone());
+
+    vfat.create_file("/Long Name One.txt").expect("create");
+    vfat.create_file("/Long Name Two.txt").expect("create");
+    vfat.create_file("/README").expect("create");
+    vfat.create_file("/.profile").expect("create");
+    vfat.lock(|vfat| vfat.flush()).expect("flush");
+
+    let root = raw_cluster(&device, 2);
+    let short_names: Vec<&[u8]> = root.chunks(32)
+        .take_while(|entry| entry[0] != 0x00)
+        .filter(|entry| entry[11] != 0x0F)
+        .map(|entry| &entry[..11])
+        .collect();
+    assert_eq!(short_names, vec![
+        &b"EMPTY   TXT"[..],
+        &b"LONGNA~1TXT"[..],
+        &b"LONGNA~2TXT"[..],
+        &b"README     "[..],
+        &b"PROFIL~1   "[..],
+    ]);
+
+    let other = mock_vfat(device);
+    assert_eq!(entry_names(&other, "/"), vec![
+        "EMPTY.TXT", "Long Name One.txt", "Long Name Two.txt", "README", ".profile",
+    ]);
+}
+
+#[test]
+fn test_create_errors() {
+    let vfat = mock_vfat(SharedDevice::new(mock_vfat_image()));
+
+    let kind = |result: io::Result<vfat::File<StdVFatHandle>>| result.err().map(|e| e.kind());
+    assert_eq!(kind(vfat.create_file("/empty.txt")), Some(io::ErrorKind::AlreadyExists));
+    assert_eq!(kind(vfat.create_file("/a:b")), Some(io::ErrorKind::InvalidInput));
+    assert_eq!(kind(vfat.create_file("/..")), Some(io::ErrorKind::InvalidInput));
+    assert_eq!(kind(vfat.create_file("/missing/file")), Some(io::ErrorKind::NotFound));
+    assert_eq!(kind(vfat.create_file("/EMPTY.TXT/file")), Some(io::ErrorKind::Other));
+}
+
+#[test]
+fn test_create_dir() {
+    let device = SharedDevice::new(mock_vfat_image());
+    let vfat = mock_vfat(device.clone());
+
+    vfat.create_dir("/Sub Directory").expect("create dir");
+    vfat.create_dir("/Sub Directory/nested").expect("create dir");
+    let mut file = vfat.create_file("/Sub Directory/nested/file.bin").expect("create");
+    file.write_all(&pattern(100)).expect("write");
+    file.sync().expect("sync");
+
+    let other = mock_vfat(device);
+    assert_eq!(entry_names(&other, "/Sub Directory"), vec![".", "..", "nested"]);
+    assert_eq!(entry_names(&other, "/Sub Directory/nested"), vec![".", "..", "file.bin"]);
+    assert_eq!(entry_names(&other, "/Sub Directory/.."), vec!["EMPTY.TXT", "Sub Directory"]);
+    assert_eq!(entry_names(&other, "/Sub Directory/nested/.."), vec![".", "..", "nested"]);
+
+    let entry = other.open("/Sub Directory/nested").expect("entry exists");
+    assert!(entry.is_dir());
+    assert_eq!(entry.metadata().created().year(), 2020);
+}
+
+#[test]
+fn test_directory_grows() {
+    let device = SharedDevice::new(mock_vfat_image());
+    let vfat = mock_vfat(device.clone());
+
+    // 512 byte clusters hold 16 entries; each of these names needs 3
+    let names: Vec<String> = (0..40).map(|i| format!("generated file number {}", i)).collect();
+    for name in names.iter() {
+        vfat.create_file(format!("/{}", name)).expect("create");
+    }
+    vfat.lock(|vfat| vfat.flush()).expect("flush");
+
+    let mut expected = vec![String::from("EMPTY.TXT")];
+    expected.extend(names.iter().cloned());
+    assert_eq!(entry_names(&mock_vfat(device), "/"), expected);
+}
+
+#[test]
+fn test_remove() {
+    let device = SharedDevice::new(mock_vfat_image());
+    let vfat = mock_vfat(device.clone());
+
+    let mut file = vfat.create_file("/to be removed.txt").expect("create");
+    file.write_all(&pattern(2000)).expect("write");
+    vfat.create_dir("/dir").expect("create dir");
+    vfat.create_file("/dir/inner").expect("create");
+
+    let kind = |result: io::Result<()>| result.err().map(|e| e.kind());
+    assert_eq!(kind(vfat.remove("/dir")), Some(io::ErrorKind::Other));
+    let dir = vfat.open_dir("/dir").expect("directory exists");
+    assert_eq!(kind(dir.remove("..")), Some(io::ErrorKind::InvalidInput));
+    assert_eq!(kind(vfat.remove("/missing")), Some(io::ErrorKind::NotFound));
+
+    vfat.remove("/to be removed.txt").expect("remove");
+    vfat.remove("/dir/inner").expect("remove");
+    vfat.remove("/dir").expect("remove");
+    vfat.lock(|vfat| vfat.flush()).expect("flush");
+
+    assert_eq!(entry_names(&mock_vfat(device.clone()), "/"), vec!["EMPTY.TXT"]);
+
+    // Every entry, including the LFN ones, is marked as deleted
+    let root = raw_cluster(&device, 2);
+    let markers: Vec<u8> = root.chunks(32).take(7).map(|entry| entry[0]).collect();
+    assert_eq!(markers, vec![b'E', 0xE5, 0xE5, 0xE5, 0xE5, 0xE5, 0x00]);
+}
+
+#[test]
+fn test_create_reuses_deleted_entries() {
+    let device = SharedDevice::new(mock_vfat_image());
+    let vfat = mock_vfat(device.clone());
+
+    vfat.create_file("/first long name.txt").expect("create");
+    vfat.create_file("/KEPT.TXT").expect("create");
+    vfat.remove("/first long name.txt").expect("remove");
+
+    // Too long to fit in the deleted entries
+    vfat.create_file("/a much longer name that needs three entries").expect("create");
+    // Fits exactly
+    vfat.create_file("/other long name.txt").expect("create");
+    vfat.lock(|vfat| vfat.flush()).expect("flush");
+
+    let root = raw_cluster(&device, 2);
+    assert_eq!(&root[3 * 32..3 * 32 + 11], b"OTHERL~1TXT");
+    assert_eq!(&root[4 * 32..4 * 32 + 11], b"KEPT    TXT");
+    assert_eq!(root[10 * 32], 0x00);
+
+    assert_eq!(entry_names(&mock_vfat(device), "/"), vec![
+        "EMPTY.TXT", "other long name.txt", "KEPT.TXT", "a much longer name that needs three entries",
+    ]);
+}
+
+#[test]
+fn test_rename() {
+    let device = SharedDevice::new(mock_vfat_image());
+    let vfat = mock_vfat(device.clone());
+
+    let data = pattern(1000);
+    let mut file = vfat.create_file("/original.txt").expect("create");
+    file.write_all(&data).expect("write");
+    vfat.create_dir("/a").expect("create dir");
+    vfat.create_dir("/b").expect("create dir");
+    vfat.create_dir("/a/child").expect("create dir");
+
+    // Within a directory, including a change of case only
+    vfat.rename("/original.txt", "/Renamed File.txt").expect("rename");
+    vfat.rename("/Renamed File.txt", "/renamed file.txt").expect("rename");
+    // Into another directory
+    vfat.rename("/renamed file.txt", "/a/child/moved.txt").expect("rename");
+    // A directory to a new parent
+    vfat.rename("/a/child", "/b/child").expect("rename");
+
+    let kind = |result: io::Result<()>| result.err().map(|e| e.kind());
+    assert_eq!(kind(vfat.rename("/b", "/b/child/b")), Some(io::ErrorKind::InvalidInput));
+    assert_eq!(kind(vfat.rename("/a", "/EMPTY.TXT")), Some(io::ErrorKind::AlreadyExists));
+    assert_eq!(kind(vfat.rename("/missing", "/other")), Some(io::ErrorKind::NotFound));
+    vfat.lock(|vfat| vfat.flush()).expect("flush");
+
+    let other = mock_vfat(device);
+    assert_eq!(entry_names(&other, "/"), vec!["EMPTY.TXT", "a", "b"]);
+    assert_eq!(entry_names(&other, "/a"), vec![".", ".."]);
+    assert_eq!(entry_names(&other, "/b/child"), vec![".", "..", "moved.txt"]);
+    assert_eq!(entry_names(&other, "/b/child/.."), vec![".", "..", "child"]);
+
+    let mut file = other.open_file("/b/child/moved.txt").expect("file exists");
+    assert_eq!(file.size(), 1000);
+    assert_eq!(read_all(&mut file), data);
+}
+
+fn sector_of(device: &SharedDevice, sector: usize) -> Vec<u8> {
+    device.0.lock().unwrap().get_ref()[sector * 512..(sector + 1) * 512].to_vec()
+}
+
+fn mock_cache(device: &SharedDevice, capacity: usize) -> vfat::CachedPartition {
+    let partition = vfat::Partition { start: 2, num_sectors: 32, sector_size: 512 };
+    vfat::CachedPartition::with_capacity(device.clone(), partition, capacity)
+}
+
+#[test]
+fn test_cache_counts_hits_and_misses() {
+    let device = SharedDevice::new(vec![0; 64 * 512]);
+    let mut cache = mock_cache(&device, 4);
+
+    cache.get(0).expect("get");
+    cache.get(0).expect("get");
+    cache.get_mut(1).expect("get_mut");
+    cache.get(1).expect("get");
+    assert_eq!(cache.stats(), vfat::CacheStats { hits: 2, misses: 2, evictions: 0, writebacks: 0 });
+
+    assert_eq!(cache.get(32).err().map(|e| e.kind()), Some(io::ErrorKind::InvalidInput));
+}
+
+#[test]
+fn test_cache_writes_back_on_eviction() {
+    let device = SharedDevice::new(vec![0; 64 * 512]);
+    let mut cache = mock_cache(&device, 4);
+
+    for sector in 0..8 {
+        cache.get_mut(sector).expect("get_mut")[0] = sector as u8 + 1;
+    }
+
+    // The cache never holds more than 4 sectors, so half were written back
+    let stats = cache.stats();
+    assert_eq!((stats.misses, stats.evictions, stats.writebacks), (8, 4, 4));
+    let written = (0..8).filter(|&sector| sector_of(&device, sector + 2)[0] != 0).count();
+    assert_eq!(written, 4);
+
+    // Evicted sectors are read back with their new contents
+    for sector in 0..8 {
+        assert_eq!(cache.get(sector).expect("get")[0], sector as u8 + 1);
+    }
+
+    cache.flush().expect("flush");
+    for sector in 0..8 {
+        assert_eq!(sector_of(&device, sector + 2)[0], sector as u8 + 1);
+    }
+    assert_eq!(sector_of(&device, 0)[0], 0);
+    assert_eq!(sector_of(&device, 1)[0], 0);
+
+    // Nothing is dirty anymore
+    let writebacks = cache.stats().writebacks;
+    cache.flush().expect("flush");
+    assert_eq!(cache.stats().writebacks, writebacks);
+}
+
+#[test]
+fn test_cache_prefers_referenced_sectors() {
+    let device = SharedDevice::new(vec![0; 64 * 512]);
+    let mut cache = mock_cache(&device, 3);
+
+    for sector in 0..3 {
+        cache.get(sector).expect("get");
+    }
+
+    // Loading 3 clears every reference bit and evicts 0
+    cache.get(3).expect("get");
+    // 1 gets a second chance, so loading 4 evicts 2
+    cache.get(1).expect("get");
+    cache.get(4).expect("get");
+
+    let misses = cache.stats().misses;
+    cache.get(1).expect("get");
+    cache.get(3).expect("get");
+    assert_eq!(cache.stats().misses, misses);
+    cache.get(2).expect("get");
+    assert_eq!(cache.stats().misses, misses + 1);
+}
+
+#[test]
+fn test_cache_shrinking_writes_back() {
+    let device = SharedDevice::new(vec![0; 64 * 512]);
+    let mut cache = mock_cache(&device, 8);
+
+    for sector in 0..8 {
+        cache.get_mut(sector).expect("get_mut")[0] = 0xAA;
+    }
+    assert_eq!(cache.stats().writebacks, 0);
+
+    cache.set_capacity(2).expect("set_capacity");
+    assert_eq!(cache.capacity(), 2);
+    assert_eq!(cache.stats().writebacks, 6);
+    assert_eq!((2..10).filter(|&sector| sector_of(&device, sector)[0] == 0xAA).count(), 6);
+}
+
+/// A `SharedDevice` that counts the calls to `read_sector()` and
+/// `read_sectors()` it receives.
+struct CountingDevice(SharedDevice, Arc<Mutex<(usize, usize)>>);
+
+impl BlockDevice for CountingDevice {
+    fn read_sector(&mut self, n: u64, buf: &mut [u8]) -> io::Result<usize> {
+        self.1.lock().unwrap().0 += 1;
+        self.0.read_sector(n, buf)
+    }
+
+    fn read_sectors(&mut self, n: u64, buf: &mut [u8]) -> io::Result<usize> {
+        self.1.lock().unwrap().1 += 1;
+        self.0.read_sectors(n, buf)
+    }
+
+    fn write_sector(&mut self, n: u64, buf: &[u8]) -> io::Result<usize> {
+        self.0.write_sector(n, buf)
+    }
+}
+
+#[test]
+fn test_cache_reads_logical_sector_at_once() {
+    let mut image = vec![0; 64 * 512];
+    for (i, byte) in image.iter_mut().enumerate() {
+        *byte = (i / 512) as u8;
+    }
+
+    let calls = Arc::new(Mutex::new((0, 0)));
+    let device = CountingDevice(SharedDevice::new(image), calls.clone());
+    let partition = vfat::Partition { start: 2, num_sectors: 8, sector_size: 2048 };
+    let mut cache = vfat::CachedPartition::with_capacity(device, partition, 4);
+
+    // Logical sector 1 is physical sectors 6 to 9, read in one request
+    let data = cache.get(1).expect("get").to_vec();
+    assert_eq!(data.len(), 2048);
+    assert_eq!((data[0], data[512], data[1024], data[1536]), (6, 7, 8, 9));
+    assert_eq!(*calls.lock().unwrap(), (0, 1));
+}
+
+#[test]
+fn test_write_with_small_cache() {
+    let device = SharedDevice::new(mock_vfat_image());
+    let vfat = mock_vfat(device.clone());
+    vfat.lock(|vfat| vfat.set_cache_capacity(4)).expect("set_cache_capacity");
+
+    let data = pattern(100 * 1024);
+    let mut file = vfat.create_file("/big.bin").expect("create");
+    file.write_all(&data).expect("write");
+    file.seek(io::SeekFrom::Start(0)).expect("seek");
+    assert_eq!(read_all(&mut file), data);
+    file.sync().expect("sync");
+
+    let stats = vfat.lock(|vfat| vfat.cache_stats());
+    assert!(stats.evictions > 0);
+    assert!(stats.writebacks >= 200);
+
+    let mut file = mock_vfat(device).open_file("/big.bin").expect("file exists");
+    assert_eq!(read_all(&mut file), data);
+}
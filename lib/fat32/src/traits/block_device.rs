@@ -20,6 +20,25 @@ pub trait BlockDevice: Send {
     /// Returns an error if seeking or reading from `self` fails.
     fn read_sector(&mut self, n: u64, buf: &mut [u8]) -> io::Result<usize>;
 
+    /// Reads the consecutive sectors starting at sector `n` into `buf`. The
+    /// number of bytes read is returned.
+    ///
+    /// The default implementation calls `read_sector()` once per sector.
+    /// Devices that support multi-sector transfers should override it.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if reading any of the sectors fails.
+    fn read_sectors(&mut self, n: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let sector_size = self.sector_size() as usize;
+
+        let mut read = 0;
+        for (i, chunk) in buf.chunks_mut(sector_size).enumerate() {
+            read += self.read_sector(n + i as u64, chunk)?;
+        }
+        Ok(read)
+    }
+
     /// Append sector number `n` into `vec`.
     ///
     /// `self.sector_size()` bytes are appended to `vec`. The number of bytes
@@ -56,6 +75,25 @@ pub trait BlockDevice: Send {
     /// error of `UnexpectedEof` if the length of `buf` is less than
     /// `self.sector_size()`.
     fn write_sector(&mut self, n: u64, buf: &[u8]) -> io::Result<usize>;
+
+    /// Overwrites the consecutive sectors starting at sector `n` with the
+    /// contents of `buf`. The number of bytes written is returned.
+    ///
+    /// The default implementation calls `write_sector()` once per sector.
+    /// Devices that support multi-sector transfers should override it.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if writing any of the sectors fails.
+    fn write_sectors(&mut self, n: u64, buf: &[u8]) -> io::Result<usize> {
+        let sector_size = self.sector_size() as usize;
+
+        let mut written = 0;
+        for (i, chunk) in buf.chunks(sector_size).enumerate() {
+            written += self.write_sector(n + i as u64, chunk)?;
+        }
+        Ok(written)
+    }
 }
 
 impl<'a, T: BlockDevice> BlockDevice for &'a mut T {
@@ -63,9 +101,17 @@ impl<'a, T: BlockDevice> BlockDevice for &'a mut T {
         (*self).read_sector(n, buf)
     }
 
+    fn read_sectors(&mut self, n: u64, buf: &mut [u8]) -> io::Result<usize> {
+        (*self).read_sectors(n, buf)
+    }
+
     fn write_sector(&mut self, n: u64, buf: &[u8]) -> io::Result<usize> {
         (*self).write_sector(n, buf)
     }
+
+    fn write_sectors(&mut self, n: u64, buf: &[u8]) -> io::Result<usize> {
+        (*self).write_sectors(n, buf)
+    }
 }
 
 macro impl_for_read_write_seek($(<$($gen:tt),*>)* $T:path) {
@@ -113,4 +113,47 @@ pub trait FileSystem: Sized {
             .into_dir()
             .ok_or(io::Error::new(io::ErrorKind::Other, "not a directory"))
     }
+
+    /// Creates an empty file at `path` and returns it. `path` must be
+    /// absolute.
+    ///
+    /// # Errors
+    ///
+    /// If the parent of `path` is not an existing directory, the error
+    /// conditions of `open_dir()` apply.
+    ///
+    /// If an entry already exists at `path`, an error kind of `AlreadyExists`
+    /// is returned.
+    ///
+    /// If the last component of `path` is not a valid file name, an error
+    /// kind of `InvalidInput` is returned.
+    fn create_file<P: AsRef<Path>>(self, path: P) -> io::Result<Self::File>;
+
+    /// Creates an empty directory at `path` and returns it. `path` must be
+    /// absolute.
+    ///
+    /// # Errors
+    ///
+    /// The error conditions are the same as for `create_file()`.
+    fn create_dir<P: AsRef<Path>>(self, path: P) -> io::Result<Self::Dir>;
+
+    /// Removes the file or empty directory at `path`. `path` must be
+    /// absolute.
+    ///
+    /// # Errors
+    ///
+    /// In addition to the error conditions for `open()`, this method returns
+    /// an error kind of `Other` if `path` refers to a directory that is not
+    /// empty.
+    fn remove<P: AsRef<Path>>(self, path: P) -> io::Result<()>;
+
+    /// Moves the entry at `from` to `to`. Both paths must be absolute.
+    ///
+    /// # Errors
+    ///
+    /// In addition to the error conditions for `open()` on `from`, this
+    /// method returns an error kind of `AlreadyExists` if an entry already
+    /// exists at `to` and `InvalidInput` if a directory would be moved into
+    /// itself.
+    fn rename<P: AsRef<Path>, Q: AsRef<Path>>(self, from: P, to: Q) -> io::Result<()>;
 }
@@ -10,6 +10,33 @@ use crate::traits::BlockDevice;
 struct CacheEntry {
     data: Vec<u8>,
     dirty: bool,
+    referenced: bool,
+}
+
+/// The number of logical sectors cached by default.
+pub const DEFAULT_CACHE_CAPACITY: usize = 256;
+
+/// Counters describing how well the sector cache is doing.
+#[derive(Default, Debug, Copy, Clone, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Accesses served from the cache.
+    pub hits: u64,
+    /// Accesses that had to read the sector from the disk.
+    pub misses: u64,
+    /// Sectors dropped from the cache to make room for others.
+    pub evictions: u64,
+    /// Dirty sectors written back to the disk.
+    pub writebacks: u64,
+}
+
+impl fmt::Display for CacheStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "hits: {}, misses: {}, evictions: {}, writebacks: {}",
+            self.hits, self.misses, self.evictions, self.writebacks
+        )
+    }
 }
 
 pub struct Partition {
@@ -25,6 +52,13 @@ pub struct CachedPartition {
     device: Box<dyn BlockDevice>,
     cache: HashMap<u64, CacheEntry>,
     partition: Partition,
+    /// Maximum number of sectors held in `cache`.
+    capacity: usize,
+    /// Cached sectors in CLOCK order.
+    clock: Vec<u64>,
+    /// Index into `clock` of the next eviction candidate.
+    hand: usize,
+    stats: CacheStats,
 }
 
 impl CachedPartition {
@@ -41,19 +75,39 @@ impl CachedPartition {
     /// `partition.sector_size` must be an integer multiple of
     /// `device.sector_size()`.
     ///
+    /// At most `DEFAULT_CACHE_CAPACITY` sectors are cached at once.
+    ///
     /// # Panics
     ///
     /// Panics if the partition's sector size is < the device's sector size.
     pub fn new<T>(device: T, partition: Partition) -> CachedPartition
         where
             T: BlockDevice + 'static,
+    {
+        CachedPartition::with_capacity(device, partition, DEFAULT_CACHE_CAPACITY)
+    }
+
+    /// Like `new()`, but caches at most `capacity` sectors at once.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the partition's sector size is < the device's sector size or
+    /// if `capacity` is 0.
+    pub fn with_capacity<T>(device: T, partition: Partition, capacity: usize) -> CachedPartition
+        where
+            T: BlockDevice + 'static,
     {
         assert!(partition.sector_size >= device.sector_size());
+        assert!(capacity > 0);
 
         CachedPartition {
             device: Box::new(device),
             cache: HashMap::new(),
             partition: partition,
+            capacity: capacity,
+            clock: Vec::with_capacity(capacity),
+            hand: 0,
+            stats: CacheStats::default(),
         }
     }
 
@@ -76,6 +130,42 @@ impl CachedPartition {
         Some(physical_sector)
     }
 
+    /// Returns the maximum number of sectors cached at once.
+    pub fn capacity(&self) -> usize {
+        return self.capacity;
+    }
+
+    /// Sets the maximum number of sectors cached at once, evicting sectors
+    /// (and writing them back if dirty) until the cache fits.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if there is an error writing an evicted sector back
+    /// to the disk.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `capacity` is 0.
+    pub fn set_capacity(&mut self, capacity: usize) -> io::Result<()> {
+        assert!(capacity > 0);
+        self.capacity = capacity;
+
+        while self.clock.len() > self.capacity {
+            let slot = self.evict()?;
+            self.clock.remove(slot);
+            if self.hand >= self.clock.len() {
+                self.hand = 0;
+            }
+        }
+
+        return Ok(());
+    }
+
+    /// Returns the cache's hit, miss, eviction and writeback counters.
+    pub fn stats(&self) -> CacheStats {
+        return self.stats;
+    }
+
     /// Returns a mutable reference to the cached sector `sector`. If the sector
     /// is not already cached, the sector is first read from the disk.
     ///
@@ -87,7 +177,7 @@ impl CachedPartition {
     ///
     /// Returns an error if there is an error reading the sector from the disk.
     pub fn get_mut(&mut self, sector: u64) -> io::Result<&mut [u8]> {
-        self.get(sector)?;
+        self.load(sector)?;
 
         let entry = self.cache.get_mut(&sector).expect("Expected entry");
         entry.dirty = true;
@@ -102,27 +192,103 @@ impl CachedPartition {
     ///
     /// Returns an error if there is an error reading the sector from the disk.
     pub fn get(&mut self, sector: u64) -> io::Result<&[u8]> {
-        use crate::traits::BlockDevice;
+        self.load(sector)?;
+
+        return Ok(self.cache.get(&sector).expect("Expected entry").data.as_slice());
+    }
+
+    /// Ensures `sector` is cached and marks it as recently used. A sector is
+    /// evicted first if the cache is full.
+    fn load(&mut self, sector: u64) -> io::Result<()> {
+        if let Some(entry) = self.cache.get_mut(&sector) {
+            entry.referenced = true;
+            self.stats.hits += 1;
+            return Ok(());
+        }
 
-        if !self.cache.contains_key(&sector) {
-            let physical_sector = match self.virtual_to_physical(sector) {
-                Some(sector) => sector,
-                None => panic!("Translation failed"),
-            };
+        let physical_sector = match self.virtual_to_physical(sector) {
+            Some(sector) => sector,
+            None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "Sector out of range")),
+        };
 
-            let num_physical_sector = self.factor();
+        let mut vec = vec![0u8; (self.factor() * self.device.sector_size()) as usize];
+        let read = self.device.read_sectors(physical_sector, &mut vec)?;
+        vec.truncate(read);
+        self.stats.misses += 1;
 
-            let mut vec: Vec<u8> = Vec::new();
+        if self.clock.len() < self.capacity {
+            self.clock.push(sector);
+        } else {
+            let slot = self.evict()?;
+            self.clock[slot] = sector;
+            self.hand = (slot + 1) % self.clock.len();
+        }
 
-            for i in 0..num_physical_sector {
-                self.device.read_all_sector(physical_sector + i, &mut vec);
+        let entry = CacheEntry { data: vec, dirty: false, referenced: true };
+        self.cache.insert(sector, entry);
+
+        return Ok(());
+    }
+
+    /// Picks a sector to evict using the CLOCK algorithm, writes it back if
+    /// it is dirty and removes it from the cache. Returns the slot in `clock`
+    /// that the evicted sector occupied.
+    fn evict(&mut self) -> io::Result<usize> {
+        loop {
+            let sector = self.clock[self.hand];
+            let entry = self.cache.get_mut(&sector).expect("Expected entry");
+
+            if entry.referenced {
+                // Give it a second chance
+                entry.referenced = false;
+                self.hand = (self.hand + 1) % self.clock.len();
+                continue;
             }
 
-            let entry = CacheEntry { data: vec, dirty: false };
-            self.cache.insert(sector, entry);
+            self.write_back(sector)?;
+            self.cache.remove(&sector);
+            self.stats.evictions += 1;
+
+            return Ok(self.hand);
         }
+    }
 
-        return Ok(self.cache.get(&sector).expect("Expected entry").data.as_slice());
+    /// Writes the cached sector `sector` to the disk if it is dirty and marks
+    /// it clean.
+    fn write_back(&mut self, sector: u64) -> io::Result<()> {
+        let physical_sector = self.partition.start + sector * self.factor();
+
+        let entry = self.cache.get_mut(&sector).expect("Expected entry");
+        if !entry.dirty {
+            return Ok(());
+        }
+
+        self.device.write_sectors(physical_sector, &entry.data)?;
+
+        entry.dirty = false;
+        self.stats.writebacks += 1;
+
+        return Ok(());
+    }
+
+    /// Writes every dirty cached sector back to the disk and marks it clean.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if there is an error writing a sector to the disk.
+    /// Sectors that could not be written remain dirty.
+    pub fn flush(&mut self) -> io::Result<()> {
+        let mut dirty: Vec<u64> = self.cache.iter()
+            .filter(|(_, entry)| entry.dirty)
+            .map(|(&sector, _)| sector)
+            .collect();
+        dirty.sort();
+
+        for sector in dirty {
+            self.write_back(sector)?;
+        }
+
+        return Ok(());
     }
 }
 
@@ -148,7 +314,9 @@ impl fmt::Debug for CachedPartition {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         f.debug_struct("CachedPartition")
             .field("device", &"<block device>")
-            .field("cache", &self.cache)
+            .field("capacity", &self.capacity)
+            .field("cached", &self.cache.len())
+            .field("stats", &self.stats)
             .finish()
     }
 }
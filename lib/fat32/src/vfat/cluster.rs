@@ -16,4 +16,10 @@ impl Cluster {
     pub fn index(&self) -> u64 {
         return self.0 as u64 - 2;
     }
+
+    /// Returns `true` if this cluster refers to a data cluster. Empty files
+    /// have no clusters and store a starting cluster of `0`.
+    pub fn is_data(&self) -> bool {
+        return self.0 >= 2;
+    }
 }
@@ -7,12 +7,14 @@ use shim::io;
 use shim::newioerr;
 
 use crate::traits;
+use crate::traits::{Dir as DirTrait, Entry as EntryTrait};
 use crate::util::VecExt;
 use crate::vfat::{Attributes, Date, Metadata, Time, Timestamp};
-use crate::vfat::{Cluster, Entry, File, VFatHandle};
+use crate::vfat::{Cluster, Entry, File, VFat, VFatHandle};
 use crate::vfat::error::{Error};
 
 use core::char::{decode_utf16, REPLACEMENT_CHARACTER};
+use core::mem::size_of;
 
 #[derive(Debug)]
 pub struct Dir<HANDLE: VFatHandle> {
@@ -21,24 +23,48 @@ pub struct Dir<HANDLE: VFatHandle> {
     curr: Cluster,
     pub metadata: Metadata,
     pub name: String,
+    location: Option<EntryLocation>,
 }
 
 impl<HANDLE: VFatHandle> Dir<HANDLE> {
-    pub fn new(vfat: HANDLE, start: Cluster, metadata: Metadata, name: String) -> Dir<HANDLE> {
+    pub fn new(vfat: HANDLE, start: Cluster, metadata: Metadata, name: String, location: Option<EntryLocation>) -> Dir<HANDLE> {
         return Dir {
             vfat,
             start,
             curr: start,
             metadata,
             name,
+            location,
         };
     }
 
     pub fn root(vfat: HANDLE, root_cluster: Cluster) -> Dir<HANDLE> {
-        return Dir::new(vfat, root_cluster, Metadata::now(), String::from("/"));
+        return Dir::new(vfat, root_cluster, Metadata::now(), String::from("/"), None);
+    }
+
+    /// Returns the first cluster of the directory.
+    pub fn start(&self) -> Cluster {
+        return self.start;
+    }
+
+    /// Returns the location of the directory's entry in its parent, or `None`
+    /// for the root directory.
+    pub(crate) fn location(&self) -> Option<EntryLocation> {
+        return self.location;
     }
 }
 
+/// The on-disk position of a regular directory entry: the first cluster of
+/// the directory holding it and its index (in 32-byte entries) in that
+/// directory. `first` is the index of the first LFN entry belonging to it,
+/// or `index` itself when it has no long file name.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct EntryLocation {
+    pub dir: Cluster,
+    pub first: usize,
+    pub index: usize,
+}
+
 #[repr(C, packed)]
 #[derive(Copy, Clone)]
 pub struct VFatRegularDirEntry {
@@ -60,14 +86,83 @@ pub struct VFatRegularDirEntry {
 
 const_assert_size!(VFatRegularDirEntry, 32);
 
+impl VFatRegularDirEntry {
+    /// Creates an entry named `short_name` (8.3, space padded) whose
+    /// creation, modification and access times are all `timestamp`.
+    pub fn new(short_name: [u8; 11], attributes: u8, cluster: Cluster, timestamp: Timestamp) -> VFatRegularDirEntry {
+        let mut entry = VFatRegularDirEntry {
+            file_name: [b' '; 8],
+            file_ext: [b' '; 3],
+            attributes,
+            _reserved: [0],
+            _tenths: [0],
+            created_at_time: timestamp.time,
+            created_at_date: timestamp.date,
+            accessed_at: timestamp.date,
+            high_bits_first_cluster_number: 0,
+            modified_at_time: timestamp.time,
+            modified_at_date: timestamp.date,
+            low_bits_first_cluster_number: 0,
+            size: 0,
+        };
+        entry.set_short_name(short_name);
+        entry.set_cluster(cluster);
+
+        return entry;
+    }
+
+    /// Returns the 8.3 name of the entry, space padded.
+    pub fn short_name(&self) -> [u8; 11] {
+        let mut name = [0u8; 11];
+        name[..8].copy_from_slice(&self.file_name);
+        name[8..].copy_from_slice(&self.file_ext);
+
+        return name;
+    }
+
+    /// Sets the 8.3 name of the entry.
+    pub fn set_short_name(&mut self, short_name: [u8; 11]) {
+        self.file_name.copy_from_slice(&short_name[..8]);
+        self.file_ext.copy_from_slice(&short_name[8..]);
+    }
+
+    /// Returns `true` if the entry is a directory.
+    pub fn is_dir(&self) -> bool {
+        return self.attributes & 0x10 != 0;
+    }
+
+    /// Returns the first cluster of the entry's data.
+    pub fn cluster(&self) -> Cluster {
+        return Cluster::from((self.high_bits_first_cluster_number as u32) << 16 | self.low_bits_first_cluster_number as u32);
+    }
+
+    /// Sets the first cluster of the entry's data.
+    pub fn set_cluster(&mut self, cluster: Cluster) {
+        self.high_bits_first_cluster_number = (cluster.num() >> 16) as u16;
+        self.low_bits_first_cluster_number = cluster.num() as u16;
+    }
+
+    /// Sets the size, in bytes, of the entry's data.
+    pub fn set_size(&mut self, size: u32) {
+        self.size = size;
+    }
+
+    /// Sets the last modification and access times of the entry.
+    pub fn set_modified(&mut self, timestamp: Timestamp) {
+        self.modified_at_date = timestamp.date;
+        self.modified_at_time = timestamp.time;
+        self.accessed_at = timestamp.date;
+    }
+}
+
 #[repr(C, packed)]
 #[derive(Copy, Clone)]
 pub struct VFatLfnDirEntry {
     sequence_number: u8,
     first_file_name: [u16; 5],
-    _3: [u8; 1],
+    attributes: u8,
     _4: [u8; 1],
-    _5: [u8; 1],
+    checksum: u8,
     second_file_name: [u16; 6],
     _7: [u8; 2],
     third_file_name: [u16; 2],
@@ -75,6 +170,40 @@ pub struct VFatLfnDirEntry {
 
 const_assert_size!(VFatLfnDirEntry, 32);
 
+impl VFatLfnDirEntry {
+    /// Creates the LFN entry holding the 13 UTF-16 code units `name` of the
+    /// entry whose short name has checksum `checksum`.
+    pub fn new(sequence_number: u8, name: [u16; 13], checksum: u8) -> VFatLfnDirEntry {
+        let mut first_file_name = [0u16; 5];
+        let mut second_file_name = [0u16; 6];
+        let mut third_file_name = [0u16; 2];
+        first_file_name.copy_from_slice(&name[..5]);
+        second_file_name.copy_from_slice(&name[5..11]);
+        third_file_name.copy_from_slice(&name[11..]);
+
+        return VFatLfnDirEntry {
+            sequence_number,
+            first_file_name,
+            attributes: 0x0F,
+            _4: [0],
+            checksum,
+            second_file_name,
+            _7: [0; 2],
+            third_file_name,
+        };
+    }
+
+    /// Returns the 13 UTF-16 code units of the name stored in this entry.
+    pub fn name(&self) -> [u16; 13] {
+        let mut name = [0u16; 13];
+        name[..5].copy_from_slice(&{ self.first_file_name });
+        name[5..11].copy_from_slice(&{ self.second_file_name });
+        name[11..].copy_from_slice(&{ self.third_file_name });
+
+        return name;
+    }
+}
+
 #[repr(C, packed)]
 #[derive(Copy, Clone)]
 pub struct VFatUnknownDirEntry {
@@ -87,14 +216,57 @@ pub struct VFatUnknownDirEntry {
 
 const_assert_size!(VFatUnknownDirEntry, 32);
 
+#[derive(Copy, Clone)]
 pub union VFatDirEntry {
     unknown: VFatUnknownDirEntry,
     regular: VFatRegularDirEntry,
     long_filename: VFatLfnDirEntry,
 }
 
+impl VFatDirEntry {
+    /// Returns the regular entry view of this entry.
+    pub fn regular(&self) -> VFatRegularDirEntry {
+        return unsafe { self.regular };
+    }
+
+    /// Returns a mutable regular entry view of this entry.
+    pub fn regular_mut(&mut self) -> &mut VFatRegularDirEntry {
+        return unsafe { &mut self.regular };
+    }
+
+    /// Returns the first byte of the entry's name: `0x00` marks the end of
+    /// the directory and `0xE5` a deleted entry.
+    pub fn marker(&self) -> u8 {
+        return unsafe { self.unknown.file_name[0] };
+    }
+
+    /// Returns `true` if the entry is part of a long file name.
+    pub fn is_lfn(&self) -> bool {
+        return unsafe { self.unknown.attributes } == 0x0F;
+    }
+
+    /// Marks the entry as deleted.
+    pub fn set_deleted(&mut self) {
+        unsafe { self.unknown.file_name[0] = 0xE5 };
+    }
+}
+
+impl From<VFatRegularDirEntry> for VFatDirEntry {
+    fn from(regular: VFatRegularDirEntry) -> VFatDirEntry {
+        return VFatDirEntry { regular };
+    }
+}
+
+impl From<VFatLfnDirEntry> for VFatDirEntry {
+    fn from(long_filename: VFatLfnDirEntry) -> VFatDirEntry {
+        return VFatDirEntry { long_filename };
+    }
+}
+
 pub struct EntryIterator<HANDLE: VFatHandle> {
     pub vfat: HANDLE,
+    dir: Cluster,
+    root: Cluster,
     entries: Vec<VFatDirEntry>,
     curr_index: usize,
 }
@@ -122,7 +294,7 @@ fn parse_null_string(buf: &[u8]) -> String {
 /// Parse utf16 string
 fn parse_utf16_string(buf: &[u16]) -> String {
     let end = buf.iter()
-        .position(|&c| c == 0x00 || c == 0xFF)
+        .position(|&c| c == 0x0000 || c == 0xFFFF)
         .unwrap_or(buf.len());
 
     let part = buf[..end].to_vec();
@@ -132,16 +304,256 @@ fn parse_utf16_string(buf: &[u16]) -> String {
         .collect::<String>();
 }
 
+/// Returns the display form of an 8.3 name: the base name, followed by a
+/// dot and the extension if there is one.
+fn short_display_name(short_name: &[u8; 11]) -> String {
+    let mut string = parse_null_string(&short_name[..8]);
+    let extension = parse_null_string(&short_name[8..]);
+
+    if extension.len() > 0 {
+        string.push_str(".");
+        string.push_str(&extension);
+    }
+
+    return string;
+}
+
+/// Characters other than letters and digits allowed in 8.3 names.
+const SHORT_NAME_SPECIAL: &[u8] = b"$%'-_@~`!(){}^#&";
+
+/// Characters never allowed in a file name.
+const INVALID_NAME_CHARS: &str = "\"*/:<>?\\|";
+
+/// Checks that `name` can be used as the name of a new entry and returns it.
+///
+/// # Errors
+///
+/// Returns an error of kind `InvalidInput` if `name` is not valid UTF-8, is
+/// empty, consists of dots only, is longer than 255 UTF-16 code units or
+/// contains control characters or any of `"*/:<>?\|`.
+fn validate_name(name: &OsStr) -> io::Result<&str> {
+    let name = match name.to_str() {
+        Some(name) => name,
+        None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid name")),
+    };
+
+    if name.is_empty() || name.chars().all(|c| c == '.') || name.encode_utf16().count() > 255 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid name"));
+    }
+
+    if name.chars().any(|c| c < ' ' || INVALID_NAME_CHARS.contains(c)) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid character in name"));
+    }
+
+    return Ok(name);
+}
+
+/// Converts `part` to at most `max_len` 8.3 name characters. Also returns
+/// whether any information was lost in the conversion.
+fn short_name_part(part: &str, max_len: usize) -> (Vec<u8>, bool) {
+    let mut out: Vec<u8> = Vec::new();
+    let mut lossy = false;
+
+    for c in part.chars() {
+        if c == ' ' || c == '.' {
+            lossy = true;
+            continue;
+        }
+
+        if out.len() == max_len {
+            lossy = true;
+            break;
+        }
+
+        if c.is_ascii_alphanumeric() || (c.is_ascii() && SHORT_NAME_SPECIAL.contains(&(c as u8))) {
+            out.push(c.to_ascii_uppercase() as u8);
+        } else {
+            out.push(b'_');
+            lossy = true;
+        }
+    }
+
+    return (out, lossy);
+}
+
+/// Generates an 8.3 name for `name` that isn't in `taken`. Names that can't
+/// be represented exactly, or that collide, get a numeric tail (`~1`, `~2`,
+/// ...) as Windows does.
+fn short_name(name: &str, taken: &[[u8; 11]]) -> io::Result<[u8; 11]> {
+    let stripped = name.trim_start_matches('.');
+    let (base, ext) = match stripped.rfind('.') {
+        Some(i) => (&stripped[..i], &stripped[i + 1..]),
+        None => (stripped, ""),
+    };
+
+    let (base, base_lossy) = short_name_part(base, 8);
+    let (ext, ext_lossy) = short_name_part(ext, 3);
+    let lossy = base_lossy || ext_lossy || base.is_empty() || stripped.len() != name.len();
+
+    let build = |base: &[u8]| {
+        let mut short = [b' '; 11];
+        short[..base.len()].copy_from_slice(base);
+        short[8..8 + ext.len()].copy_from_slice(&ext);
+        short
+    };
+
+    if !lossy {
+        let short = build(&base);
+        if !taken.contains(&short) {
+            return Ok(short);
+        }
+    }
+
+    for n in 1..1_000_000 {
+        let tail = format!("~{}", n);
+        let keep = core::cmp::min(base.len(), 8 - tail.len());
+
+        let mut candidate: Vec<u8> = base[..keep].to_vec();
+        candidate.extend_from_slice(tail.as_bytes());
+
+        let short = build(&candidate);
+        if !taken.contains(&short) {
+            return Ok(short);
+        }
+    }
+
+    return Err(io::Error::new(io::ErrorKind::AlreadyExists, "No short name available"));
+}
+
+/// Computes the checksum of an 8.3 name stored in each of its LFN entries.
+fn lfn_checksum(short_name: &[u8; 11]) -> u8 {
+    let mut sum: u8 = 0;
+    for &c in short_name.iter() {
+        sum = ((sum & 1) << 7).wrapping_add(sum >> 1).wrapping_add(c);
+    }
+
+    return sum;
+}
+
+/// Builds the entries for `name`: its LFN entries, if the name can't be
+/// stored in 8.3 form, in on-disk order followed by `regular`.
+fn name_entries(name: &str, regular: VFatRegularDirEntry) -> Vec<VFatDirEntry> {
+    let mut entries: Vec<VFatDirEntry> = Vec::new();
+    let short = regular.short_name();
+
+    if short_display_name(&short) != name {
+        let units: Vec<u16> = name.encode_utf16().collect();
+        let count = (units.len() + 12) / 13;
+        let checksum = lfn_checksum(&short);
+
+        // The last part of the name is stored first
+        for seq in (1..=count).rev() {
+            let part = &units[(seq - 1) * 13..core::cmp::min(seq * 13, units.len())];
+
+            // Terminated with 0x0000 and padded with 0xFFFF
+            let mut chunk = [0xFFFFu16; 13];
+            chunk[..part.len()].copy_from_slice(part);
+            if part.len() < 13 {
+                chunk[part.len()] = 0x0000;
+            }
+
+            let sequence_number = if seq == count { seq as u8 | 0x40 } else { seq as u8 };
+            entries.push(VFatLfnDirEntry::new(sequence_number, chunk, checksum).into());
+        }
+    }
+
+    entries.push(regular.into());
+    return entries;
+}
+
+/// Reads every raw entry of the directory starting at `dir`.
+fn read_entries<HANDLE: VFatHandle>(vfat: &mut VFat<HANDLE>, dir: Cluster) -> io::Result<Vec<VFatDirEntry>> {
+    let mut entries_data: Vec<u8> = Vec::new();
+    vfat.read_chain(dir, &mut entries_data)?;
+
+    return Ok(unsafe { entries_data.cast() });
+}
+
+/// Adds `regular` to the directory starting at `dir` under `name`, giving it
+/// a unique short name and LFN entries as needed. Deleted entries are reused
+/// when enough consecutive ones are found; otherwise the entries are placed
+/// at the end of the directory, which is extended if it is full.
+fn insert_entry<HANDLE: VFatHandle>(vfat: &mut VFat<HANDLE>, dir: Cluster, name: &str, mut regular: VFatRegularDirEntry) -> io::Result<EntryLocation> {
+    let existing = read_entries(vfat, dir)?;
+
+    let taken: Vec<[u8; 11]> = existing.iter()
+        .take_while(|entry| entry.marker() != 0x00)
+        .filter(|entry| entry.marker() != 0xE5 && !entry.is_lfn())
+        .map(|entry| entry.regular().short_name())
+        .collect();
+    regular.set_short_name(short_name(name, &taken)?);
+
+    let entries = name_entries(name, regular);
+    let count = entries.len();
+
+    // Find `count` consecutive free slots
+    let mut run = 0;
+    let mut first = existing.len();
+    for (i, entry) in existing.iter().enumerate() {
+        if entry.marker() == 0x00 {
+            // Everything from here on is free
+            first = i - run;
+            break;
+        }
+
+        if entry.marker() == 0xE5 {
+            run += 1;
+            if run == count {
+                first = i + 1 - count;
+                break;
+            }
+        } else {
+            run = 0;
+        }
+    }
+    if first == existing.len() {
+        first -= run;
+    }
+
+    // Grow the directory until the entries fit
+    let entries_per_cluster = vfat.cluster_size() as usize / size_of::<VFatDirEntry>();
+    let mut capacity = existing.len();
+    if first + count > capacity {
+        let mut last = dir;
+        while let Some(next) = vfat.next_cluster(last) {
+            last = next;
+        }
+
+        while first + count > capacity {
+            last = vfat.alloc_cluster(Some(last))?;
+            capacity += entries_per_cluster;
+        }
+    }
+
+    for (i, entry) in entries.iter().enumerate() {
+        vfat.write_dir_entry(EntryLocation { dir, first, index: first + i }, entry)?;
+    }
+
+    return Ok(EntryLocation { dir, first, index: first + count - 1 });
+}
+
+/// Marks the entry at `location` and its LFN entries as deleted.
+fn delete_entries<HANDLE: VFatHandle>(vfat: &mut VFat<HANDLE>, location: EntryLocation) -> io::Result<()> {
+    for index in location.first..=location.index {
+        let entry_location = EntryLocation { index, ..location };
+
+        let mut entry = vfat.read_dir_entry(entry_location)?;
+        entry.set_deleted();
+        vfat.write_dir_entry(entry_location, &entry)?;
+    }
+
+    return Ok(());
+}
+
 /// Implement iterator trait for our EntryIterator struct
 impl<HANDLE: VFatHandle> Iterator for EntryIterator<HANDLE> {
     type Item = Entry<HANDLE>;
 
     /// Get next item in iterator
     fn next(&mut self) -> Option<Self::Item> {
-        // String to store the file name
+        // Long file name parts along with their sequence numbers
         let mut lfn: Vec<(u8, String)> = Vec::new();
-        let mut in_lfn = false;
-        let mut lfn_len = 0;
+        let mut first_index = self.curr_index;
 
         while self.curr_index < self.entries.len() {
             // Get entry at curr_index
@@ -153,40 +565,24 @@ impl<HANDLE: VFatHandle> Iterator for EntryIterator<HANDLE> {
                 return None;
             } else if unknown_entry.file_name[0] == 0xE5 {
                 // This one is deleted, continue to next
+                lfn.clear();
+                first_index = self.curr_index;
                 continue;
             }
 
-
             match unknown_entry.attributes {
                 0x0F => {
                     // Long file name
                     let lfn_entry = unsafe { entry.long_filename };
 
-                    if lfn_entry.sequence_number | 0x10 != 0 {
-                        // First entry!
-                        in_lfn = true;
-                    }
-
-                    if lfn_entry.sequence_number | 0x00 == 0 {
-                        // Last entry!
-                        in_lfn = false;
+                    if lfn_entry.sequence_number & 0x40 != 0 {
+                        // Last part of the name, which is stored first
+                        lfn.clear();
+                        first_index = self.curr_index - 1;
                     }
 
-                    if in_lfn {
-                        let mut first: String = parse_utf16_string(&{lfn_entry.first_file_name});
-                        let second: String = parse_utf16_string(&{lfn_entry.second_file_name});
-                        let third: String = parse_utf16_string(&{lfn_entry.third_file_name});
+                    lfn.push((lfn_entry.sequence_number & 0x1F, parse_utf16_string(&lfn_entry.name())));
 
-                        if first.len() == 5 {
-                            first.push_str(&second);
-                        }
-                        if first.len() == 11 {
-                            first.push_str(&third);
-                        }
-
-                        lfn.push((lfn_entry.sequence_number, first));
-                        lfn_len += 1;
-                    }
                     // Keep going until regular entry
                     continue;
                 },
@@ -200,20 +596,15 @@ impl<HANDLE: VFatHandle> Iterator for EntryIterator<HANDLE> {
                         Timestamp::new(re.modified_at_date, re.modified_at_time),
                         Attributes(re.attributes),
                     );
-                    let starting_cluster = Cluster::from((re.high_bits_first_cluster_number as u32) << 16 | re.low_bits_first_cluster_number as u32);
-
-                    let mut name = match lfn_len {
-                        0 => {
-                            let mut string = parse_null_string(&re.file_name);
-                            let mut extension = parse_null_string(&re.file_ext);
-
-                            if extension.len() > 0 {
-                                string.push_str(".");
-                                string.push_str(&extension);
-                            }
+                    let mut starting_cluster = re.cluster();
+                    let location = EntryLocation {
+                        dir: self.dir,
+                        first: first_index,
+                        index: self.curr_index - 1,
+                    };
 
-                            string
-                        },
+                    let name = match lfn.len() {
+                        0 => short_display_name(&re.short_name()),
                         _ => {
                             // Sort by sequence number
                             lfn.sort_by_key(|k| k.0);
@@ -229,6 +620,11 @@ impl<HANDLE: VFatHandle> Iterator for EntryIterator<HANDLE> {
                     };
 
                     if re.attributes & 0x10 != 0 {
+                        // ".." entries refer to the root directory as cluster 0
+                        if !starting_cluster.is_data() {
+                            starting_cluster = self.root;
+                        }
+
                         return Some(
                             Entry::EntryDir(
                                 Dir::new(
@@ -236,6 +632,7 @@ impl<HANDLE: VFatHandle> Iterator for EntryIterator<HANDLE> {
                                     starting_cluster,
                                     metadata,
                                     name,
+                                    Some(location),
                                 )
                             )
                         );
@@ -248,14 +645,11 @@ impl<HANDLE: VFatHandle> Iterator for EntryIterator<HANDLE> {
                                 starting_cluster,
                                 re.size as u64,
                                 name,
+                                location,
                             )
                         )
                     );
                 },
-                _ => {
-                    // println!("{}", unknown_entry.attributes);
-                    panic!("Why are we here")
-                }
             }
         }
         return None;
@@ -292,6 +686,187 @@ impl<HANDLE: VFatHandle> Dir<HANDLE> {
 
         return Err(io::Error::new(io::ErrorKind::NotFound, "File not found"));
     }
+
+    /// Validates `name` for a new entry in `self`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `AlreadyExists` if an entry named `name`
+    /// exists, unless it is the entry at `allowed`.
+    fn check_new_name<'n>(&self, name: &'n OsStr, allowed: Option<EntryLocation>) -> io::Result<&'n str> {
+        let name = validate_name(name)?;
+
+        match self.find(name) {
+            Ok(entry) => {
+                if allowed.is_none() || entry.location() != allowed {
+                    return Err(io::Error::new(io::ErrorKind::AlreadyExists, "Entry already exists"));
+                }
+            },
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {},
+            Err(err) => return Err(err),
+        }
+
+        return Ok(name);
+    }
+
+    /// Creates an empty file named `name` in `self` and returns it.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `AlreadyExists` if an entry named `name`
+    /// already exists and `InvalidInput` if `name` is not a valid file name.
+    pub fn create_file<P: AsRef<OsStr>>(&self, name: P) -> io::Result<File<HANDLE>> {
+        let name = self.check_new_name(name.as_ref(), None)?;
+        let start = self.start;
+
+        let (timestamp, location) = self.vfat.lock(|vfat| -> io::Result<(Timestamp, EntryLocation)> {
+            let timestamp = vfat.now();
+            let regular = VFatRegularDirEntry::new([b' '; 11], 0x20, Cluster::from(0), timestamp);
+            let location = insert_entry(vfat, start, name, regular)?;
+
+            return Ok((timestamp, location));
+        })?;
+
+        let metadata = Metadata::new(
+            timestamp,
+            Timestamp::new(timestamp.date, Time(0)),
+            timestamp,
+            Attributes(0x20),
+        );
+
+        return Ok(File::new(self.vfat.clone(), metadata, Cluster::from(0), 0, String::from(name), location));
+    }
+
+    /// Creates an empty directory named `name` in `self` and returns it. The
+    /// new directory contains only the `.` and `..` entries.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `AlreadyExists` if an entry named `name`
+    /// already exists and `InvalidInput` if `name` is not a valid file name.
+    pub fn create_dir<P: AsRef<OsStr>>(&self, name: P) -> io::Result<Dir<HANDLE>> {
+        let name = self.check_new_name(name.as_ref(), None)?;
+        let start = self.start;
+
+        let (timestamp, cluster, location) = self.vfat.lock(|vfat| -> io::Result<(Timestamp, Cluster, EntryLocation)> {
+            let timestamp = vfat.now();
+
+            // ".." refers to the root directory as cluster 0
+            let parent = if start == vfat.root_cluster() { Cluster::from(0) } else { start };
+
+            let cluster = vfat.alloc_cluster(None)?;
+            let dot = VFatRegularDirEntry::new(*b".          ", 0x10, cluster, timestamp);
+            let dotdot = VFatRegularDirEntry::new(*b"..         ", 0x10, parent, timestamp);
+            vfat.write_dir_entry(EntryLocation { dir: cluster, first: 0, index: 0 }, &dot.into())?;
+            vfat.write_dir_entry(EntryLocation { dir: cluster, first: 1, index: 1 }, &dotdot.into())?;
+
+            let regular = VFatRegularDirEntry::new([b' '; 11], 0x10, cluster, timestamp);
+            match insert_entry(vfat, start, name, regular) {
+                Ok(location) => return Ok((timestamp, cluster, location)),
+                Err(err) => {
+                    vfat.free_chain(cluster)?;
+                    return Err(err);
+                }
+            }
+        })?;
+
+        let metadata = Metadata::new(
+            timestamp,
+            Timestamp::new(timestamp.date, Time(0)),
+            timestamp,
+            Attributes(0x10),
+        );
+
+        return Ok(Dir::new(self.vfat.clone(), cluster, metadata, String::from(name), Some(location)));
+    }
+
+    /// Removes the entry named `name` from `self` and frees its clusters.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `NotFound` if there is no such entry,
+    /// `InvalidInput` if `name` is `.` or `..` and `Other` if `name` is a
+    /// directory that is not empty.
+    pub fn remove<P: AsRef<OsStr>>(&self, name: P) -> io::Result<()> {
+        let entry = self.find(name)?;
+        if entry.name() == "." || entry.name() == ".." {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Cannot remove . or .."));
+        }
+
+        if let Entry::EntryDir(dir) = &entry {
+            if dir.entries()?.any(|e| e.name() != "." && e.name() != "..") {
+                return Err(io::Error::new(io::ErrorKind::Other, "Directory not empty"));
+            }
+        }
+
+        let location = entry.location().expect("child entries have a location");
+        self.vfat.lock(|vfat| {
+            let cluster = vfat.read_dir_entry(location)?.regular().cluster();
+            if cluster.is_data() {
+                vfat.free_chain(cluster)?;
+            }
+
+            delete_entries(vfat, location)
+        })
+    }
+
+    /// Moves the entry named `name` in `self` to `to`, naming it `new_name`.
+    /// The entry keeps its data and timestamps.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `NotFound` if there is no such entry,
+    /// `AlreadyExists` if `to` already has an entry named `new_name` and
+    /// `InvalidInput` if `name` is `.` or `..`, `new_name` is not a valid
+    /// file name or a directory would be moved into itself.
+    pub fn rename<P: AsRef<OsStr>, Q: AsRef<OsStr>>(&self, name: P, to: &Dir<HANDLE>, new_name: Q) -> io::Result<()> {
+        let entry = self.find(name)?;
+        if entry.name() == "." || entry.name() == ".." {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Cannot rename . or .."));
+        }
+
+        let location = entry.location().expect("child entries have a location");
+        let new_name = to.check_new_name(new_name.as_ref(), Some(location))?;
+        let moved_dir = match &entry {
+            Entry::EntryDir(dir) => Some(dir.start),
+            Entry::EntryFile(_) => None,
+        };
+        let (from_start, to_start) = (self.start, to.start);
+
+        self.vfat.lock(|vfat| {
+            let root = vfat.root_cluster();
+
+            if let Some(moved) = moved_dir {
+                // Refuse to move a directory into itself or a descendant
+                let mut curr = to_start;
+                while curr != root {
+                    if curr == moved {
+                        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Cannot move a directory into itself"));
+                    }
+
+                    let parent = vfat.read_dir_entry(EntryLocation { dir: curr, first: 1, index: 1 })?.regular().cluster();
+                    curr = if parent.is_data() { parent } else { root };
+                }
+            }
+
+            let regular = vfat.read_dir_entry(location)?.regular();
+            insert_entry(vfat, to_start, new_name, regular)?;
+            delete_entries(vfat, location)?;
+
+            if let Some(moved) = moved_dir {
+                if from_start != to_start {
+                    let dotdot_location = EntryLocation { dir: moved, first: 1, index: 1 };
+                    let parent = if to_start == root { Cluster::from(0) } else { to_start };
+
+                    let mut dotdot = vfat.read_dir_entry(dotdot_location)?;
+                    dotdot.regular_mut().set_cluster(parent);
+                    vfat.write_dir_entry(dotdot_location, &dotdot)?;
+                }
+            }
+
+            return Ok(());
+        })
+    }
 }
 
 impl<HANDLE: VFatHandle> traits::Dir for Dir<HANDLE> {
@@ -299,14 +874,15 @@ impl<HANDLE: VFatHandle> traits::Dir for Dir<HANDLE> {
     type Iter = EntryIterator<HANDLE>;
 
     fn entries(&self) -> io::Result<Self::Iter> {
-        let mut entries_data: Vec<u8> = Vec::new();
-        self.vfat.lock(|vfat| vfat.read_chain(self.start, &mut entries_data));
-
-        let entries: Vec<VFatDirEntry> = unsafe { entries_data.cast() };
+        let (root, entries) = self.vfat.lock(|vfat| -> io::Result<(Cluster, Vec<VFatDirEntry>)> {
+            return Ok((vfat.root_cluster(), read_entries(vfat, self.start)?));
+        })?;
 
         return Ok(
             EntryIterator {
                 vfat: self.vfat.clone(),
+                dir: self.start,
+                root: root,
                 entries: entries,
                 curr_index: 0,
             }
@@ -1,5 +1,5 @@
 use crate::traits;
-use crate::vfat::{Dir, File, Metadata, VFatHandle};
+use crate::vfat::{Dir, EntryLocation, File, Metadata, VFatHandle};
 use core::fmt;
 
 // You can change this definition if you want
@@ -9,6 +9,17 @@ pub enum Entry<HANDLE: VFatHandle> {
     EntryDir(Dir<HANDLE>),
 }
 
+impl<HANDLE: VFatHandle> Entry<HANDLE> {
+    /// Returns the location of the entry in its directory, or `None` for the
+    /// root directory.
+    pub(crate) fn location(&self) -> Option<EntryLocation> {
+        match self {
+            Entry::EntryFile(file) => Some(file.location()),
+            Entry::EntryDir(dir) => dir.location(),
+        }
+    }
+}
+
 impl<HANDLE: VFatHandle> traits::Entry for Entry<HANDLE> {
     // FIXME: Implement `traits::Entry` for `Entry`.
     type File = File<HANDLE>;
@@ -3,7 +3,7 @@ use core::fmt;
 
 use self::Status::*;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Copy, Clone)]
 pub enum Status {
     /// The FAT entry corresponds to an unused (free) cluster.
     Free,
@@ -35,6 +35,21 @@ impl FatEntry {
             _ => panic!("Unknown status"),
         }
     }
+
+    /// Sets the status of the FAT entry `self`, preserving the reserved high
+    /// four bits of the entry.
+    pub fn set_status(&mut self, status: Status) {
+        let mask = !(0xF << 28);
+        let value = match status {
+            Status::Free => 0x0000000,
+            Status::Reserved => 0x0000001,
+            Status::Data(next) => next.num() as u32,
+            Status::Bad => 0xFFFFFF7,
+            Status::Eoc(n) => n & mask,
+        };
+
+        self.0 = (self.0 & !mask) | value;
+    }
 }
 
 impl fmt::Debug for FatEntry {
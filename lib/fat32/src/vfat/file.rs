@@ -1,11 +1,12 @@
 use alloc::string::String;
+use alloc::vec::Vec;
 
-use shim::io::{self, SeekFrom, Error, ErrorKind};
+use shim::io::{self, SeekFrom, Error, ErrorKind, Write};
 
 use crate::traits;
-use crate::vfat::{VFat, Cluster, Metadata, VFatHandle};
+use crate::vfat::{VFat, Cluster, EntryLocation, Metadata, VFatHandle};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct File<HANDLE: VFatHandle> {
     pub vfat: HANDLE,
     pub metadata: Metadata,
@@ -14,20 +15,131 @@ pub struct File<HANDLE: VFatHandle> {
     curr_offset: u64,
     pub size: u64,
     pub name: String,
+    location: EntryLocation,
 }
 
 impl<HANDLE: VFatHandle> File<HANDLE> {
-    pub fn new(vfat: HANDLE, metadata: Metadata, starting_cluster: Cluster, size: u64, name: String) -> File<HANDLE> {
+    pub fn new(vfat: HANDLE, metadata: Metadata, starting_cluster: Cluster, size: u64, name: String, location: EntryLocation) -> File<HANDLE> {
+        let curr_cluster = if starting_cluster.is_data() { Some(starting_cluster) } else { None };
+
         return File {
             vfat,
             metadata,
             starting_cluster,
-            curr_cluster: Some(starting_cluster),
+            curr_cluster,
             curr_offset: 0,
             size,
             name,
+            location,
         };
     }
+
+    /// Returns the location of the file's entry in its directory.
+    pub(crate) fn location(&self) -> EntryLocation {
+        return self.location;
+    }
+
+    /// Truncates or extends the file to `len` bytes. Extended bytes are
+    /// zero-filled. The current offset is clamped to the new size.
+    ///
+    /// Clusters no longer needed by a truncated file are freed.
+    pub fn set_len(&mut self, len: u64) -> io::Result<()> {
+        let offset = self.curr_offset;
+
+        if len > self.size {
+            let cluster_size = self.vfat.lock(|vfat| vfat.cluster_size());
+            let zeroes: Vec<u8> = vec![0; cluster_size as usize];
+
+            self.set_offset(self.size);
+            while self.size < len {
+                let size = core::cmp::min(len - self.size, cluster_size);
+                self.write_all(&zeroes[..size as usize])?;
+            }
+            self.set_offset(offset);
+
+            return Ok(());
+        }
+
+        let starting_cluster = self.starting_cluster;
+        if starting_cluster.is_data() {
+            if len == 0 {
+                self.vfat.lock(|vfat| vfat.free_chain(starting_cluster))?;
+                self.starting_cluster = Cluster::from(0);
+            } else {
+                self.vfat.lock(|vfat| -> io::Result<()> {
+                    match vfat.locate(starting_cluster, len - 1) {
+                        Some((last, _)) => vfat.truncate_chain(last),
+                        None => Ok(()),
+                    }
+                })?;
+            }
+        }
+
+        self.size = len;
+        self.set_offset(core::cmp::min(offset, len));
+
+        return self.update_entry();
+    }
+
+    /// Moves the current offset to `offset`, finding the cluster that holds
+    /// it. `offset` must not be beyond the end of the file.
+    fn set_offset(&mut self, offset: u64) {
+        let starting_cluster = self.starting_cluster;
+
+        self.curr_offset = offset;
+        self.curr_cluster = if starting_cluster.is_data() {
+            self.vfat.lock(|vfat| vfat.locate(starting_cluster, offset)).map(|(cluster, _)| cluster)
+        } else {
+            None
+        };
+    }
+
+    /// Allocates a new cluster at the end of the file's chain and makes it
+    /// the current cluster.
+    fn extend(&mut self) -> io::Result<Cluster> {
+        let starting_cluster = self.starting_cluster;
+
+        let cluster = self.vfat.lock(|vfat| {
+            if !starting_cluster.is_data() {
+                return vfat.alloc_cluster(None);
+            }
+
+            let mut last = starting_cluster;
+            while let Some(next) = vfat.next_cluster(last) {
+                last = next;
+            }
+            vfat.alloc_cluster(Some(last))
+        })?;
+
+        if !starting_cluster.is_data() {
+            self.starting_cluster = cluster;
+        }
+        self.curr_cluster = Some(cluster);
+
+        return Ok(cluster);
+    }
+
+    /// Stamps the file as modified now and writes its first cluster, size and
+    /// modification time back to its directory entry.
+    fn update_entry(&mut self) -> io::Result<()> {
+        let timestamp = self.vfat.lock(|vfat| vfat.now());
+        self.metadata.set_modified(timestamp);
+
+        let location = self.location;
+        let starting_cluster = self.starting_cluster;
+        let size = self.size as u32;
+
+        self.vfat.lock(|vfat| {
+            let mut entry = vfat.read_dir_entry(location)?;
+
+            let regular = entry.regular_mut();
+            regular.set_cluster(starting_cluster);
+            regular.set_size(size);
+            regular.set_modified(timestamp);
+
+            vfat.write_dir_entry(location, &entry)
+        })
+    }
 }
 
 // FIXME: Implement `traits::File` (and its supertraits) for `File`.
@@ -68,14 +180,60 @@ impl<HANDLE: VFatHandle> io::Read for File<HANDLE> {
     }
 }
 
-/// Probably gonna need this for the project
 impl<HANDLE: VFatHandle> io::Write for File<HANDLE> {
+    /// Writes `buf` at the current offset, allocating clusters as the file
+    /// grows. The directory entry's size and modification time are updated,
+    /// but the data only reaches the disk once the file is flushed.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `Other` if the file system is full or the file
+    /// would grow beyond the 4GiB FAT32 limit.
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        unimplemented!("OS Pals' project baby")
+        if buf.len() == 0 {
+            return Ok(0);
+        }
+
+        let max_size = (core::u32::MAX as u64 - self.curr_offset) as usize;
+        if max_size == 0 {
+            return Err(Error::new(ErrorKind::Other, "File too large"));
+        }
+        let buf = if buf.len() > max_size { &buf[..max_size] } else { buf };
+
+        let cluster_size = self.vfat.lock(|vfat| vfat.cluster_size());
+
+        let mut total_size: usize = 0;
+        while total_size < buf.len() {
+            let curr_cluster = match self.curr_cluster {
+                Some(cluster) => cluster,
+                None => self.extend()?,
+            };
+
+            let offset = self.curr_offset % cluster_size;
+
+            let size = self.vfat.lock(|vfat| vfat.write_cluster(curr_cluster, offset as usize, &buf[total_size..]))?;
+
+            self.curr_offset += size as u64;
+            total_size += size;
+
+            if size as u64 == cluster_size - offset {
+                // At end of the cluster, get next cluster
+                self.curr_cluster = self.vfat.lock(|vfat| vfat.next_cluster(curr_cluster));
+            }
+        }
+
+        if self.curr_offset > self.size {
+            self.size = self.curr_offset;
+        }
+        self.update_entry()?;
+
+        return Ok(total_size);
     }
 
     fn flush(&mut self) -> io::Result<()> {
-        unimplemented!("Yessir")
+        use traits::File;
+
+        self.sync()
     }
 }
 
@@ -95,39 +253,29 @@ impl<HANDLE: VFatHandle> io::Seek for File<HANDLE> {
     /// Seeking before the start of a file or beyond the end of the file results
     /// in an `InvalidInput` error.
     fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
-        match pos {
-            SeekFrom::Start(offset) => {
-                if offset >= self.size {
-                    return Err(Error::new(ErrorKind::InvalidInput, "Invalid offset"));
-                }
-                self.curr_offset = offset;
-                return Ok(self.curr_offset);
-            },
-            SeekFrom::End(offset) => {
-                let offset = self.size as i64 + offset;
-                if offset < 0 {
-                    return Err(Error::new(ErrorKind::InvalidInput, "Cannot seek to before start of file"));
-                }
-
-                self.curr_offset = offset as u64;
-                return Ok(self.curr_offset);
-            },
-            SeekFrom::Current(offset) => {
-                let offset = self.curr_offset as i64 + offset;
-                if offset < 0 {
-                    return Err(Error::new(ErrorKind::InvalidInput, "Cannot seek to before start of file"));
-                }
-
-                self.curr_offset = offset as u64;
-                return Ok(self.curr_offset);
-            }
+        let offset = match pos {
+            SeekFrom::Start(offset) => offset as i64,
+            SeekFrom::End(offset) => self.size as i64 + offset,
+            SeekFrom::Current(offset) => self.curr_offset as i64 + offset,
+        };
+
+        if offset < 0 {
+            return Err(Error::new(ErrorKind::InvalidInput, "Cannot seek to before start of file"));
+        }
+        if offset as u64 > self.size {
+            return Err(Error::new(ErrorKind::InvalidInput, "Cannot seek beyond end of file"));
         }
+
+        self.set_offset(offset as u64);
+        return Ok(self.curr_offset);
     }
 }
 
 impl<HANDLE: VFatHandle> traits::File for File<HANDLE> {
+    /// Writes the file's dirty sectors, along with every other dirty sector
+    /// in the file system's cache, back to the disk.
     fn sync(&mut self) -> io::Result<()> {
-        unimplemented!("Sync is not implemented");
+        self.vfat.lock(|vfat| vfat.flush())
     }
 
     fn size(&self) -> u64 {
@@ -19,6 +19,23 @@ pub struct Time(pub u16);
 #[derive(Default, Debug, Copy, Clone, PartialEq, Eq)]
 pub struct Attributes(pub u8);
 
+impl Date {
+    /// Returns a `Date` for the calendar day `day` of `month` in `year`.
+    /// `year` must be in the range [1980, 2107].
+    pub fn new(year: usize, month: u8, day: u8) -> Date {
+        let year = (year - 1980) as u16;
+        return Date(year << 9 | (month as u16 & 0xF) << 5 | (day as u16 & 0x1F));
+    }
+}
+
+impl Time {
+    /// Returns a `Time` for `hour:minute:second`. FAT32 only stores seconds
+    /// at a two second granularity, so odd seconds are rounded down.
+    pub fn new(hour: u8, minute: u8, second: u8) -> Time {
+        return Time((hour as u16 & 0x1F) << 11 | (minute as u16 & 0x3F) << 5 | (second as u16 / 2));
+    }
+}
+
 /// A structure containing a date and time.
 #[derive(Default, Copy, Clone, PartialEq, Eq)]
 pub struct Timestamp {
@@ -54,6 +71,12 @@ impl Metadata {
         };
     }
 
+    /// Sets the last modification (and access) time to `timestamp`.
+    pub fn set_modified(&mut self, timestamp: Timestamp) {
+        self.modified_at = timestamp;
+        self.accessed_at = Timestamp::new(timestamp.date, Time(0));
+    }
+
     pub fn now() -> Metadata {
         let timestamp = Timestamp::new(Date(0), Time(0));
         let attr = Attributes(0);
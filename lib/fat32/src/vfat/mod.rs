@@ -9,6 +9,7 @@ pub(crate) mod file;
 pub(crate) mod metadata;
 pub(crate) mod vfat;
 
+pub use self::cache::{CacheStats, DEFAULT_CACHE_CAPACITY};
 pub use self::dir::Dir;
 pub use self::ebpb::BiosParameterBlock;
 pub use self::entry::Entry;
@@ -19,4 +20,5 @@ pub use self::vfat::{VFat, VFatHandle};
 
 pub(crate) use self::cache::{CachedPartition, Partition};
 pub(crate) use self::cluster::Cluster;
+pub(crate) use self::dir::{EntryLocation, VFatDirEntry};
 pub(crate) use self::fat::{FatEntry, Status};
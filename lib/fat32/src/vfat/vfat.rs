@@ -4,6 +4,7 @@ use core::mem::size_of;
 
 use alloc::vec::Vec;
 
+use shim::ffi::OsStr;
 use shim::io;
 use shim::path;
 use shim::path::Path;
@@ -11,8 +12,9 @@ use shim::path::Path;
 use crate::mbr::{MasterBootRecord, PartitionEntry};
 use crate::traits::{BlockDevice, FileSystem};
 use crate::traits::Dir as DirTrait;
-use crate::vfat::{BiosParameterBlock, CachedPartition, Partition};
-use crate::vfat::{Cluster, Dir, Entry, Error, FatEntry, File, Status};
+use crate::util::SliceExt;
+use crate::vfat::{BiosParameterBlock, CacheStats, CachedPartition, Partition};
+use crate::vfat::{Cluster, Dir, Entry, EntryLocation, Error, FatEntry, File, Status, Timestamp, VFatDirEntry};
 
 /// A generic trait that handles a critical section as a closure
 pub trait VFatHandle: Clone + Debug + Send + Sync {
@@ -32,6 +34,10 @@ pub struct VFat<HANDLE: VFatHandle> {
     rootdir_cluster: Cluster,
     cluster_size: u64,
     total_fat_sectors: u64,
+    num_fats: u8,
+    num_clusters: u64,
+    next_free: u64,
+    clock: Option<fn() -> Timestamp>,
 }
 
 impl<HANDLE: VFatHandle> VFat<HANDLE> {
@@ -64,6 +70,11 @@ impl<HANDLE: VFatHandle> VFat<HANDLE> {
         let total_fat_sectors: u64 = ebpb.sectors_per_fat as u64 * ebpb.num_fats as u64;
         let data_start_sector: u64 = fat_start_sector + total_fat_sectors;
 
+        // Clusters addressable by both the data region and the FAT
+        let data_clusters = (ebpb.num_logical_sectors as u64 - data_start_sector) / ebpb.sectors_per_cluster as u64;
+        let fat_entries = ebpb.sectors_per_fat as u64 * (ebpb.bytes_per_sector as u64 / size_of::<FatEntry>() as u64);
+        let num_clusters = core::cmp::min(data_clusters + 2, fat_entries);
+
         // TODO logical num sectors could be wrong
         let partition: Partition = Partition {
             start: fat_base, // TODO wrong?
@@ -82,6 +93,10 @@ impl<HANDLE: VFatHandle> VFat<HANDLE> {
             rootdir_cluster: Cluster::from(ebpb.root_cluster_num),
             cluster_size: ebpb.bytes_per_sector as u64 * ebpb.sectors_per_cluster as u64,
             total_fat_sectors: total_fat_sectors,
+            num_fats: ebpb.num_fats,
+            num_clusters: num_clusters,
+            next_free: 2,
+            clock: None,
         };
 
         return Ok(VFatHandle::new(vfat));
@@ -122,6 +137,41 @@ impl<HANDLE: VFatHandle> VFat<HANDLE> {
         return Ok(total_size);
     }
 
+    /// A method to write from a buffer into an offset of a cluster. Returns the
+    /// number of bytes written, which is less than `buf.len()` if the write
+    /// reaches the end of the cluster.
+    pub fn write_cluster(&mut self, cluster: Cluster, offset: usize, buf: &[u8]) -> io::Result<usize> {
+        let rem_cluster_size = self.cluster_size as usize - offset;
+        let max_size: usize = if buf.len() > rem_cluster_size { rem_cluster_size } else { buf.len() };
+
+        let sector_index = offset / self.bytes_per_sector() as usize;
+        let mut sector_offset = offset % self.bytes_per_sector() as usize;
+
+        let mut curr_sector = self.data_start_sector + (cluster.index() * self.sectors_per_cluster as u64) + sector_index as u64;
+
+        let mut total_size = 0;
+        while total_size < max_size {
+            let content = self.device.get_mut(curr_sector)?;
+
+            let left_in_sector = self.bytes_per_sector as usize - sector_offset;
+            let size = if max_size - total_size > left_in_sector {
+                left_in_sector
+            } else {
+                max_size - total_size
+            };
+
+            content[sector_offset..sector_offset + size].copy_from_slice(&buf[total_size..total_size + size]);
+
+            // Only offset on first copy
+            sector_offset = 0;
+
+            total_size += size;
+            curr_sector += 1;
+        }
+
+        return Ok(total_size);
+    }
+
     /// A method to read all of the clusters chained from a starting cluster
     /// into a vector.
     pub fn read_chain(&mut self, start: Cluster, buf: &mut Vec<u8>) -> io::Result<usize> {
@@ -160,6 +210,158 @@ impl<HANDLE: VFatHandle> VFat<HANDLE> {
         return Ok(&entries[fat_offset as usize]);
     }
 
+    /// Sets the `FatEntry` for `cluster` to `status` in every copy of the FAT.
+    fn set_fat_entry(&mut self, cluster: Cluster, status: Status) -> io::Result<()> {
+        let entries_per_fat = self.bytes_per_sector as u64 / size_of::<FatEntry>() as u64;
+        let fat_index = cluster.num() / entries_per_fat;
+        let fat_offset = cluster.num() % entries_per_fat;
+
+        for i in 0..self.num_fats as u64 {
+            let sector = self.fat_start_sector + i * self.sectors_per_fat as u64 + fat_index;
+            let data = self.device.get_mut(sector)?;
+            let entries: &mut [FatEntry] = unsafe { data.cast_mut() };
+            entries[fat_offset as usize].set_status(status);
+        }
+
+        return Ok(());
+    }
+
+    /// Allocates a free cluster, marks it as the end of a chain and zeroes
+    /// its contents. If `prev` is `Some`, the new cluster is linked after it.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `Other` if there are no free clusters left.
+    pub fn alloc_cluster(&mut self, prev: Option<Cluster>) -> io::Result<Cluster> {
+        let mut found: Option<Cluster> = None;
+
+        // Search from the hint first, then wrap around to the start
+        for i in 0..self.num_clusters - 2 {
+            let num = 2 + (self.next_free - 2 + i) % (self.num_clusters - 2);
+            let cluster = Cluster::from(num as u32);
+            if self.fat_entry(cluster)?.status() == Status::Free {
+                found = Some(cluster);
+                break;
+            }
+        }
+
+        let cluster = match found {
+            Some(cluster) => cluster,
+            None => return Err(io::Error::new(io::ErrorKind::Other, "No free clusters")),
+        };
+
+        self.set_fat_entry(cluster, Status::Eoc(0xFFFFFFF))?;
+        if let Some(prev) = prev {
+            self.set_fat_entry(prev, Status::Data(cluster))?;
+        }
+
+        let zeroes: Vec<u8> = vec![0; self.cluster_size as usize];
+        self.write_cluster(cluster, 0, &zeroes)?;
+
+        self.next_free = cluster.num() + 1;
+
+        return Ok(cluster);
+    }
+
+    /// Marks `cluster` as the end of its chain and frees every cluster that
+    /// followed it.
+    pub fn truncate_chain(&mut self, cluster: Cluster) -> io::Result<()> {
+        let next = self.next_cluster(cluster);
+        self.set_fat_entry(cluster, Status::Eoc(0xFFFFFFF))?;
+
+        match next {
+            Some(next) => self.free_chain(next),
+            None => Ok(()),
+        }
+    }
+
+    /// Frees every cluster in the chain starting at `start`.
+    pub fn free_chain(&mut self, start: Cluster) -> io::Result<()> {
+        let mut curr = Some(start);
+
+        while let Some(cluster) = curr {
+            curr = self.next_cluster(cluster);
+            self.set_fat_entry(cluster, Status::Free)?;
+
+            if cluster.num() < self.next_free {
+                self.next_free = cluster.num();
+            }
+        }
+
+        return Ok(());
+    }
+
+    /// Returns the cluster holding byte `offset` of the chain starting at
+    /// `start` along with the offset into that cluster. Returns `None` if the
+    /// chain ends before `offset`.
+    pub fn locate(&mut self, start: Cluster, offset: u64) -> Option<(Cluster, usize)> {
+        let mut curr = start;
+
+        for _ in 0..offset / self.cluster_size {
+            curr = self.next_cluster(curr)?;
+        }
+
+        return Some((curr, (offset % self.cluster_size) as usize));
+    }
+
+    /// Reads the raw directory entry at `location`.
+    pub(crate) fn read_dir_entry(&mut self, location: EntryLocation) -> io::Result<VFatDirEntry> {
+        let offset = (location.index * size_of::<VFatDirEntry>()) as u64;
+        let (cluster, offset) = match self.locate(location.dir, offset) {
+            Some(found) => found,
+            None => return Err(io::Error::new(io::ErrorKind::NotFound, "Directory entry out of range")),
+        };
+
+        let mut buf = [0u8; 32];
+        self.read_cluster(cluster, offset, &mut buf)?;
+
+        return Ok(unsafe { core::mem::transmute(buf) });
+    }
+
+    /// Overwrites the raw directory entry at `location` with `entry`.
+    pub(crate) fn write_dir_entry(&mut self, location: EntryLocation, entry: &VFatDirEntry) -> io::Result<()> {
+        let offset = (location.index * size_of::<VFatDirEntry>()) as u64;
+        let (cluster, offset) = match self.locate(location.dir, offset) {
+            Some(found) => found,
+            None => return Err(io::Error::new(io::ErrorKind::NotFound, "Directory entry out of range")),
+        };
+
+        let buf: [u8; 32] = unsafe { core::mem::transmute(*entry) };
+        self.write_cluster(cluster, offset, &buf)?;
+
+        return Ok(());
+    }
+
+    /// Writes every dirty sector back to the disk.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.device.flush()
+    }
+
+    /// Returns the sector cache's hit, miss, eviction and writeback counters.
+    pub fn cache_stats(&self) -> CacheStats {
+        return self.device.stats();
+    }
+
+    /// Sets the maximum number of sectors held in the sector cache. Dirty
+    /// sectors that no longer fit are written back.
+    pub fn set_cache_capacity(&mut self, capacity: usize) -> io::Result<()> {
+        self.device.set_capacity(capacity)
+    }
+
+    /// Sets the function used to timestamp modified entries.
+    pub fn set_clock(&mut self, clock: fn() -> Timestamp) {
+        self.clock = Some(clock);
+    }
+
+    /// Returns the current time according to the clock set with
+    /// `set_clock()`, or a zeroed `Timestamp` if no clock was set.
+    pub fn now(&self) -> Timestamp {
+        match self.clock {
+            Some(clock) => clock(),
+            None => Timestamp::default(),
+        }
+    }
+
     /// Get the next cluster for a cluster, returns None if EOC
     pub fn next_cluster(&mut self, cluster: Cluster) -> Option<Cluster> {
         let fat_entry = self.fat_entry(cluster).expect("Expected valid fat entry");
@@ -180,6 +382,11 @@ impl<HANDLE: VFatHandle> VFat<HANDLE> {
     pub fn cluster_size(&self) -> u64 {
         return self.cluster_size as u64;
     }
+
+    /// Getter for the first cluster of the root directory
+    pub fn root_cluster(&self) -> Cluster {
+        return self.rootdir_cluster;
+    }
 }
 
 impl<'a, HANDLE: VFatHandle> FileSystem for &'a HANDLE {
@@ -219,4 +426,41 @@ impl<'a, HANDLE: VFatHandle> FileSystem for &'a HANDLE {
 
         return Ok(curr_entry);
     }
+
+    fn create_file<P: AsRef<Path>>(self, path: P) -> io::Result<Self::File> {
+        let (parent, name) = split_path(self, path.as_ref())?;
+        return parent.create_file(name);
+    }
+
+    fn create_dir<P: AsRef<Path>>(self, path: P) -> io::Result<Self::Dir> {
+        let (parent, name) = split_path(self, path.as_ref())?;
+        return parent.create_dir(name);
+    }
+
+    fn remove<P: AsRef<Path>>(self, path: P) -> io::Result<()> {
+        let (parent, name) = split_path(self, path.as_ref())?;
+        return parent.remove(name);
+    }
+
+    fn rename<P: AsRef<Path>, Q: AsRef<Path>>(self, from: P, to: Q) -> io::Result<()> {
+        let (from_parent, from_name) = split_path(self, from.as_ref())?;
+        let (to_parent, to_name) = split_path(self, to.as_ref())?;
+        return from_parent.rename(from_name, &to_parent, to_name);
+    }
+}
+
+/// Splits `path` into its parent directory, which is opened, and its last
+/// component.
+fn split_path<'p, HANDLE: VFatHandle>(handle: &HANDLE, path: &'p Path) -> io::Result<(Dir<HANDLE>, &'p OsStr)> {
+    let name = match path.file_name() {
+        Some(name) => name,
+        None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "Path has no file name")),
+    };
+
+    let parent = match path.parent() {
+        Some(parent) => parent,
+        None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "Path has no parent")),
+    };
+
+    return Ok((handle.open_dir(parent)?, name));
 }
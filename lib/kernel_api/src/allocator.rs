@@ -0,0 +1,118 @@
+use core::alloc::{GlobalAlloc, Layout};
+use core::cell::UnsafeCell;
+use core::ptr;
+
+use crate::syscall::sbrk;
+
+/// The smallest amount of memory requested from the kernel at once.
+const GROW_SIZE: usize = 64 * 1024;
+
+/// The number of size classes: blocks of 2^3 up to 2^(3 + BINS - 1) bytes.
+const BINS: usize = 40;
+
+/// A user-space heap allocator based on size classes, in the spirit of the
+/// kernel's bin allocator. Block sizes are powers of two; free blocks are
+/// kept in one intrusive list per size and are never merged. New blocks are
+/// carved out of memory obtained by growing the heap with `sbrk`.
+///
+/// User programs are single-threaded, so no locking is done.
+pub struct Allocator(UnsafeCell<Heap>);
+
+struct Heap {
+    bins: [*mut usize; BINS],
+    free_start: usize,
+    free_end: usize,
+}
+
+unsafe impl Sync for Allocator {}
+
+/// Maps an allocation of `layout` to the bin of the smallest block that
+/// can hold it with the right alignment.
+fn map_to_bin(layout: &Layout) -> usize {
+    let size = core::cmp::max(layout.size(), layout.align());
+    let size = core::cmp::max(size, 8).next_power_of_two();
+    return size.trailing_zeros() as usize - 3;
+}
+
+/// Maps a bin index to the size of its blocks.
+fn map_to_size(bin: usize) -> usize {
+    return 1 << (bin + 3);
+}
+
+impl Allocator {
+    /// Returns an allocator with an empty heap.
+    pub const fn new() -> Allocator {
+        Allocator(UnsafeCell::new(Heap {
+            bins: [ptr::null_mut(); BINS],
+            free_start: 0,
+            free_end: 0,
+        }))
+    }
+}
+
+impl Heap {
+    /// Carves a block of `size` bytes, aligned to `size`, out of the unused
+    /// end of the heap, growing the heap if needed.
+    unsafe fn carve(&mut self, size: usize) -> *mut u8 {
+        let mut start = (self.free_start + size - 1) & !(size - 1);
+
+        if start.checked_add(size).map_or(true, |end| end > self.free_end) {
+            let increment = core::cmp::max(size * 2, GROW_SIZE);
+            let old = match sbrk(increment as isize) {
+                Ok(old) => old as usize,
+                Err(_) => return ptr::null_mut(),
+            };
+
+            // Someone else may have moved the break in the meantime
+            if old != self.free_end {
+                self.free_start = old;
+            }
+            self.free_end = old + increment;
+
+            start = (self.free_start + size - 1) & !(size - 1);
+        }
+
+        self.free_start = start + size;
+        return start as *mut u8;
+    }
+}
+
+unsafe impl GlobalAlloc for Allocator {
+    /// Allocates a block from the bin matching `layout`, or a new one from
+    /// the heap if the bin is empty. Returns a null pointer if the kernel
+    /// refuses to grow the heap.
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let heap = &mut *self.0.get();
+        let bin = map_to_bin(&layout);
+        if bin >= BINS {
+            return ptr::null_mut();
+        }
+
+        let block = heap.bins[bin];
+        if !block.is_null() {
+            heap.bins[bin] = *block as *mut usize;
+            return block as *mut u8;
+        }
+
+        return heap.carve(map_to_size(bin));
+    }
+
+    /// Returns the block at `ptr` to the bin it was allocated from.
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let heap = &mut *self.0.get();
+        let bin = map_to_bin(&layout);
+        let block = ptr as *mut usize;
+
+        *block = heap.bins[bin] as usize;
+        heap.bins[bin] = block;
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Allocator = Allocator::new();
+
+#[alloc_error_handler]
+fn alloc_error(layout: Layout) -> ! {
+    crate::println!("Out of memory allocating {} bytes", layout.size());
+    crate::syscall::exit(-1);
+}
@@ -1,6 +1,10 @@
 #![feature(asm)]
+#![cfg_attr(feature = "allocator", feature(alloc_error_handler))]
 #![no_std]
 
+#[cfg(feature = "allocator")]
+extern crate alloc;
+
 use core::fmt;
 
 use shim::io;
@@ -8,6 +12,9 @@ use shim::io;
 #[cfg(feature = "user-space")]
 pub mod syscall;
 
+#[cfg(feature = "allocator")]
+pub mod allocator;
+
 pub type OsResult<T> = core::result::Result<T, OsError>;
 
 #[derive(Copy, Clone, Debug, PartialEq)]
@@ -22,6 +29,9 @@ pub enum OsError {
     BadAddress = 50,
     FileExists = 60,
     InvalidArgument = 70,
+    BadDescriptor = 80,
+    TooManyFiles = 81,
+    Oversubscribed = 90,
 
     IoError = 101,
     IoErrorEof = 102,
@@ -46,11 +56,15 @@ impl core::convert::From<u64> for OsError {
             50 => OsError::BadAddress,
             60 => OsError::FileExists,
             70 => OsError::InvalidArgument,
+            80 => OsError::BadDescriptor,
+            81 => OsError::TooManyFiles,
+            90 => OsError::Oversubscribed,
 
             101 => OsError::IoError,
             102 => OsError::IoErrorEof,
             103 => OsError::IoErrorInvalidData,
             104 => OsError::IoErrorInvalidInput,
+            105 => OsError::IoErrorTimedOut,
 
             200 => OsError::InvalidSocket,
             201 => OsError::SocketAlreadyOpen,
@@ -69,6 +83,7 @@ impl core::convert::From<io::Error> for OsError {
             io::ErrorKind::InvalidInput => OsError::IoErrorInvalidInput,
             io::ErrorKind::TimedOut => OsError::IoErrorTimedOut,
             io::ErrorKind::NotFound => OsError::NoEntry,
+            io::ErrorKind::AlreadyExists => OsError::FileExists,
             _ => OsError::IoError,
         }
     }
@@ -79,3 +94,57 @@ pub const NR_TIME: usize = 2;
 pub const NR_EXIT: usize = 3;
 pub const NR_WRITE: usize = 4;
 pub const NR_GETPID: usize = 5;
+pub const NR_OPEN: usize = 6;
+pub const NR_CLOSE: usize = 7;
+pub const NR_READ: usize = 8;
+pub const NR_WRITE_FD: usize = 9;
+pub const NR_SEEK: usize = 10;
+pub const NR_STAT: usize = 11;
+pub const NR_FORK: usize = 12;
+pub const NR_EXEC: usize = 13;
+pub const NR_WAIT: usize = 14;
+pub const NR_BRK: usize = 15;
+pub const NR_MMAP: usize = 16;
+pub const NR_MUNMAP: usize = 17;
+pub const NR_MPROTECT: usize = 18;
+pub const NR_MMAP_FILE: usize = 19;
+pub const NR_MSYNC: usize = 20;
+pub const NR_GETPRIORITY: usize = 21;
+pub const NR_SETPRIORITY: usize = 22;
+pub const NR_SET_REALTIME: usize = 23;
+
+/// The maximum number of arguments that can be passed to `exec`.
+pub const MAX_ARGS: usize = 16;
+
+/// Flag for `open`: create the file if it does not exist.
+pub const OPEN_CREATE: u64 = 1 << 0;
+/// Flag for `open`: truncate the file to zero length once opened.
+pub const OPEN_TRUNCATE: u64 = 1 << 1;
+
+/// Protection flags of `mmap` and `mprotect`. `PROT_READ` must always be set.
+pub const PROT_READ: u64 = 1 << 0;
+pub const PROT_WRITE: u64 = 1 << 1;
+pub const PROT_EXEC: u64 = 1 << 2;
+
+/// Scheduling priorities of `getpriority` and `setpriority`. Processes with
+/// a lower value run first; new processes start at `PRIORITY_DEFAULT`.
+pub const PRIORITY_HIGHEST: u64 = 0;
+pub const PRIORITY_DEFAULT: u64 = 4;
+pub const PRIORITY_LOWEST: u64 = 7;
+
+/// `whence` values of the `seek` system call.
+pub const SEEK_START: u64 = 0;
+pub const SEEK_CURRENT: u64 = 1;
+pub const SEEK_END: u64 = 2;
+
+/// File information filled in by the `stat` system call.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Stat {
+    /// The size of the file in bytes, or zero for a directory.
+    pub size: u64,
+    /// Whether the entry is a directory.
+    pub directory: bool,
+    /// Whether the entry is read-only.
+    pub read_only: bool,
+}
@@ -2,6 +2,8 @@ use core::fmt;
 use core::fmt::Write;
 use core::time::Duration;
 
+use shim::io;
+
 use crate::*;
 
 macro_rules! err_or {
@@ -56,12 +58,13 @@ pub fn time() -> Duration {
     return Duration::new(secs, millis * 10000);
 }
 
-pub fn exit() -> ! {
+pub fn exit(code: i32) -> ! {
     unsafe {
-        asm!("svc $0"
-             : 
-             : "i"(NR_EXIT)
+        asm!("mov x0, $0
+              svc $1"
              : 
+             : "r"(code as i64), "i"(NR_EXIT)
+             : "x0"
              : "volatile");
     }
 
@@ -97,6 +100,313 @@ pub fn getpid() -> u64 {
     return pid;
 }
 
+/// Returns the scheduling priority of the process `pid`, or of the calling
+/// process if `pid` is 0.
+pub fn getpriority(pid: u64) -> OsResult<u64> {
+    let mut priority: u64;
+    let mut ecode: u64;
+
+    unsafe {
+        asm!("mov x0, $2
+              svc $3
+              mov $0, x0
+              mov $1, x7"
+             : "=r"(priority), "=r"(ecode)
+             : "r"(pid), "i"(NR_GETPRIORITY)
+             : "x0", "x7"
+             : "volatile");
+    }
+
+    err_or!(ecode, priority)
+}
+
+/// Sets the scheduling priority of the calling process, if `pid` is 0 or
+/// its ID, or of one of its children to `priority`, between
+/// `PRIORITY_HIGHEST` and `PRIORITY_LOWEST`. Returns `NoAccess` if that
+/// would raise the priority above the parent's.
+pub fn setpriority(pid: u64, priority: u64) -> OsResult<()> {
+    let mut ecode: u64;
+
+    unsafe {
+        asm!("mov x0, $1
+              mov x1, $2
+              svc $3
+              mov $0, x7"
+             : "=r"(ecode)
+             : "r"(pid), "r"(priority), "i"(NR_SETPRIORITY)
+             : "x0", "x1", "x7"
+             : "volatile");
+    }
+
+    err_or!(ecode, ())
+}
+
+/// Makes the calling process a real-time process that is guaranteed `budget`
+/// of CPU time in every `period`, ahead of all other processes, or a normal
+/// process again if `period` is zero. Fails with `Oversubscribed` if the
+/// real-time processes would reserve too much of the CPU.
+pub fn set_realtime(period: Duration, budget: Duration) -> OsResult<()> {
+    let mut ecode: u64;
+
+    unsafe {
+        asm!("mov x0, $1
+              mov x1, $2
+              svc $3
+              mov $0, x7"
+             : "=r"(ecode)
+             : "r"(period.as_micros() as u64), "r"(budget.as_micros() as u64), "i"(NR_SET_REALTIME)
+             : "x0", "x1", "x7"
+             : "volatile");
+    }
+
+    err_or!(ecode, ())
+}
+
+pub fn open(path: &str, flags: u64) -> OsResult<u64> {
+    let mut fd: u64;
+    let mut ecode: u64;
+
+    unsafe {
+        asm!("mov x0, $2
+              mov x1, $3
+              mov x2, $4
+              svc $5
+              mov $0, x0
+              mov $1, x7"
+             : "=r"(fd), "=r"(ecode)
+             : "r"(path.as_ptr()), "r"(path.len()), "r"(flags), "i"(NR_OPEN)
+             : "x0", "x1", "x2", "x7"
+             : "volatile");
+    }
+
+    err_or!(ecode, fd)
+}
+
+pub fn close(fd: u64) -> OsResult<()> {
+    let mut ecode: u64;
+
+    unsafe {
+        asm!("mov x0, $1
+              svc $2
+              mov $0, x7"
+             : "=r"(ecode)
+             : "r"(fd), "i"(NR_CLOSE)
+             : "x0", "x7"
+             : "volatile");
+    }
+
+    err_or!(ecode, ())
+}
+
+pub fn read(fd: u64, buf: &mut [u8]) -> OsResult<usize> {
+    let mut count: u64;
+    let mut ecode: u64;
+
+    unsafe {
+        asm!("mov x0, $2
+              mov x1, $3
+              mov x2, $4
+              svc $5
+              mov $0, x0
+              mov $1, x7"
+             : "=r"(count), "=r"(ecode)
+             : "r"(fd), "r"(buf.as_mut_ptr()), "r"(buf.len()), "i"(NR_READ)
+             : "x0", "x1", "x2", "x7", "memory"
+             : "volatile");
+    }
+
+    err_or!(ecode, count as usize)
+}
+
+pub fn write_fd(fd: u64, buf: &[u8]) -> OsResult<usize> {
+    let mut count: u64;
+    let mut ecode: u64;
+
+    unsafe {
+        asm!("mov x0, $2
+              mov x1, $3
+              mov x2, $4
+              svc $5
+              mov $0, x0
+              mov $1, x7"
+             : "=r"(count), "=r"(ecode)
+             : "r"(fd), "r"(buf.as_ptr()), "r"(buf.len()), "i"(NR_WRITE_FD)
+             : "x0", "x1", "x2", "x7"
+             : "volatile");
+    }
+
+    err_or!(ecode, count as usize)
+}
+
+pub fn seek(fd: u64, pos: io::SeekFrom) -> OsResult<u64> {
+    let (whence, offset) = match pos {
+        io::SeekFrom::Start(offset) => (SEEK_START, offset as i64),
+        io::SeekFrom::Current(offset) => (SEEK_CURRENT, offset),
+        io::SeekFrom::End(offset) => (SEEK_END, offset),
+    };
+    let mut position: u64;
+    let mut ecode: u64;
+
+    unsafe {
+        asm!("mov x0, $2
+              mov x1, $3
+              mov x2, $4
+              svc $5
+              mov $0, x0
+              mov $1, x7"
+             : "=r"(position), "=r"(ecode)
+             : "r"(fd), "r"(whence), "r"(offset), "i"(NR_SEEK)
+             : "x0", "x1", "x2", "x7"
+             : "volatile");
+    }
+
+    err_or!(ecode, position)
+}
+
+pub fn stat(path: &str) -> OsResult<Stat> {
+    let mut stat = Stat::default();
+    let mut ecode: u64;
+
+    unsafe {
+        asm!("mov x0, $1
+              mov x1, $2
+              mov x2, $3
+              svc $4
+              mov $0, x7"
+             : "=r"(ecode)
+             : "r"(path.as_ptr()), "r"(path.len()), "r"(&mut stat as *mut Stat), "i"(NR_STAT)
+             : "x0", "x1", "x2", "x7", "memory"
+             : "volatile");
+    }
+
+    err_or!(ecode, stat)
+}
+
+/// Creates a copy of the calling process. Returns the child's process ID in
+/// the parent and zero in the child. The child inherits the parent's
+/// scheduling priority but not its real-time reservation.
+pub fn fork() -> OsResult<u64> {
+    let mut pid: u64;
+    let mut ecode: u64;
+
+    unsafe {
+        asm!("svc $2
+              mov $0, x0
+              mov $1, x7"
+             : "=r"(pid), "=r"(ecode)
+             : "i"(NR_FORK)
+             : "x0", "x7", "memory"
+             : "volatile");
+    }
+
+    err_or!(ecode, pid)
+}
+
+/// Replaces the program of the calling process with the one at `path`,
+/// passing it `args`. Only returns if that fails, with the reason.
+pub fn exec(path: &str, args: &[&str]) -> OsError {
+    if args.len() > MAX_ARGS {
+        return OsError::InvalidArgument;
+    }
+
+    let mut argv = [[0u64; 2]; MAX_ARGS];
+    for (pair, arg) in argv.iter_mut().zip(args) {
+        *pair = [arg.as_ptr() as u64, arg.len() as u64];
+    }
+
+    let mut ecode: u64;
+
+    unsafe {
+        asm!("mov x0, $1
+              mov x1, $2
+              mov x2, $3
+              mov x3, $4
+              svc $5
+              mov $0, x7"
+             : "=r"(ecode)
+             : "r"(path.as_ptr()), "r"(path.len()), "r"(argv.as_ptr()), "r"(args.len()),
+               "i"(NR_EXEC)
+             : "x0", "x1", "x2", "x3", "x7", "memory"
+             : "volatile");
+    }
+
+    OsError::from(ecode)
+}
+
+/// Waits for the child process `pid` to exit and returns its exit code.
+pub fn wait(pid: u64) -> OsResult<i32> {
+    let mut code: u64;
+    let mut ecode: u64;
+
+    unsafe {
+        asm!("mov x0, $2
+              svc $3
+              mov $0, x0
+              mov $1, x7"
+             : "=r"(code), "=r"(ecode)
+             : "r"(pid), "i"(NR_WAIT)
+             : "x0", "x7"
+             : "volatile");
+    }
+
+    err_or!(ecode, code as i32)
+}
+
+/// Sets the end of the heap (the break) to `addr`, or leaves it unchanged if
+/// `addr` is zero. Returns the break after the call.
+pub fn brk(addr: usize) -> OsResult<usize> {
+    let mut end: u64;
+    let mut ecode: u64;
+
+    unsafe {
+        asm!("mov x0, $2
+              svc $3
+              mov $0, x0
+              mov $1, x7"
+             : "=r"(end), "=r"(ecode)
+             : "r"(addr), "i"(NR_BRK)
+             : "x0", "x7"
+             : "volatile");
+    }
+
+    err_or!(ecode, end as usize)
+}
+
+/// Moves the break by `increment` bytes and returns its previous value, the
+/// start of the newly added memory when growing.
+pub fn sbrk(increment: isize) -> OsResult<*mut u8> {
+    let old = brk(0)?;
+    if increment != 0 {
+        let new = if increment > 0 {
+            old.checked_add(increment as usize)
+        } else {
+            old.checked_sub(increment.wrapping_neg() as usize)
+        };
+        brk(new.ok_or(OsError::NoVmSpace)?)?;
+    }
+
+    Ok(old as *mut u8)
+}
+
+static mut ARGC: usize = 0;
+static mut ARGV: *const [u64; 2] = core::ptr::null();
+
+/// Records the arguments the kernel passed to the program in `x0` and `x1`.
+/// Called once by the program's entry point before `main`.
+pub unsafe fn init_args(argc: usize, argv: *const [u64; 2]) {
+    ARGC = argc;
+    ARGV = argv;
+}
+
+/// Returns the arguments the program was started with by `exec`.
+pub fn args() -> impl Iterator<Item = &'static str> {
+    (0..unsafe { ARGC }).map(|i| unsafe {
+        let [address, len] = *ARGV.add(i);
+        let bytes = core::slice::from_raw_parts(address as *const u8, len as usize);
+        core::str::from_utf8_unchecked(bytes)
+    })
+}
+
 
 struct Console;
 
@@ -127,3 +437,110 @@ pub fn vprint(args: fmt::Arguments) {
     let mut c = Console;
     c.write_fmt(args).unwrap();
 }
+
+/// Maps `len` bytes of zero-filled memory with the `PROT_*` protection `prot`
+/// at `addr`, or wherever the kernel sees fit if `addr` is null, and returns
+/// the start of the mapping.
+pub fn mmap(addr: *mut u8, len: usize, prot: u64) -> OsResult<*mut u8> {
+    let mut start: u64;
+    let mut ecode: u64;
+
+    unsafe {
+        asm!("mov x0, $2
+              mov x1, $3
+              mov x2, $4
+              svc $5
+              mov $0, x0
+              mov $1, x7"
+             : "=r"(start), "=r"(ecode)
+             : "r"(addr), "r"(len), "r"(prot), "i"(NR_MMAP)
+             : "x0", "x1", "x2", "x7"
+             : "volatile");
+    }
+
+    err_or!(ecode, start as *mut u8)
+}
+
+/// Maps `len` bytes of the open file `fd` from the page-aligned `offset` on
+/// with the `PROT_*` protection `prot` at `addr`, or wherever the kernel sees
+/// fit if `addr` is null, and returns the start of the mapping. Writes to a
+/// writable mapping reach the file on `msync` or `munmap`.
+pub fn mmap_file(addr: *mut u8, len: usize, prot: u64, fd: u64, offset: u64) -> OsResult<*mut u8> {
+    let mut start: u64;
+    let mut ecode: u64;
+
+    unsafe {
+        asm!("mov x0, $2
+              mov x1, $3
+              mov x2, $4
+              mov x3, $5
+              mov x4, $6
+              svc $7
+              mov $0, x0
+              mov $1, x7"
+             : "=r"(start), "=r"(ecode)
+             : "r"(addr), "r"(len), "r"(prot), "r"(fd), "r"(offset), "i"(NR_MMAP_FILE)
+             : "x0", "x1", "x2", "x3", "x4", "x7"
+             : "volatile");
+    }
+
+    err_or!(ecode, start as *mut u8)
+}
+
+/// Writes the modified pages of the file mappings in the `len` bytes at
+/// `addr` back to their files.
+pub fn msync(addr: *mut u8, len: usize) -> OsResult<()> {
+    let mut ecode: u64;
+
+    unsafe {
+        asm!("mov x0, $1
+              mov x1, $2
+              svc $3
+              mov $0, x7"
+             : "=r"(ecode)
+             : "r"(addr), "r"(len), "i"(NR_MSYNC)
+             : "x0", "x1", "x7"
+             : "volatile");
+    }
+
+    err_or!(ecode, ())
+}
+
+/// Unmaps the memory mapped with `mmap` or `mmap_file` in the `len` bytes at
+/// `addr`, writing modified file pages back first.
+pub fn munmap(addr: *mut u8, len: usize) -> OsResult<()> {
+    let mut ecode: u64;
+
+    unsafe {
+        asm!("mov x0, $1
+              mov x1, $2
+              svc $3
+              mov $0, x7"
+             : "=r"(ecode)
+             : "r"(addr), "r"(len), "i"(NR_MUNMAP)
+             : "x0", "x1", "x7", "memory"
+             : "volatile");
+    }
+
+    err_or!(ecode, ())
+}
+
+/// Changes the protection of the `len` bytes of mapped memory at `addr`
+/// to the `PROT_*` flags `prot`.
+pub fn mprotect(addr: *mut u8, len: usize, prot: u64) -> OsResult<()> {
+    let mut ecode: u64;
+
+    unsafe {
+        asm!("mov x0, $1
+              mov x1, $2
+              mov x2, $3
+              svc $4
+              mov $0, x7"
+             : "=r"(ecode)
+             : "r"(addr), "r"(len), "r"(prot), "i"(NR_MPROTECT)
+             : "x0", "x1", "x2", "x7", "memory"
+             : "volatile");
+    }
+
+    err_or!(ecode, ())
+}
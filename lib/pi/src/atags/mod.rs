@@ -4,7 +4,7 @@ mod raw;
 pub use self::atag::*;
 
 /// The address at which the firmware loads the ATAGS.
-const ATAG_BASE: usize = 0x100;
+pub const ATAG_BASE: usize = 0x100;
 
 /// An iterator over the ATAGS on this system.
 pub struct Atags {
@@ -18,6 +18,21 @@ impl Atags {
       ptr: Some(unsafe { &*(ATAG_BASE as *const raw::Atag) }),
     }
   }
+
+  /// Returns the address just past the `NONE` ATAG ending the remaining
+  /// ATAGS, or 0 if there are none left.
+  pub fn end(&self) -> usize {
+    let mut atag = match self.ptr {
+      Some(atag) => atag,
+      None => { return 0; }
+    };
+
+    while let Some(next) = atag.next() {
+      atag = next;
+    }
+
+    return atag as *const raw::Atag as usize + atag.dwords as usize * 4;
+  }
 }
 
 impl Iterator for Atags {
@@ -87,6 +102,9 @@ mod test {
       ptr: Some(unsafe { &*(&MEM as *const u32 as *const raw::Atag) }),
     };
 
+    let end = &MEM as *const u32 as usize + MEM.len() * 4;
+    assert_eq!(atags.end(), end);
+
     assert_eq!(
       atags.next(),
       Some(Atag::Core(raw::Core {
@@ -115,5 +133,6 @@ mod test {
     assert_eq!(atags.next(), None);
     assert_eq!(atags.next(), None);
     assert_eq!(atags.next(), None);
+    assert_eq!(atags.end(), 0);
   }
 }
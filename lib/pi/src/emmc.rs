@@ -0,0 +1,485 @@
+use core::time::Duration;
+
+use shim::const_assert_size;
+
+use volatile::prelude::*;
+use volatile::{ReadVolatile, Reserved, Volatile};
+
+use crate::common::IO_BASE;
+use crate::gpio::{Function, Gpio};
+use crate::timer;
+
+/// The base address for the EMMC (SD host controller) registers.
+const EMMC_REG_BASE: usize = IO_BASE + 0x300000;
+
+/// The size, in bytes, of a block transferred to or from the card.
+pub const BLOCK_SIZE: usize = 512;
+
+/// The largest number of blocks moved by a single transfer.
+pub const MAX_BLOCKS: usize = 0xFFFF;
+
+/// How long to wait for the controller or the card before giving up.
+const TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The frequency of the controller's base clock, in Hz.
+const BASE_CLOCK: u32 = 41666666;
+
+/// The SD clock frequency used during card identification, in Hz.
+const IDENTIFICATION_CLOCK: u32 = 400000;
+
+/// The SD clock frequency used for data transfers, in Hz.
+const TRANSFER_CLOCK: u32 = 25000000;
+
+/// `SLOTISR_VER` value of a host controller implementing SDHCI 2.00.
+const HOST_SPEC_V2: u32 = 1;
+
+/// Bit fields of the `STATUS` register.
+#[repr(u32)]
+enum Status {
+    CmdInhibit = 1 << 0,
+    DatInhibit = 1 << 1,
+}
+
+/// Bit fields of the `CONTROL0` register.
+#[repr(u32)]
+enum Control0 {
+    BusWidth4 = 1 << 1,
+}
+
+/// Bit fields of the `CONTROL1` register.
+#[repr(u32)]
+enum Control1 {
+    ClockInternalEnable = 1 << 0,
+    ClockStable = 1 << 1,
+    ClockEnable = 1 << 2,
+    DataTimeoutMax = 0xE << 16,
+    ResetHost = 1 << 24,
+}
+
+/// Bit fields of the `INTERRUPT` register.
+#[repr(u32)]
+enum Interrupt {
+    CmdDone = 1 << 0,
+    DataDone = 1 << 1,
+    WriteReady = 1 << 4,
+    ReadReady = 1 << 5,
+    CmdTimeout = 1 << 16,
+    DataTimeout = 1 << 20,
+    Errors = 0x017E8000,
+}
+
+/// Bits of an R1 card status response that indicate an error.
+const R1_ERRORS_MASK: u32 = 0xFFF9C004;
+
+/// R1 card status bit set when the card expects an application command.
+const R1_APP_CMD: u32 = 1 << 5;
+
+/// Bits of an R6 response that indicate an error.
+const R6_ERRORS_MASK: u32 = 0xE000;
+
+/// `CMD8` argument: 2.7-3.6V and a check pattern echoed back by the card.
+const IF_COND_ARG: u32 = 0x1AA;
+
+/// `ACMD41` argument: high capacity support and the 3.0-3.6V window.
+const OP_COND_ARG: u32 = 0x51FF8000;
+
+/// `ACMD41` response bits: initialization complete and high capacity card.
+const OP_COND_READY: u32 = 1 << 31;
+const OP_COND_CCS: u32 = 1 << 30;
+
+/// `SCR` bit set if the card supports the 4 bit data bus.
+const SCR_BUS_WIDTH_4: u32 = 1 << 10;
+
+/// `CMDTM` values: the command index in the top byte, followed by the
+/// response type, data and multi-block transfer flags. Application commands
+/// must be preceded by `AppCmd`.
+#[repr(u32)]
+#[derive(Copy, Clone, PartialEq)]
+enum Command {
+    GoIdle = 0x00000000,
+    AllSendCid = 0x02010000,
+    SendRelativeAddr = 0x03020000,
+    SetBusWidth = 0x06020000,
+    SelectCard = 0x07030000,
+    SendIfCond = 0x08020000,
+    StopTransmission = 0x0C030000,
+    ReadSingleBlock = 0x11220010,
+    ReadMultipleBlock = 0x12220032,
+    WriteSingleBlock = 0x18220000,
+    WriteMultipleBlock = 0x19220022,
+    SendOpCond = 0x29020000,
+    SendScr = 0x33220010,
+    AppCmd = 0x37000000,
+    AppCmdRca = 0x37020000,
+}
+
+/// Errors reported by the EMMC controller.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Error {
+    /// The controller or the card did not respond in time.
+    Timeout,
+    /// A command failed or the card reported an error.
+    Command,
+}
+
+#[repr(C)]
+#[allow(non_snake_case)]
+struct Registers {
+    ARG2: Volatile<u32>,
+    BLKSIZECNT: Volatile<u32>,
+    ARG1: Volatile<u32>,
+    CMDTM: Volatile<u32>,
+    RESP: [ReadVolatile<u32>; 4],
+    DATA: Volatile<u32>,
+    STATUS: ReadVolatile<u32>,
+    CONTROL0: Volatile<u32>,
+    CONTROL1: Volatile<u32>,
+    INTERRUPT: Volatile<u32>,
+    IRPT_MASK: Volatile<u32>,
+    IRPT_EN: Volatile<u32>,
+    CONTROL2: Volatile<u32>,
+    _reserved0: [Reserved<u32>; 4],
+    FORCE_IRPT: Volatile<u32>,
+    _reserved1: [Reserved<u32>; 7],
+    BOOT_TIMEOUT: Volatile<u32>,
+    DBG_SEL: Volatile<u32>,
+    _reserved2: [Reserved<u32>; 2],
+    EXRDFIFO_CFG: Volatile<u32>,
+    EXRDFIFO_EN: Volatile<u32>,
+    TUNE_STEP: Volatile<u32>,
+    TUNE_STEPS_STD: Volatile<u32>,
+    TUNE_STEPS_DDR: Volatile<u32>,
+    _reserved3: [Reserved<u32>; 23],
+    SPI_INT_SPT: Volatile<u32>,
+    _reserved4: [Reserved<u32>; 2],
+    SLOTISR_VER: ReadVolatile<u32>,
+}
+
+const_assert_size!(Registers, 0x100);
+
+/// The Raspberry Pi's EMMC controller, attached to the SD card slot.
+pub struct Emmc {
+    registers: &'static mut Registers,
+    /// The relative card address assigned during initialization.
+    rca: u32,
+    /// Whether the card is addressed by block (SDHC/SDXC) or by byte.
+    block_addressing: bool,
+}
+
+impl Emmc {
+    /// Resets the EMMC controller, routes it to the SD card slot (GPIO pins
+    /// 48 to 53) and initializes the card in the slot: the card is
+    /// identified, selected and switched to a 4 bit bus if it supports one.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Error::Timeout` if the controller or the card stops
+    /// responding and `Error::Command` if the card rejects a command or is
+    /// not a (2.0 or later) SD card.
+    pub fn new() -> Result<Emmc, Error> {
+        for pin in 48..=53 {
+            Gpio::new(pin).into_alt(Function::Alt3);
+        }
+
+        let mut emmc = Emmc {
+            registers: unsafe { &mut *(EMMC_REG_BASE as *mut Registers) },
+            rca: 0,
+            block_addressing: false,
+        };
+
+        emmc.reset()?;
+        emmc.set_clock(IDENTIFICATION_CLOCK)?;
+
+        // Report every interrupt in `INTERRUPT`, without raising IRQs
+        emmc.registers.IRPT_EN.write(0xFFFFFFFF);
+        emmc.registers.IRPT_MASK.write(0xFFFFFFFF);
+
+        emmc.identify()?;
+        emmc.set_clock(TRANSFER_CLOCK)?;
+        emmc.send_command(Command::SelectCard, emmc.rca)?;
+        emmc.configure_bus()?;
+
+        return Ok(emmc);
+    }
+
+    /// Resets the host controller and enables its internal clock.
+    fn reset(&mut self) -> Result<(), Error> {
+        self.registers.CONTROL0.write(0);
+        self.registers.CONTROL1.or_mask(Control1::ResetHost as u32);
+
+        let deadline = timer::current_time() + TIMEOUT;
+        while self.registers.CONTROL1.has_mask(Control1::ResetHost as u32) {
+            if timer::current_time() > deadline {
+                return Err(Error::Timeout);
+            }
+        }
+
+        self.registers.CONTROL1.or_mask(Control1::ClockInternalEnable as u32 | Control1::DataTimeoutMax as u32);
+        timer::spin_sleep(Duration::from_millis(10));
+
+        return Ok(());
+    }
+
+    /// Sets the SD clock to at most `freq` Hz.
+    fn set_clock(&mut self, freq: u32) -> Result<(), Error> {
+        self.wait_status_clear(Status::CmdInhibit as u32 | Status::DatInhibit as u32)?;
+
+        self.registers.CONTROL1.and_mask(!(Control1::ClockEnable as u32));
+        timer::spin_sleep(Duration::from_millis(10));
+
+        // SDHCI 3.00 controllers take a 10 bit divisor; older ones only
+        // accept powers of two up to 128.
+        let divisor = BASE_CLOCK / freq;
+        let version = (self.registers.SLOTISR_VER.read() >> 16) & 0xFF;
+        let mut divisor = if version > HOST_SPEC_V2 {
+            divisor
+        } else if divisor <= 1 {
+            1
+        } else {
+            let shift = 31 - (divisor - 1).leading_zeros();
+            1 << core::cmp::min(shift, 7)
+        };
+        if divisor <= 2 {
+            divisor = 2;
+        }
+
+        let bits = (divisor & 0xFF) << 8 | (divisor & 0x300) >> 2;
+        let control1 = self.registers.CONTROL1.read() & 0xFFFF003F;
+        self.registers.CONTROL1.write(control1 | bits);
+        timer::spin_sleep(Duration::from_millis(10));
+
+        self.registers.CONTROL1.or_mask(Control1::ClockEnable as u32);
+        timer::spin_sleep(Duration::from_millis(10));
+
+        let deadline = timer::current_time() + TIMEOUT;
+        while !self.registers.CONTROL1.has_mask(Control1::ClockStable as u32) {
+            if timer::current_time() > deadline {
+                return Err(Error::Timeout);
+            }
+        }
+
+        return Ok(());
+    }
+
+    /// Takes the card from idle to standby: checks it is an SD 2.0 card,
+    /// waits for it to power up and assigns it a relative address.
+    fn identify(&mut self) -> Result<(), Error> {
+        self.command(Command::GoIdle, 0)?;
+
+        if self.command(Command::SendIfCond, IF_COND_ARG)? != IF_COND_ARG {
+            return Err(Error::Command);
+        }
+
+        let deadline = timer::current_time() + TIMEOUT;
+        loop {
+            let ocr = self.send_app_command(Command::SendOpCond, OP_COND_ARG)?;
+            if ocr & OP_COND_READY != 0 {
+                self.block_addressing = ocr & OP_COND_CCS != 0;
+                break;
+            }
+
+            if timer::current_time() > deadline {
+                return Err(Error::Timeout);
+            }
+            timer::spin_sleep(Duration::from_millis(10));
+        }
+
+        self.command(Command::AllSendCid, 0)?;
+
+        let response = self.command(Command::SendRelativeAddr, 0)?;
+        if response & R6_ERRORS_MASK != 0 {
+            return Err(Error::Command);
+        }
+        self.rca = response & 0xFFFF0000;
+
+        return Ok(());
+    }
+
+    /// Reads the card's `SCR` and switches to the 4 bit bus if supported.
+    fn configure_bus(&mut self) -> Result<(), Error> {
+        self.wait_status_clear(Status::DatInhibit as u32)?;
+        self.registers.BLKSIZECNT.write(1 << 16 | 8);
+
+        self.send_app_command(Command::SendScr, 0)?;
+        self.wait_interrupt(Interrupt::ReadReady as u32)?;
+        let scr = self.registers.DATA.read();
+        self.registers.DATA.read();
+        self.wait_interrupt(Interrupt::DataDone as u32)?;
+
+        if scr & SCR_BUS_WIDTH_4 != 0 {
+            let status = self.send_app_command(Command::SetBusWidth, self.rca | 2)?;
+            if status & R1_ERRORS_MASK != 0 {
+                return Err(Error::Command);
+            }
+            self.registers.CONTROL0.or_mask(Control0::BusWidth4 as u32);
+        }
+
+        return Ok(());
+    }
+
+    /// Spins until none of the bits in `mask` are set in `STATUS`.
+    fn wait_status_clear(&self, mask: u32) -> Result<(), Error> {
+        let deadline = timer::current_time() + TIMEOUT;
+        while self.registers.STATUS.read() & mask != 0 {
+            if timer::current_time() > deadline {
+                return Err(Error::Timeout);
+            }
+        }
+
+        return Ok(());
+    }
+
+    /// Spins until any of the bits in `mask` are raised in `INTERRUPT` and
+    /// acknowledges them. Error interrupts are acknowledged and reported.
+    fn wait_interrupt(&mut self, mask: u32) -> Result<(), Error> {
+        let wait_mask = mask | Interrupt::Errors as u32;
+        let deadline = timer::current_time() + TIMEOUT;
+
+        let mut interrupt = self.registers.INTERRUPT.read();
+        while interrupt & wait_mask == 0 {
+            if timer::current_time() > deadline {
+                return Err(Error::Timeout);
+            }
+            interrupt = self.registers.INTERRUPT.read();
+        }
+
+        if interrupt & Interrupt::Errors as u32 != 0 {
+            self.registers.INTERRUPT.write(interrupt);
+
+            let timeouts = Interrupt::CmdTimeout as u32 | Interrupt::DataTimeout as u32;
+            if interrupt & timeouts != 0 {
+                return Err(Error::Timeout);
+            }
+            return Err(Error::Command);
+        }
+
+        self.registers.INTERRUPT.write(mask);
+        return Ok(());
+    }
+
+    /// Sends `command` with argument `arg` and returns the first word of the
+    /// card's response without interpreting it.
+    fn command(&mut self, command: Command, arg: u32) -> Result<u32, Error> {
+        self.wait_status_clear(Status::CmdInhibit as u32)?;
+
+        // Acknowledge anything left over from a previous command
+        let stale = self.registers.INTERRUPT.read();
+        self.registers.INTERRUPT.write(stale);
+
+        self.registers.ARG1.write(arg);
+        self.registers.CMDTM.write(command as u32);
+        self.wait_interrupt(Interrupt::CmdDone as u32)?;
+
+        return Ok(self.registers.RESP[0].read());
+    }
+
+    /// Sends `command`, which must have an R1 response, with argument `arg`
+    /// and returns the card's status.
+    fn send_command(&mut self, command: Command, arg: u32) -> Result<u32, Error> {
+        let status = self.command(command, arg)?;
+        if status & R1_ERRORS_MASK != 0 {
+            return Err(Error::Command);
+        }
+
+        return Ok(status);
+    }
+
+    /// Sends the application specific `command` with argument `arg` and
+    /// returns the first word of the card's response.
+    fn send_app_command(&mut self, command: Command, arg: u32) -> Result<u32, Error> {
+        if self.rca == 0 {
+            // The card has no address yet and does not answer
+            self.command(Command::AppCmd, 0)?;
+        } else if self.send_command(Command::AppCmdRca, self.rca)? & R1_APP_CMD == 0 {
+            return Err(Error::Command);
+        }
+
+        return self.command(command, arg);
+    }
+
+    /// Returns the command argument addressing block `n`.
+    fn address(&self, n: u32) -> u32 {
+        if self.block_addressing {
+            return n;
+        }
+        return n * BLOCK_SIZE as u32;
+    }
+
+    /// Prepares the controller for a transfer of `count` blocks.
+    fn start_transfer(&mut self, count: usize) -> Result<(), Error> {
+        assert!(count > 0 && count <= MAX_BLOCKS);
+
+        self.wait_status_clear(Status::DatInhibit as u32)?;
+        self.registers.BLKSIZECNT.write((count as u32) << 16 | BLOCK_SIZE as u32);
+
+        return Ok(());
+    }
+
+    /// Reads the consecutive blocks starting at block `n` into `buf`. A
+    /// single block is read with `CMD17`; several with `CMD18` followed by
+    /// `CMD12`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `buf` is empty, its length is not a multiple of
+    /// `BLOCK_SIZE` or it spans more than `MAX_BLOCKS` blocks.
+    pub fn read_blocks(&mut self, n: u32, buf: &mut [u8]) -> Result<(), Error> {
+        assert!(buf.len() % BLOCK_SIZE == 0);
+        let count = buf.len() / BLOCK_SIZE;
+
+        let command = if count == 1 { Command::ReadSingleBlock } else { Command::ReadMultipleBlock };
+        let arg = self.address(n);
+
+        self.start_transfer(count)?;
+        self.send_command(command, arg)?;
+
+        for block in buf.chunks_mut(BLOCK_SIZE) {
+            self.wait_interrupt(Interrupt::ReadReady as u32)?;
+            for word in block.chunks_mut(4) {
+                word.copy_from_slice(&self.registers.DATA.read().to_le_bytes());
+            }
+        }
+
+        self.wait_interrupt(Interrupt::DataDone as u32)?;
+
+        if count > 1 {
+            self.send_command(Command::StopTransmission, 0)?;
+        }
+
+        return Ok(());
+    }
+
+    /// Writes `buf` to the consecutive blocks starting at block `n`. A single
+    /// block is written with `CMD24`; several with `CMD25` followed by
+    /// `CMD12`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `buf` is empty, its length is not a multiple of
+    /// `BLOCK_SIZE` or it spans more than `MAX_BLOCKS` blocks.
+    pub fn write_blocks(&mut self, n: u32, buf: &[u8]) -> Result<(), Error> {
+        assert!(buf.len() % BLOCK_SIZE == 0);
+        let count = buf.len() / BLOCK_SIZE;
+
+        let command = if count == 1 { Command::WriteSingleBlock } else { Command::WriteMultipleBlock };
+        let arg = self.address(n);
+
+        self.start_transfer(count)?;
+        self.send_command(command, arg)?;
+
+        for block in buf.chunks(BLOCK_SIZE) {
+            self.wait_interrupt(Interrupt::WriteReady as u32)?;
+            for word in block.chunks(4) {
+                self.registers.DATA.write(u32::from_le_bytes([word[0], word[1], word[2], word[3]]));
+            }
+        }
+
+        self.wait_interrupt(Interrupt::DataDone as u32)?;
+
+        if count > 1 {
+            self.send_command(Command::StopTransmission, 0)?;
+        }
+
+        return Ok(());
+    }
+}
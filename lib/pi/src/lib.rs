@@ -7,6 +7,7 @@
 
 pub mod atags;
 pub mod common;
+pub mod emmc;
 pub mod gpio;
 pub mod interrupt;
 pub mod timer;
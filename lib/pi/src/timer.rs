@@ -48,6 +48,26 @@ impl Timer {
         // Clear old match
         self.registers.CS.write(0b0010);
     }
+
+    /// Clears a match in timer 1 without setting up a new one. No timer 1
+    /// interrupt is issued until the counter wraps around to the old compare
+    /// value, about 71 minutes later.
+    pub fn stop_tick(&mut self) {
+        self.registers.CS.write(0b0010);
+    }
+
+    /// Sets up a match in timer 3 to occur when the counter reaches `t`,
+    /// clearing any old match. Only the low 32 bits of the counter are
+    /// compared, so a `t` more than about 71 minutes away matches early.
+    pub fn alarm_at(&mut self, t: Duration) {
+        self.registers.COMPARE[3].write(t.as_micros() as u32);
+        self.clear_alarm();
+    }
+
+    /// Clears a match in timer 3, acknowledging its interrupt.
+    pub fn clear_alarm(&mut self) {
+        self.registers.CS.write(0b1000);
+    }
 }
 
 /// Returns current time.
@@ -70,3 +90,20 @@ pub fn spin_sleep(t: Duration) {
 pub fn tick_in(t: Duration) {
     Timer::new().tick_in(t);
 }
+
+/// Clears a match in timer 1 without setting up a new one.
+pub fn stop_tick() {
+    Timer::new().stop_tick();
+}
+
+/// Sets up a match in timer 3 to occur when the counter reaches `t`. If
+/// interrupts for timer 3 are enabled and IRQs are unmasked, then a timer
+/// interrupt will be issued at `t`.
+pub fn alarm_at(t: Duration) {
+    Timer::new().alarm_at(t);
+}
+
+/// Clears a match in timer 3.
+pub fn clear_alarm() {
+    Timer::new().clear_alarm();
+}
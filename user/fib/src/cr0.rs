@@ -23,8 +23,9 @@ unsafe fn zeros_bss() {
 }
 
 #[no_mangle]
-pub unsafe extern "C" fn _start() -> ! {
+pub unsafe extern "C" fn _start(argc: usize, argv: *const [u64; 2]) -> ! {
     zeros_bss();
+    kernel_api::syscall::init_args(argc, argv);
     crate::main();
-    kernel_api::syscall::exit();
+    kernel_api::syscall::exit(0);
 }
@@ -25,12 +25,12 @@ fn main() {
     if pid == 2 {
         sleep(Duration::new(2, 500000000));
         println!("Exiting slept process (pid={}) at time {:?}", pid, time());
-        exit();
+        exit(0);
     }
 
     let rtn = fib(30);
 
     println!("Ended: Result = {}", rtn);
     println!("Exiting process (pid={})", pid);
-    exit();
+    exit(0);
 }